@@ -0,0 +1,200 @@
+//! End-to-end benchmark of kafka-json-processor with a mock Kafka cluster (provided by librdkafka).
+//!
+//! It measures:
+//! * throughput - a backlog of messages is produced to the input topic first, then kafka-json-processor is started
+//!   and the time until all output messages are consumed is measured,
+//! * latency - messages are then produced at a constant rate and the time from producing an input message
+//!   to consuming its output message is measured.
+//!
+//! Run with `cargo bench --bench pipeline`. The number of messages and the rate can be changed with
+//! `BENCH_MESSAGES` (default: 100000), `BENCH_LATENCY_MESSAGES` (default: 10000) and `BENCH_RATE` (messages per second, default: 2000).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rdkafka::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::message::Message;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+use serde_json::{json, Value};
+use kafka_json_processor_core::{run_processor, Stream};
+use kafka_json_processor_core::error::ProcessingError;
+use kafka_json_processor_core::processor::ObjectKey::Key;
+use kafka_json_processor_core::processor::{ObjectTree, OutputMessage};
+
+const INPUT_TOPIC: &str = "bench-in";
+const OUTPUT_TOPIC: &str = "bench-out";
+
+fn main() {
+    let messages = env_or("BENCH_MESSAGES", 100_000);
+    let latency_messages = env_or("BENCH_LATENCY_MESSAGES", 10_000);
+    let rate = env_or("BENCH_RATE", 2_000);
+
+    // the mock cluster lives as long as the client that created it
+    let cluster: BaseProducer = ClientConfig::new()
+        .set("test.mock.num.brokers", "1")
+        .create()
+        .expect("Cannot create mock cluster");
+    let metadata = cluster.client().fetch_metadata(None, Duration::from_secs(10))
+        .expect("Cannot fetch metadata of mock cluster");
+    let broker = &metadata.brokers()[0];
+    let bootstrap_servers = format!("{}:{}", broker.host(), broker.port());
+
+    let producer: BaseProducer = ClientConfig::new()
+        .set("bootstrap.servers", &bootstrap_servers)
+        .set("linger.ms", "5")
+        .create()
+        .expect("Cannot create input producer");
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &bootstrap_servers)
+        .set("group.id", "kjp-bench-output")
+        .set("auto.offset.reset", "earliest")
+        .set("fetch.wait.max.ms", "10")
+        .create()
+        .expect("Cannot create output consumer");
+    consumer.subscribe(&[OUTPUT_TOPIC]).expect("Cannot subscribe to output topic");
+
+    // the output consumer should join its group before the measurement
+    producer.send(BaseRecord::to(OUTPUT_TOPIC).key("warm-up").payload("{}"))
+        .expect("Cannot send warm-up message");
+    producer.flush(Duration::from_secs(10));
+    receive(&consumer, 1);
+
+    println!("Producing {messages} input message(s)...");
+    for i in 0..messages {
+        send(&producer, i);
+    }
+    producer.flush(Duration::from_secs(60));
+
+    start_processor(&bootstrap_servers);
+
+    // startup (and joining the consumer group) is not a part of the measurement
+    let startup = Instant::now();
+    let first = receive(&consumer, 1);
+    println!("First output message after {:.2}s.", startup.elapsed().as_secs_f64());
+
+    let start = Instant::now();
+    let rest = receive(&consumer, messages - first);
+    let elapsed = start.elapsed();
+    println!("Throughput: {rest} message(s) in {:.2}s ({:.0} msg/s).", elapsed.as_secs_f64(), rest as f64 / elapsed.as_secs_f64());
+
+    println!("Producing {latency_messages} message(s) at {rate} msg/s...");
+    let interval = Duration::from_secs_f64(1.0 / rate as f64);
+    let paced_start = Instant::now();
+    let mut latencies = Vec::with_capacity(latency_messages);
+    for i in 0..latency_messages {
+        let due = paced_start + interval * i as u32;
+        while Instant::now() < due {
+            producer.poll(Duration::ZERO);
+            latencies.extend(receive_available(&consumer));
+        }
+        send(&producer, i);
+        producer.poll(Duration::ZERO);
+    }
+    while latencies.len() < latency_messages {
+        producer.poll(Duration::ZERO);
+        latencies.extend(receive_available(&consumer));
+    }
+
+    latencies.sort();
+    println!(
+        "Latency: p50 {:.1}ms, p99 {:.1}ms, max {:.1}ms.",
+        percentile(&latencies, 0.5), percentile(&latencies, 0.99), percentile(&latencies, 1.0),
+    );
+
+    std::process::exit(0);
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name).ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+fn send(producer: &BaseProducer, i: usize) {
+    let key = i.to_string();
+    let payload = json!({ "id": i, "sent_us": now_micros(), "message": "kafka-json-processor benchmark" }).to_string();
+    loop {
+        match producer.send(BaseRecord::to(INPUT_TOPIC).key(&key).payload(&payload)) {
+            Ok(()) => return,
+            Err(_) => {
+                // queue is full
+                producer.poll(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+/// Receives at least given number of messages. Returns the number of received messages.
+fn receive(consumer: &BaseConsumer, count: usize) -> usize {
+    let mut received = 0;
+    while received < count {
+        received += receive_available(consumer).len();
+    }
+    received
+}
+
+/// Receives messages that are available now and returns their latencies (in microseconds).
+fn receive_available(consumer: &BaseConsumer) -> Vec<u64> {
+    let mut latencies = vec![];
+    while let Some(result) = consumer.poll(Duration::from_millis(1)) {
+        let message = match result {
+            Ok(message) => message,
+            // output topic does not exist until the first output message is produced
+            Err(_) => continue,
+        };
+        let sent = message.payload()
+            .and_then(|payload| serde_json::from_slice::<Value>(payload).ok())
+            .and_then(|value| value["sent_us"].as_u64())
+            .unwrap_or_default();
+        latencies.push(now_micros().saturating_sub(sent));
+    }
+    latencies
+}
+
+fn percentile(sorted: &[u64], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+    sorted[index] as f64 / 1000.0
+}
+
+fn start_processor(bootstrap_servers: &str) {
+    let directory = std::env::temp_dir().join(format!("kjp-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).expect("Cannot create benchmark directory");
+    let config_path = directory.join("processor.properties");
+    let config = format!(
+        "bootstrap.servers={bootstrap_servers}\n\
+        consumer.group.id=kjp-bench\n\
+        consumer.auto.offset.reset=earliest\n\
+        consumer.fetch.wait.max.ms=10\n\
+        producer.linger.ms=5\n\
+        processor.journal.path={}\n",
+        directory.join("journal").display(),
+    );
+    std::fs::write(&config_path, config).expect("Cannot write benchmark config");
+    std::env::set_var("KAFKA_PROCESSOR_CONFIG_PATH", &config_path);
+
+    std::thread::spawn(|| {
+        let mut streams = HashMap::new();
+        streams.insert("bench".to_string(), Stream {
+            source_topic: INPUT_TOPIC.to_string(),
+            target_topic: OUTPUT_TOPIC.to_string(),
+            processors: &[&copy_fields],
+            ..Default::default()
+        });
+        run_processor(streams);
+    });
+}
+
+fn copy_fields(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+    for field in ["id", "sent_us", "message"] {
+        let value = input.get_val(&[Key(field.to_string())])?.clone();
+        message.insert_val(&[Key(field.to_string())], value)?;
+    }
+    Ok(())
+}
@@ -18,6 +18,7 @@ fn main() {
         source_topic: "aaaa".to_string(),
         target_topic: "bbbb".to_string(),
         processors: &[&add_static_field, &format_xml_field, &format_json_field],
+        ..Default::default()
     });
 
     run_processor(streams);
@@ -68,6 +69,7 @@ mod simulations {
             source_topic: "example".to_string(),
             target_topic: "example".to_string(),
             processors: &[&add_static_field, &format_xml_field, &format_json_field],
+            ..Default::default()
         });
 
         simulate_streams_from_default_folder(streams);
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use serde_json::{json, Map, Number, Value};
+use crate::error::{ErrorKind, ProcessingError};
+use crate::processor::{json_path_to_object_keys, ObjectKey, ObjectTree};
+
+/// Fields of a summary message describing its window (aggregates cannot have these names).
+const RESERVED_FIELDS: [&str; 3] = ["key", "window_start", "window_end"];
+
+/// Windowed aggregation of a stream: messages are grouped by a field and aggregated in event-time windows.
+/// When a window closes, a summary message is produced to the target topic of the stream, eg.
+/// `{"key": "customer-1", "window_start": 1690000000000, "window_end": 1690000060000, "events": 3, "total": 12.5}`.
+///
+/// Windows are tumbling (`advance_ms` equal to `window_ms`) or hopping (a message belongs to every window
+/// that contains its timestamp). A window closes when the watermark (the highest timestamp seen in the partition)
+/// passes its end by `lateness_ms`. Messages that belong only to closed windows are late and dropped.
+///
+/// Parsed from `;`-separated options, eg.
+/// `group.by=$.customerId;window.ms=60000;advance.ms=10000;lateness.ms=5000;timestamp=$.ts;events=count;total=sum($.amount)`.
+/// Options other than `group.by`, `window.ms`, `advance.ms`, `lateness.ms` and `timestamp` are aggregates:
+/// `count`, `sum($.field)`, `min($.field)`, `max($.field)` or `collect($.field)`.
+#[derive(Clone, Debug)]
+pub struct Aggregation {
+    pub group_by: Vec<ObjectKey>,
+    pub window_ms: i64,
+    pub advance_ms: i64,
+    pub lateness_ms: i64,
+    /// Field with the event time in milliseconds (if not set, the timestamp of the Kafka message is used).
+    pub timestamp: Option<Vec<ObjectKey>>,
+    /// Aggregates by their names in summary messages.
+    pub aggregates: Vec<(String, Aggregate)>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Aggregate {
+    /// Number of messages.
+    Count,
+    /// Sum of numeric values of the field.
+    Sum(Vec<ObjectKey>),
+    /// Minimum of numeric values of the field.
+    Min(Vec<ObjectKey>),
+    /// Maximum of numeric values of the field.
+    Max(Vec<ObjectKey>),
+    /// All values of the field (in the order they were aggregated).
+    Collect(Vec<ObjectKey>),
+}
+
+impl Aggregate {
+    fn initial(&self) -> Value {
+        match self {
+            Aggregate::Count => json!(0),
+            Aggregate::Sum(_) => json!(0),
+            Aggregate::Min(_) | Aggregate::Max(_) => Value::Null,
+            Aggregate::Collect(_) => json!([]),
+        }
+    }
+
+    /// Adds the value of the field of the input message to the accumulator. Missing fields are ignored,
+    /// as are values that are not numbers (except for `collect`).
+    fn add(&self, accumulator: &mut Value, input: &Value) {
+        let field = |path: &Vec<ObjectKey>| input.get_val(path).ok().filter(|value| !value.is_null());
+        match self {
+            Aggregate::Count => *accumulator = add_numbers(accumulator, &json!(1)),
+            Aggregate::Sum(path) => if let Some(value) = field(path).filter(|value| value.is_number()) {
+                *accumulator = add_numbers(accumulator, value);
+            },
+            Aggregate::Min(path) => if let Some(value) = field(path).filter(|value| value.is_number()) {
+                if accumulator.as_f64().is_none_or(|min| value.as_f64().unwrap_or(f64::NAN) < min) {
+                    *accumulator = value.clone();
+                }
+            },
+            Aggregate::Max(path) => if let Some(value) = field(path).filter(|value| value.is_number()) {
+                if accumulator.as_f64().is_none_or(|max| value.as_f64().unwrap_or(f64::NAN) > max) {
+                    *accumulator = value.clone();
+                }
+            },
+            Aggregate::Collect(path) => if let (Some(value), Value::Array(values)) = (field(path), accumulator) {
+                values.push(value.clone());
+            },
+        }
+    }
+}
+
+/// Adds numbers as integers if both are integers (and the sum does not overflow), otherwise as floats.
+fn add_numbers(a: &Value, b: &Value) -> Value {
+    match (a.as_i64(), b.as_i64()) {
+        (Some(a), Some(b)) if a.checked_add(b).is_some() => json!(a + b),
+        _ => Number::from_f64(a.as_f64().unwrap_or_default() + b.as_f64().unwrap_or_default())
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+    }
+}
+
+impl Aggregation {
+    /// Starts of windows containing the timestamp (from the latest).
+    fn window_starts(&self, timestamp: i64) -> impl Iterator<Item = i64> + '_ {
+        let last_start = timestamp - timestamp.rem_euclid(self.advance_ms);
+        (0..)
+            .map(move |i| last_start - i * self.advance_ms)
+            .take_while(move |start| start + self.window_ms > timestamp)
+    }
+
+    fn closes_at(&self, start: i64) -> i64 {
+        start + self.window_ms + self.lateness_ms
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut group_by = None;
+        let mut window_ms = None;
+        let mut advance_ms = None;
+        let mut lateness_ms = 0;
+        let mut timestamp = None;
+        let mut aggregates = vec![];
+
+        for option in s.split(';').map(str::trim).filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| format!("Invalid aggregation option: {option}. Expected <name>=<value>."))?;
+            let millis = |value: &str| value.parse::<i64>().ok()
+                .filter(|ms| *ms >= 0)
+                .ok_or_else(|| format!("Invalid value of aggregation option {name}: '{value}'. Expected milliseconds."));
+
+            match name {
+                "group.by" => group_by = Some(json_path_to_object_keys(value)),
+                "window.ms" => window_ms = Some(millis(value)?),
+                "advance.ms" => advance_ms = Some(millis(value)?),
+                "lateness.ms" => lateness_ms = millis(value)?,
+                "timestamp" => timestamp = Some(json_path_to_object_keys(value)),
+                _ if RESERVED_FIELDS.contains(&name) =>
+                    return Err(format!("Aggregate cannot be named {name}, it's a field of summary messages.")),
+                _ => aggregates.push((name.to_string(), value.parse()?)),
+            }
+        }
+
+        let group_by = group_by.ok_or("Aggregation requires group.by (JSONPath of the grouping field).")?;
+        let window_ms = window_ms.filter(|ms| *ms > 0).ok_or("Aggregation requires window.ms greater than 0.")?;
+        let advance_ms = advance_ms.unwrap_or(window_ms);
+        if advance_ms == 0 || advance_ms > window_ms {
+            return Err(format!("Aggregation advance.ms must be greater than 0 and not greater than window.ms ({window_ms})."));
+        }
+        if aggregates.is_empty() {
+            return Err("Aggregation requires at least one aggregate (eg. events=count).".to_string());
+        }
+
+        Ok(Aggregation { group_by, window_ms, advance_ms, lateness_ms, timestamp, aggregates })
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "count" {
+            return Ok(Aggregate::Count);
+        }
+
+        let (function, path) = s.strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .map(|(function, path)| (function.trim(), json_path_to_object_keys(path.trim())))
+            .ok_or_else(|| format!("Unknown aggregate: {s}. Available aggregates: count, sum($.field), min($.field), max($.field), collect($.field)."))?;
+        match function {
+            "sum" => Ok(Aggregate::Sum(path)),
+            "min" => Ok(Aggregate::Min(path)),
+            "max" => Ok(Aggregate::Max(path)),
+            "collect" => Ok(Aggregate::Collect(path)),
+            _ => Err(format!("Unknown aggregate function: {function}. Available functions: count, sum, min, max, collect.")),
+        }
+    }
+}
+
+impl Display for Aggregation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "group.by={};window.ms={};advance.ms={};lateness.ms={}",
+               json_path(&self.group_by), self.window_ms, self.advance_ms, self.lateness_ms)?;
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, ";timestamp={}", json_path(timestamp))?;
+        }
+        for (name, aggregate) in &self.aggregates {
+            match aggregate {
+                Aggregate::Count => write!(f, ";{name}=count")?,
+                Aggregate::Sum(path) => write!(f, ";{name}=sum({})", json_path(path))?,
+                Aggregate::Min(path) => write!(f, ";{name}=min({})", json_path(path))?,
+                Aggregate::Max(path) => write!(f, ";{name}=max({})", json_path(path))?,
+                Aggregate::Collect(path) => write!(f, ";{name}=collect({})", json_path(path))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn json_path(path: &[ObjectKey]) -> String {
+    path.iter()
+        .fold("$".to_string(), |acc, key| match key {
+            ObjectKey::Key(key) => format!("{acc}.{key}"),
+            ObjectKey::Index(i) => format!("{acc}[{i}]"),
+        })
+}
+
+/// Result of aggregating a message.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Aggregated {
+    /// Summaries of windows closed by this message (with their group keys).
+    Closed(Vec<(String, Value)>),
+    /// The message belongs only to closed windows.
+    Late { timestamp: i64, watermark: i64 },
+    /// The message was aggregated before the last checkpoint (it's consumed again after a restart).
+    Replayed,
+}
+
+/// Open windows of a partition.
+#[derive(Debug, Default)]
+pub(crate) struct Windows {
+    /// Accumulators (in the order of aggregates) by window start and group key.
+    open: BTreeMap<(i64, String), Vec<Value>>,
+    /// The highest timestamp seen.
+    watermark: Option<i64>,
+    /// The highest offset aggregated.
+    last_offset: Option<i64>,
+    /// Offset saved in the checkpoint the windows were restored from - messages up to it are already aggregated.
+    restored_offset: Option<i64>,
+}
+
+impl Windows {
+    /// Adds the message to its windows and closes windows the watermark has passed.
+    pub(crate) fn add(&mut self, aggregation: &Aggregation, input: &Value, timestamp: Option<i64>, offset: i64) -> Result<Aggregated, ProcessingError> {
+        if self.restored_offset.is_some_and(|restored| offset <= restored) {
+            return Ok(Aggregated::Replayed);
+        }
+
+        let timestamp = match &aggregation.timestamp {
+            Some(path) => input.get_val(path)?.as_i64()
+                .ok_or_else(|| ErrorKind::OtherError { err: format!("Timestamp field {} is not a number of milliseconds.", json_path(path)).into() })?,
+            None => timestamp
+                .ok_or_else(|| ErrorKind::OtherError { err: "Message has no timestamp.".into() })?,
+        };
+        let group = match input.get_val(&aggregation.group_by)? {
+            Value::String(group) => group.clone(),
+            group => group.to_string(),
+        };
+
+        self.last_offset = self.last_offset.max(Some(offset));
+        let watermark = self.watermark.map_or(timestamp, |watermark| watermark.max(timestamp));
+        self.watermark = Some(watermark);
+
+        let mut aggregated = false;
+        for start in aggregation.window_starts(timestamp) {
+            if aggregation.closes_at(start) <= watermark {
+                continue;
+            }
+            let accumulators = self.open.entry((start, group.clone()))
+                .or_insert_with(|| aggregation.aggregates.iter().map(|(_, aggregate)| aggregate.initial()).collect());
+            for ((_, aggregate), accumulator) in aggregation.aggregates.iter().zip(accumulators.iter_mut()) {
+                aggregate.add(accumulator, input);
+            }
+            aggregated = true;
+        }
+
+        let closed = self.close(aggregation, watermark);
+        if !aggregated {
+            return Ok(Aggregated::Late { timestamp, watermark });
+        }
+        Ok(Aggregated::Closed(closed))
+    }
+
+    fn close(&mut self, aggregation: &Aggregation, watermark: i64) -> Vec<(String, Value)> {
+        let closed: Vec<(i64, String)> = self.open.keys()
+            .take_while(|(start, _)| aggregation.closes_at(*start) <= watermark)
+            .cloned()
+            .collect();
+
+        closed.into_iter()
+            .filter_map(|key| self.open.remove_entry(&key))
+            .map(|((start, group), accumulators)| {
+                let mut summary = Map::new();
+                summary.insert("key".to_string(), json!(group));
+                summary.insert("window_start".to_string(), json!(start));
+                summary.insert("window_end".to_string(), json!(start + aggregation.window_ms));
+                for ((name, _), accumulator) in aggregation.aggregates.iter().zip(accumulators) {
+                    summary.insert(name.clone(), accumulator);
+                }
+                (group, Value::Object(summary))
+            })
+            .collect()
+    }
+
+    /// Serializes windows for a checkpoint.
+    pub(crate) fn to_json(&self) -> Vec<u8> {
+        let open: Vec<Value> = self.open.iter()
+            .map(|((start, group), accumulators)| json!([start, group, accumulators]))
+            .collect();
+        serde_json::to_vec(&json!({
+            "watermark": self.watermark,
+            "last_offset": self.last_offset,
+            "open": open,
+        })).expect("windows are valid JSON-s")
+    }
+
+    pub(crate) fn from_json(content: &[u8]) -> Result<Windows, String> {
+        let checkpoint: Value = serde_json::from_slice(content)
+            .map_err(|e| e.to_string())?;
+        let open: Vec<(i64, String, Vec<Value>)> = serde_json::from_value(checkpoint["open"].clone())
+            .map_err(|e| e.to_string())?;
+
+        Ok(Windows {
+            open: open.into_iter()
+                .map(|(start, group, accumulators)| ((start, group), accumulators))
+                .collect(),
+            watermark: checkpoint["watermark"].as_i64(),
+            last_offset: checkpoint["last_offset"].as_i64(),
+            restored_offset: checkpoint["last_offset"].as_i64(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.open.is_empty() && self.watermark.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::aggregation::{Aggregated, Aggregation, Windows};
+
+    fn aggregation(spec: &str) -> Aggregation {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn should_parse_aggregation() {
+        let spec = "group.by=$.customer.id;window.ms=60000;advance.ms=10000;lateness.ms=5000;timestamp=$.ts;\
+            events=count;total=sum($.amount);smallest=min($.amount);largest=max($.amount);ids=collect($.id)";
+
+        assert_eq!(spec, aggregation(spec).to_string());
+        assert_eq!("group.by=$.c;window.ms=100;advance.ms=100;lateness.ms=0;n=count", aggregation("group.by=$.c; window.ms=100; n=count").to_string());
+        assert!("group.by=$.c;window.ms=100".parse::<Aggregation>().is_err());
+        assert!("group.by=$.c;window.ms=100;advance.ms=200;n=count".parse::<Aggregation>().is_err());
+        assert!("group.by=$.c;window.ms=100;key=count".parse::<Aggregation>().is_err());
+        assert!("group.by=$.c;window.ms=100;n=avg($.x)".parse::<Aggregation>().is_err());
+    }
+
+    #[test]
+    fn should_aggregate_tumbling_windows_with_allowed_lateness() {
+        let aggregation = aggregation("group.by=$.c;window.ms=100;lateness.ms=50;n=count;total=sum($.x);low=min($.x);high=max($.x);all=collect($.x)");
+        let mut windows = Windows::default();
+        let mut add = |c: &str, x: f64, timestamp: i64, offset: i64| windows.add(&aggregation, &json!({"c": c, "x": x}), Some(timestamp), offset).unwrap();
+
+        assert_eq!(Aggregated::Closed(vec![]), add("a", 1.0, 10, 0));
+        assert_eq!(Aggregated::Closed(vec![]), add("b", 2.0, 20, 1));
+        assert_eq!(Aggregated::Closed(vec![]), add("a", 3.5, 120, 2));
+        // within allowed lateness
+        assert_eq!(Aggregated::Closed(vec![]), add("a", 2.0, 90, 3));
+        assert_eq!(Aggregated::Closed(vec![
+            ("a".to_string(), json!({"key": "a", "window_start": 0, "window_end": 100, "n": 2, "total": 3.0, "low": 1.0, "high": 2.0, "all": [1.0, 2.0]})),
+            ("b".to_string(), json!({"key": "b", "window_start": 0, "window_end": 100, "n": 1, "total": 2.0, "low": 2.0, "high": 2.0, "all": [2.0]})),
+        ]), add("c", 1.0, 150, 4));
+        assert_eq!(Aggregated::Late { timestamp: 99, watermark: 150 }, add("a", 1.0, 99, 5));
+    }
+
+    #[test]
+    fn should_aggregate_hopping_windows_and_skip_replayed_messages() {
+        let aggregation = aggregation("group.by=$.c;window.ms=100;advance.ms=50;n=count");
+        let mut windows = Windows::default();
+        let input = json!({"c": "a"});
+
+        windows.add(&aggregation, &input, Some(60), 0).unwrap();
+        let mut windows = Windows::from_json(&windows.to_json()).unwrap();
+        assert_eq!(Aggregated::Replayed, windows.add(&aggregation, &input, Some(60), 0).unwrap());
+
+        assert_eq!(Aggregated::Closed(vec![
+            ("a".to_string(), json!({"key": "a", "window_start": 0, "window_end": 100, "n": 1})),
+        ]), windows.add(&aggregation, &input, Some(110), 1).unwrap());
+        assert_eq!(Aggregated::Closed(vec![
+            ("a".to_string(), json!({"key": "a", "window_start": 50, "window_end": 150, "n": 2})),
+            ("a".to_string(), json!({"key": "a", "window_start": 100, "window_end": 200, "n": 1})),
+        ]), windows.add(&aggregation, &input, Some(250), 2).unwrap());
+    }
+}
@@ -1,25 +1,136 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fmt::{Display, Formatter};
 use std::path::Path;
-use log::warn;
+use std::str::FromStr;
+use lazy_static::lazy_static;
+use log::{info, warn};
 use rdkafka::ClientConfig;
+use regex::{Captures, Regex};
+use crate::aggregation::Aggregation;
+use crate::dedup::DedupPolicy;
+use crate::error_policy::ErrorPolicy;
+use crate::key::KeyPolicy;
+use crate::ordering::OrderingPolicy;
+use crate::payload::PayloadPolicy;
 
 #[derive(Clone)]
 pub struct Config {
     pub consumer_config: ClientConfig,
     pub producer_config: ClientConfig,
-    pub internal_config: InternalConfig
+    pub internal_config: InternalConfig,
+    /// Overrides of single streams (`stream.<name>.*` properties) by normalized stream name
+    /// (see [Config::stream_config]).
+    pub stream_configs: HashMap<String, StreamConfig>,
 }
 
 #[derive(Clone)]
 pub struct InternalConfig {
     pub worker_threads: usize,
     pub channel_capacity: usize,
-    pub queue_slowdown_ms: usize,
     pub queue_size: usize,
+    pub max_in_flight: usize,
     pub journal_enabled: bool,
     pub journal_path: String,
+    pub dead_letter_topic: Option<String>,
+    pub delivery_mode: DeliveryMode,
+    pub delivery_max_attempts: usize,
+    pub commit_interval_ms: usize,
+    pub transaction_batch_size: usize,
+    pub transaction_interval_ms: usize,
+    pub transaction_timeout_ms: usize,
+    pub propagate_headers: bool,
+    pub key_policy: KeyPolicy,
+    pub null_payload_policy: PayloadPolicy,
+    pub invalid_payload_policy: PayloadPolicy,
+    pub ordering: OrderingPolicy,
+    pub error_policy: ErrorPolicy,
+    pub time_budget_ms: usize,
+    pub dedup_window_ms: usize,
+    pub dedup_max_entries: usize,
+    pub http_enabled: bool,
+    pub http_bind: String,
+    pub http_liveness_timeout_ms: usize,
+    pub shutdown_timeout_ms: usize,
+}
+
+/// Options of a single stream set by `stream.<name>.*` properties.
+/// They override both the template and the defaults from `processor.*` properties.
+#[derive(Clone, Default, Debug)]
+pub struct StreamConfig {
+    pub dead_letter_topic: Option<Option<String>>,
+    pub propagate_headers: Option<bool>,
+    pub key_policy: Option<KeyPolicy>,
+    pub null_payload_policy: Option<PayloadPolicy>,
+    pub invalid_payload_policy: Option<PayloadPolicy>,
+    pub ordering: Option<OrderingPolicy>,
+    pub error_policy: Option<ErrorPolicy>,
+    pub concurrency: Option<usize>,
+    pub time_budget_ms: Option<usize>,
+    pub state_changelog_topic: Option<Option<String>>,
+    pub dedup: Option<Option<DedupPolicy>>,
+    pub dedup_window_ms: Option<usize>,
+    pub dedup_max_entries: Option<usize>,
+    pub aggregation: Option<Option<Aggregation>>,
+    pub lookup_tables: Option<Vec<String>>,
+    /// rdkafka properties of the producer used by this stream (`stream.<name>.producer.*`).
+    pub producer_config: Vec<(String, String)>,
+}
+
+/// Options that can be set by `stream.<name>.<option>` properties (besides `producer.*`).
+const STREAM_OPTIONS: [&str; 15] = [
+    "dead.letter.topic",
+    "headers.propagate",
+    "key.policy",
+    "null.payload.policy",
+    "invalid.payload.policy",
+    "ordering",
+    "error.policy",
+    "concurrency",
+    "time.budget.ms",
+    "state.changelog.topic",
+    "dedup",
+    "dedup.window.ms",
+    "dedup.max.entries",
+    "aggregation",
+    "lookup.tables",
+];
+
+/// Decides when offsets of consumed messages are committed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeliveryMode {
+    /// Offsets are committed automatically by rdkafka, journal is updated when a message is enqueued in producer.
+    /// A crash may cause loss of messages that were consumed, but not yet delivered.
+    AutoCommit,
+    /// Offsets are committed (to Kafka and to journal) only after the delivery of all messages
+    /// up to this offset was confirmed by the broker.
+    AtLeastOnce,
+    /// Output messages are produced in transactions, consumed offsets are committed in the same transaction.
+    /// Requires `producer.transactional.id`.
+    ExactlyOnce,
+}
+
+impl FromStr for DeliveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto-commit" => Ok(DeliveryMode::AutoCommit),
+            "at-least-once" => Ok(DeliveryMode::AtLeastOnce),
+            "exactly-once" => Ok(DeliveryMode::ExactlyOnce),
+            _ => Err(format!("Unknown delivery mode: {s}. Available modes: auto-commit, at-least-once, exactly-once.")),
+        }
+    }
+}
+
+impl Display for DeliveryMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryMode::AutoCommit => write!(f, "auto-commit"),
+            DeliveryMode::AtLeastOnce => write!(f, "at-least-once"),
+            DeliveryMode::ExactlyOnce => write!(f, "exactly-once"),
+        }
+    }
 }
 
 impl Default for InternalConfig {
@@ -27,94 +138,719 @@ impl Default for InternalConfig {
         InternalConfig {
             worker_threads: 4,
             channel_capacity: 50,
-            queue_slowdown_ms: 10_000, // 10 s
             queue_size: 100_000,
+            max_in_flight: 10_000,
             journal_enabled: true,
             journal_path: "./kjp_journal".to_string(),
+            dead_letter_topic: None,
+            delivery_mode: DeliveryMode::AutoCommit,
+            delivery_max_attempts: 3,
+            commit_interval_ms: 5_000, // 5 s
+            transaction_batch_size: 1000,
+            transaction_interval_ms: 1000, // 1 s
+            transaction_timeout_ms: 30_000, // 30 s
+            propagate_headers: true,
+            key_policy: KeyPolicy::Synthetic,
+            null_payload_policy: PayloadPolicy::Skip,
+            invalid_payload_policy: PayloadPolicy::DeadLetter,
+            ordering: OrderingPolicy::Unordered,
+            error_policy: ErrorPolicy::Continue,
+            time_budget_ms: 0,
+            dedup_window_ms: 600_000, // 10 min
+            dedup_max_entries: 100_000,
+            http_enabled: false,
+            http_bind: "0.0.0.0:9090".to_string(),
+            http_liveness_timeout_ms: 60_000, // 60 s
+            shutdown_timeout_ms: 30_000, // 30 s
+        }
+    }
+}
+
+/// Invalid config entry (or invalid combination of entries).
+#[derive(Debug)]
+pub struct ConfigError {
+    /// Where the invalid entry comes from (if it's known).
+    pub location: Option<ConfigLocation>,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum ConfigLocation {
+    /// Line of the properties file (starting from 1).
+    Line(usize),
+    /// Environment variable overriding a property.
+    EnvVariable(String),
+}
+
+impl ConfigError {
+    fn new(location: Option<ConfigLocation>, reason: String) -> ConfigError {
+        ConfigError { location, reason }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(ConfigLocation::Line(line)) => write!(f, "line {line}: {}", self.reason),
+            Some(ConfigLocation::EnvVariable(name)) => write!(f, "environment variable {name}: {}", self.reason),
+            None => write!(f, "{}", self.reason),
         }
     }
 }
 
+impl Error for ConfigError {}
+
+/// Reads config from properties (eg. `"processor.delivery.mode=at-least-once"`), the same way as [`Config::read_from`].
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(content: &str) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = std::env::vars().collect();
+
+        Config::from_properties(content, &env)
+    }
+}
+
 impl Config {
-    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
-        let file = File::open(path.as_ref())?;
+    /// Reads config from given properties file (in Java properties format).
+    ///
+    /// Values can contain `${ENV_VAR}` (or `${ENV_VAR:-default}`) placeholders that are replaced with
+    /// environment variables. Any property can also be overridden by a `KJP_*` environment variable
+    /// (eg. `KJP_CONSUMER_GROUP_ID` overrides `consumer.group.id`).
+    ///
+    /// Unknown `processor.*` properties, invalid values and contradictory settings are rejected.
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let content = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| ConfigError::new(None, format!("Cannot read {}: {e}", path.as_ref().display())))?;
+        let env: HashMap<String, String> = std::env::vars().collect();
+
+        Config::from_properties(&content, &env)
+    }
+
+    /// Config with default values overridden by `KJP_*` environment variables (when there is no config file).
+    pub fn from_env() -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = std::env::vars().collect();
+
+        Config::from_properties("", &env)
+    }
+
+    fn from_properties(content: &str, env: &HashMap<String, String>) -> Result<Config, ConfigError> {
         let mut config = Config {
             consumer_config: ClientConfig::new(),
             producer_config: ClientConfig::new(),
             internal_config: InternalConfig::default(),
+            stream_configs: HashMap::new(),
         };
+        // where each property was set, to point at it in case of contradictory settings
+        let mut locations: HashMap<String, ConfigLocation> = HashMap::new();
 
-        for line in BufReader::new(&file).lines() {
-            let line: String = line?
-                .trim_start()
-                .to_string();
+        for property in parse_properties(content)? {
+            let location = ConfigLocation::Line(property.line);
+            resolve_placeholders(&property.key, &property.value, env)
+                .and_then(|value| config.set(&property.key, &value))
+                .map_err(|reason| ConfigError::new(Some(location.clone()), reason))?;
+            locations.insert(property.key, location);
+        }
 
-            if should_ignore(&line) {
-                continue;
+        for (name, key, value) in env_overrides(env) {
+            info!("Property {key} is overridden by environment variable.");
+            let location = ConfigLocation::EnvVariable(name);
+            config.set(&key, &value)
+                .map_err(|reason| ConfigError::new(Some(location.clone()), reason))?;
+            locations.insert(key, location);
+        }
+
+        config.validate(&locations)?;
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if let Some(key) = key.strip_prefix("consumer.") {
+            self.consumer_config.set(key.to_string(), value.to_string());
+        } else if let Some(key) = key.strip_prefix("producer.") {
+            self.producer_config.set(key.to_string(), value.to_string());
+        } else if key.starts_with("processor.") {
+            set_internal(key, value, &mut self.internal_config)?;
+        } else if let Some(stream_key) = key.strip_prefix("stream.") {
+            let (name, option) = split_stream_key(stream_key)
+                .ok_or_else(|| format!("Unknown property {key}. Stream properties have the form stream.<name>.<option>, \
+                    where option is one of: {}, producer.<rdkafka property>.", STREAM_OPTIONS.join(", ")))?;
+            let stream_config = self.stream_configs.entry(normalize_stream_name(name)).or_default();
+            set_stream(key, option, value, stream_config)?;
+        } else {
+            self.consumer_config.set(key.to_string(), value.to_string());
+            self.producer_config.set(key.to_string(), value.to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns overrides of given stream (if there are any).
+    ///
+    /// Stream names are matched case-insensitively and dots are interchangeable with underscores,
+    /// so that streams can be configured with environment variables (eg. `KJP_STREAM_IN_OUT_CONCURRENCY`).
+    pub fn stream_config(&self, name: &str) -> Option<&StreamConfig> {
+        self.stream_configs.get(&normalize_stream_name(name))
+    }
+
+    /// Rejects invalid and contradictory settings, applies settings required by the delivery mode.
+    fn validate(&mut self, locations: &HashMap<String, ConfigLocation>) -> Result<(), ConfigError> {
+        let location = |keys: &[&str]| keys.iter()
+            .find_map(|key| locations.get(*key))
+            .cloned();
+        let internal = &self.internal_config;
+
+        let positive = [
+            ("processor.worker.threads", internal.worker_threads),
+            ("processor.queue.size", internal.queue_size),
+            ("processor.max.in.flight", internal.max_in_flight),
+            ("processor.channel.capacity", internal.channel_capacity),
+            ("processor.delivery.max.attempts", internal.delivery_max_attempts),
+            ("processor.commit.interval.ms", internal.commit_interval_ms),
+            ("processor.transaction.batch.size", internal.transaction_batch_size),
+            ("processor.http.liveness.timeout.ms", internal.http_liveness_timeout_ms),
+            ("processor.dedup.max.entries", internal.dedup_max_entries),
+        ];
+        if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::new(location(&[key]), format!("{key} must be greater than 0.")));
+        }
+
+        let delivery_mode = internal.delivery_mode;
+        let auto_commit = self.consumer_config.get("enable.auto.commit");
+        let transactional_id = self.producer_config.get("transactional.id");
+
+        if delivery_mode != DeliveryMode::AutoCommit && auto_commit == Some("true") {
+            return Err(ConfigError::new(
+                location(&["consumer.enable.auto.commit", "enable.auto.commit"]),
+                format!("enable.auto.commit=true contradicts processor.delivery.mode={delivery_mode} (offsets are committed by kafka-json-processor)."),
+            ));
+        }
+
+        if delivery_mode != DeliveryMode::ExactlyOnce && transactional_id.is_some() {
+            return Err(ConfigError::new(
+                location(&["producer.transactional.id", "transactional.id"]),
+                format!("producer.transactional.id requires processor.delivery.mode=exactly-once (current mode: {delivery_mode})."),
+            ));
+        }
+
+        if delivery_mode == DeliveryMode::ExactlyOnce {
+            let stream_producer = locations.iter()
+                .find(|(key, _)| key.starts_with("stream.") && split_stream_key(&key["stream.".len()..])
+                    .map(|(_, option)| option.starts_with("producer."))
+                    .unwrap_or(false));
+            if let Some((key, location)) = stream_producer {
+                return Err(ConfigError::new(
+                    Some(location.clone()),
+                    format!("{key} contradicts processor.delivery.mode=exactly-once (all streams must share the transactional producer)."),
+                ));
             }
+        }
 
-            let (key, value) = key_value(&line)?;
-
-            if key.starts_with("consumer.") {
-                config.consumer_config.set(
-                    key.strip_prefix("consumer.").unwrap().to_string(),
-                    value.to_string(),
-                );
-            } else if key.starts_with("producer.") {
-                config.producer_config.set(
-                    key.strip_prefix("producer.").unwrap().to_string(),
-                    value.to_string(),
-                );
-            } else if key.starts_with("processor.") {
-                set_internal(key, value, &mut config.internal_config)?;
-            } else {
-                config.consumer_config.set(key.to_string(), value.to_string());
-                config.producer_config.set(key.to_string(), value.to_string());
+        match delivery_mode {
+            DeliveryMode::AutoCommit => {}
+            DeliveryMode::AtLeastOnce => {
+                // offsets are committed by kafka-json-processor after delivery
+                self.consumer_config.set("enable.auto.commit", "false");
+            }
+            DeliveryMode::ExactlyOnce => {
+                if transactional_id.is_none() {
+                    return Err(ConfigError::new(
+                        location(&["processor.delivery.mode"]),
+                        "Exactly-once delivery mode requires producer.transactional.id to be set.".to_string(),
+                    ));
+                }
+
+                if self.consumer_config.get("isolation.level") == Some("read_uncommitted") {
+                    return Err(ConfigError::new(
+                        location(&["consumer.isolation.level", "isolation.level"]),
+                        "isolation.level=read_uncommitted contradicts processor.delivery.mode=exactly-once.".to_string(),
+                    ));
+                }
+
+                // offsets are committed in transactions
+                self.consumer_config.set("enable.auto.commit", "false");
+                self.consumer_config.set("isolation.level", "read_committed");
             }
         }
 
-        Ok(config)
+        Ok(())
     }
 }
 
+/// A property read from properties file.
+struct Property {
+    /// Line where the property starts (starting from 1).
+    line: usize,
+    key: String,
+    value: String,
+}
+
+/// Parses properties in Java properties format: `key=value`, `key: value` or `key value` entries,
+/// `#` and `!` comments, escapes (`\t`, `\n`, `\uXXXX`, `\=`...) and lines continued with `\`.
+fn parse_properties(content: &str) -> Result<Vec<Property>, ConfigError> {
+    let mut properties = vec![];
+    let mut lines = content.lines().enumerate();
+
+    while let Some((i, line)) = lines.next() {
+        let line = line.trim_start();
+        if should_ignore(line) {
+            continue;
+        }
+
+        let mut logical_line = line.to_string();
+        while is_continued(&logical_line) {
+            logical_line.pop();
+            match lines.next() {
+                Some((_, next)) => logical_line.push_str(next.trim_start()),
+                None => break,
+            }
+        }
+
+        let line = i + 1;
+        let (key, value) = split_key_value(&logical_line);
+        let key = unescape(key)
+            .map_err(|reason| ConfigError::new(Some(ConfigLocation::Line(line)), reason))?;
+        let value = unescape(value)
+            .map_err(|reason| ConfigError::new(Some(ConfigLocation::Line(line)), reason))?;
+
+        if key.is_empty() {
+            return Err(ConfigError::new(Some(ConfigLocation::Line(line)), format!("Property without a key: {logical_line}")));
+        }
+
+        properties.push(Property { line, key, value });
+    }
+
+    Ok(properties)
+}
 
 fn should_ignore(line: &str) -> bool {
-    line.starts_with('#') || line.trim().is_empty()
+    line.starts_with('#') || line.starts_with('!') || line.trim().is_empty()
+}
+
+/// Line is continued if it ends with an odd number of backslashes.
+fn is_continued(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+/// Splits the line at the first unescaped separator: `=`, `:` or whitespace (optionally followed by `=` or `:`).
+fn split_key_value(line: &str) -> (&str, &str) {
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' => escaped = true,
+            '=' | ':' => return (&line[..i], line[i + 1..].trim_start()),
+            c if c.is_whitespace() => {
+                let rest = line[i..].trim_start();
+                let rest = rest.strip_prefix(|c| c == '=' || c == ':').unwrap_or(rest);
+                return (&line[..i], rest.trim_start());
+            }
+            _ => {}
+        }
+    }
+
+    (line, "")
 }
 
-fn key_value(line: &str) -> Result<(&str, &str), Box<dyn Error>> {
-    let key_value: Vec<&str> = line.split('=').collect();
-    let key = key_value.first()
-        .ok_or(format!("Illegal config entry (malformed key): {line}"))?;
-    let value = key_value.get(1)
-        .ok_or(format!("Illegal config entry (malformed value): {line}"))?;
+fn unescape(escaped: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
 
-    Ok((key, value))
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('f') => result.push('\u{c}'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                let unicode = Some(&code)
+                    .filter(|code| code.len() == 4 && code.chars().all(|c| c.is_ascii_hexdigit()))
+                    .and_then(|code| u32::from_str_radix(code, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or(format!("Malformed \\uXXXX escape: \\u{code}"))?;
+                result.push(unicode);
+            }
+            Some(c) => result.push(c),
+            // backslash at the end of the last line
+            None => {}
+        }
+    }
+
+    Ok(result)
+}
+
+/// Replaces `${ENV_VAR}` and `${ENV_VAR:-default}` placeholders with environment variables.
+fn resolve_placeholders(key: &str, value: &str, env: &HashMap<String, String>) -> Result<String, String> {
+    lazy_static! {
+        static ref PLACEHOLDER: Regex = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap();
+    }
+
+    let mut missing = None;
+    let resolved = PLACEHOLDER.replace_all(value, |captures: &Captures| {
+        let name = &captures[1];
+        match (env.get(name), captures.get(2)) {
+            (Some(value), _) => value.clone(),
+            (None, Some(default)) => default.as_str().to_string(),
+            (None, None) => {
+                missing.get_or_insert_with(|| name.to_string());
+                String::new()
+            }
+        }
+    });
+
+    match missing {
+        Some(name) => Err(format!("Environment variable {name} (used in {key}) is not set.")),
+        None => Ok(resolved.to_string()),
+    }
+}
+
+/// Prefixes of properties that can be overridden with environment variables.
+const ENV_OVERRIDE_PREFIXES: [&str; 4] = ["processor.", "consumer.", "producer.", "stream."];
+
+/// Maps `KJP_*` environment variables to properties, eg. `KJP_CONSUMER_GROUP_ID` to `consumer.group.id`.
+/// Returns variable name, property key and value.
+///
+/// Only properties with one of [ENV_OVERRIDE_PREFIXES] can be overridden - other `KJP_*` variables are ignored
+/// (with a warning), so that unrelated variables do not end up in rdkafka configs.
+fn env_overrides(env: &HashMap<String, String>) -> Vec<(String, String, String)> {
+    let mut overrides: Vec<(String, String, String)> = env.iter()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix("KJP_")?.to_lowercase().replace('_', ".");
+            if ENV_OVERRIDE_PREFIXES.iter().any(|prefix| key.starts_with(prefix)) {
+                Some((name.clone(), key, value.clone()))
+            } else {
+                warn!("Environment variable {name} is ignored. Only properties starting with {} can be overridden by KJP_* variables.",
+                    ENV_OVERRIDE_PREFIXES.join(", "));
+                None
+            }
+        })
+        .collect();
+
+    overrides.sort();
+    overrides
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, String>
+    where T: FromStr,
+          T::Err: Display,
+{
+    value.trim().parse()
+        .map_err(|e| format!("Invalid value of {key}: '{value}'. {e}"))
 }
 
-fn set_internal(key: &str, value: &str, config: &mut InternalConfig) -> Result<(), Box<dyn Error>> {
+fn set_internal(key: &str, value: &str, config: &mut InternalConfig) -> Result<(), String> {
     match key {
         "processor.channel.capacity" =>
-            config.channel_capacity = value.parse()?,
+            config.channel_capacity = parse(key, value)?,
 
         "processor.worker.threads" =>
-            config.worker_threads = value.parse()?,
+            config.worker_threads = parse(key, value)?,
 
         "processor.queue.size" =>
-            config.queue_size = value.parse()?,
+            config.queue_size = parse(key, value)?,
 
         "processor.queue.slowdown.ms" =>
-            config.queue_slowdown_ms = value.parse()?,
+            warn!("{key} is no longer used (see processor.max.in.flight), it will be ignored."),
+
+        "processor.max.in.flight" =>
+            config.max_in_flight = parse(key, value)?,
 
         "processor.journal.path" =>
-            config.journal_path = value.parse()?,
+            config.journal_path = parse(key, value)?,
         
         "processor.journal.enabled" =>
-            config.journal_enabled = value.parse()?,
+            config.journal_enabled = parse(key, value)?,
+
+        "processor.dead.letter.topic" =>
+            config.dead_letter_topic = Some(value.to_string())
+                .filter(|topic| !topic.trim().is_empty()),
+
+        "processor.delivery.mode" =>
+            config.delivery_mode = parse(key, value)?,
+
+        "processor.delivery.max.attempts" =>
+            config.delivery_max_attempts = parse(key, value)?,
+
+        "processor.commit.interval.ms" =>
+            config.commit_interval_ms = parse(key, value)?,
+
+        "processor.transaction.batch.size" =>
+            config.transaction_batch_size = parse(key, value)?,
+
+        "processor.transaction.interval.ms" =>
+            config.transaction_interval_ms = parse(key, value)?,
+
+        "processor.transaction.timeout.ms" =>
+            config.transaction_timeout_ms = parse(key, value)?,
+
+        "processor.headers.propagate" =>
+            config.propagate_headers = parse(key, value)?,
+
+        "processor.key.policy" =>
+            config.key_policy = parse(key, value)?,
+
+        "processor.null.payload.policy" =>
+            config.null_payload_policy = parse(key, value)?,
+
+        "processor.invalid.payload.policy" =>
+            config.invalid_payload_policy = parse(key, value)?,
+
+        "processor.ordering" =>
+            config.ordering = parse(key, value)?,
+
+        "processor.error.policy" =>
+            config.error_policy = parse(key, value)?,
+
+        "processor.time.budget.ms" =>
+            config.time_budget_ms = parse(key, value)?,
+
+        "processor.dedup.window.ms" =>
+            config.dedup_window_ms = parse(key, value)?,
+
+        "processor.dedup.max.entries" =>
+            config.dedup_max_entries = parse(key, value)?,
+
+        "processor.http.enabled" =>
+            config.http_enabled = parse(key, value)?,
+
+        "processor.http.bind" =>
+            config.http_bind = value.to_string(),
+
+        "processor.http.liveness.timeout.ms" =>
+            config.http_liveness_timeout_ms = parse(key, value)?,
+
+        "processor.shutdown.timeout.ms" =>
+            config.shutdown_timeout_ms = parse(key, value)?,
+
+        _ =>
+            return Err(format!("Unknown property {key}.")),
+    }
+    Ok(())
+}
+
+/// Splits `<name>.<option>` (the part of a stream property after `stream.`) into stream name and option.
+fn split_stream_key(key: &str) -> Option<(&str, &str)> {
+    if let Some(i) = key.find(".producer.") {
+        return Some((&key[..i], &key[i + 1..]))
+            .filter(|(name, option)| !name.is_empty() && option.len() > "producer.".len());
+    }
+
+    STREAM_OPTIONS.iter()
+        .find_map(|option| key.strip_suffix(option)
+            .and_then(|name| name.strip_suffix('.'))
+            .filter(|name| !name.is_empty())
+            .map(|name| (name, *option)))
+}
+
+fn normalize_stream_name(name: &str) -> String {
+    name.to_lowercase().replace('.', "_")
+}
+
+fn set_stream(key: &str, option: &str, value: &str, config: &mut StreamConfig) -> Result<(), String> {
+    match option {
+        "dead.letter.topic" =>
+            config.dead_letter_topic = Some(Some(value.to_string())
+                .filter(|topic| !topic.trim().is_empty())),
+
+        "headers.propagate" =>
+            config.propagate_headers = Some(parse(key, value)?),
+
+        "key.policy" =>
+            config.key_policy = Some(parse(key, value)?),
+
+        "null.payload.policy" =>
+            config.null_payload_policy = Some(parse(key, value)?),
+
+        "invalid.payload.policy" =>
+            config.invalid_payload_policy = Some(parse(key, value)?),
+
+        "ordering" =>
+            config.ordering = Some(parse(key, value)?),
+
+        "error.policy" =>
+            config.error_policy = Some(parse(key, value)?),
+
+        "concurrency" => {
+            let concurrency = parse(key, value)?;
+            if concurrency == 0 {
+                return Err(format!("{key} must be greater than 0."));
+            }
+            config.concurrency = Some(concurrency);
+        }
+
+        "time.budget.ms" =>
+            config.time_budget_ms = Some(parse(key, value)?),
+
+        "state.changelog.topic" =>
+            config.state_changelog_topic = Some(Some(value.to_string())
+                .filter(|topic| !topic.trim().is_empty())),
+
+        "dedup" =>
+            config.dedup = Some(match value.trim() {
+                "" => None,
+                policy => Some(parse(key, policy)?),
+            }),
+
+        "dedup.window.ms" =>
+            config.dedup_window_ms = Some(parse(key, value)?),
+
+        "dedup.max.entries" => {
+            let max_entries = parse(key, value)?;
+            if max_entries == 0 {
+                return Err(format!("{key} must be greater than 0."));
+            }
+            config.dedup_max_entries = Some(max_entries);
+        }
+
+        "aggregation" =>
+            config.aggregation = Some(match value.trim() {
+                "" => None,
+                aggregation => Some(parse(key, aggregation)?),
+            }),
+
+        "lookup.tables" =>
+            config.lookup_tables = Some(value.split(',')
+                .map(str::trim)
+                .filter(|topic| !topic.is_empty())
+                .map(str::to_string)
+                .collect()),
 
         _ => {
-            warn!("Unknown config option: {key}={value}. Ignoring.")
+            // split_stream_key returns only known options and producer.* properties
+            let property = option.strip_prefix("producer.").unwrap_or(option);
+            config.producer_config.retain(|(key, _)| key != property);
+            config.producer_config.push((property.to_string(), value.to_string()));
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::config::{Config, DeliveryMode};
+    use crate::dedup::DedupPolicy;
+    use crate::error_policy::ErrorPolicy;
+    use crate::key::KeyPolicy;
+    use crate::ordering::OrderingPolicy;
+
+    #[test]
+    fn should_resolve_placeholders_and_env_overrides() {
+        let properties = "bootstrap.servers=${KAFKA_HOST}:9092\n\
+            consumer.group.id=${GROUP_ID:-kjp}\n\
+            processor.delivery.mode=auto-commit\n";
+        let env = HashMap::from([
+            ("KAFKA_HOST".to_string(), "kafka".to_string()),
+            ("KJP_PRODUCER_SASL_PASSWORD".to_string(), "secret".to_string()),
+            ("KJP_PROCESSOR_DELIVERY_MODE".to_string(), "at-least-once".to_string()),
+            ("KJP_HOME".to_string(), "/opt/kjp".to_string()),
+            ("KJP_".to_string(), "empty".to_string()),
+        ]);
+
+        let config = Config::from_properties(properties, &env).unwrap();
+        assert_eq!(Some("kafka:9092"), config.consumer_config.get("bootstrap.servers"));
+        assert_eq!(Some("kjp"), config.consumer_config.get("group.id"));
+        assert_eq!(Some("secret"), config.producer_config.get("sasl.password"));
+        assert_eq!(None, config.consumer_config.get("sasl.password"));
+        assert_eq!(DeliveryMode::AtLeastOnce, config.internal_config.delivery_mode);
+        assert_eq!(None, config.consumer_config.get("home"));
+        assert_eq!(None, config.producer_config.get("home"));
+    }
+
+    #[test]
+    fn should_parse_java_properties_syntax() {
+        let properties = r#"
+! comment
+sasl.jaas.config=org.apache.kafka.common.security.plain.PlainLoginModule required \
+    username="user" password="cGFzcw==";
+consumer.group.id : group1
+producer.client.id   kjp\:producer
+processor.dead.letter.topic=
+"#;
+
+        let config = Config::from_properties(properties, &HashMap::new()).unwrap();
+        assert_eq!(
+            Some(r#"org.apache.kafka.common.security.plain.PlainLoginModule required username="user" password="cGFzcw==";"#),
+            config.consumer_config.get("sasl.jaas.config")
+        );
+        assert_eq!(Some("group1"), config.consumer_config.get("group.id"));
+        assert_eq!(Some("kjp:producer"), config.producer_config.get("client.id"));
+        assert_eq!(None, config.internal_config.dead_letter_topic);
+    }
+
+    #[test]
+    fn should_parse_stream_overrides() {
+        let properties = "stream.orders.in_orders.out.concurrency=8\n\
+            stream.orders.in_orders.out.dead.letter.topic=\n\
+            stream.orders.in_orders.out.ordering=key\n\
+            stream.orders.in_orders.out.error.policy=retry:5\n\
+            stream.orders.in_orders.out.time.budget.ms=250\n\
+            stream.orders.in_orders.out.state.changelog.topic=orders_state\n\
+            stream.orders.in_orders.out.dedup=header:event-id\n\
+            stream.orders.in_orders.out.dedup.window.ms=60000\n\
+            stream.orders.in_orders.out.aggregation=group.by=$.customerId;window.ms=60000;orders=count\n\
+            stream.orders.in_orders.out.lookup.tables=customers, products\n\
+            stream.orders.in_orders.out.producer.compression.codec=zstd\n\
+            stream.orders.in_orders.out.producer.acks=all\n";
+        let env = HashMap::from([
+            ("KJP_STREAM_ORDERS_IN_ORDERS_OUT_KEY_POLICY".to_string(), "preserve".to_string()),
+        ]);
+
+        let config = Config::from_properties(properties, &env).unwrap();
+        let stream = config.stream_config("orders.in_orders.out").unwrap();
+        assert_eq!(Some(8), stream.concurrency);
+        assert_eq!(Some(None), stream.dead_letter_topic);
+        assert_eq!(Some(OrderingPolicy::Key), stream.ordering);
+        assert_eq!(Some(ErrorPolicy::Retry { max_attempts: 5, backoff_ms: 100 }), stream.error_policy);
+        assert_eq!(Some(250), stream.time_budget_ms);
+        assert_eq!(Some(Some("orders_state".to_string())), stream.state_changelog_topic);
+        assert!(matches!(&stream.dedup, Some(Some(DedupPolicy::Header(name))) if name == "event-id"));
+        assert_eq!(Some(60000), stream.dedup_window_ms);
+        assert_eq!(Some("group.by=$.customerId;window.ms=60000;advance.ms=60000;lateness.ms=0;orders=count".to_string()),
+                   stream.aggregation.as_ref().and_then(|aggregation| aggregation.as_ref().map(ToString::to_string)));
+        assert_eq!(Some(vec!["customers".to_string(), "products".to_string()]), stream.lookup_tables);
+        assert!(matches!(stream.key_policy, Some(KeyPolicy::Preserve)));
+        assert_eq!(vec![
+            ("compression.codec".to_string(), "zstd".to_string()),
+            ("acks".to_string(), "all".to_string()),
+        ], stream.producer_config);
+        assert!(config.stream_config("orders.in_other").is_none());
+    }
+
+    #[test]
+    fn should_reject_invalid_config_with_location() {
+        let error = |properties: &str| Config::from_properties(properties, &HashMap::new())
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+
+        assert_eq!("line 2: Unknown property processor.unknown.", error("# comment\nprocessor.unknown=1"));
+        assert_eq!("line 1: Invalid value of processor.worker.threads: 'many'. invalid digit found in string", error("processor.worker.threads=many"));
+        assert_eq!("line 1: Environment variable KJP_TEST_MISSING (used in sasl.password) is not set.", error("sasl.password=${KJP_TEST_MISSING}"));
+        assert_eq!("line 1: processor.queue.size must be greater than 0.", error("processor.queue.size=0"));
+        assert_eq!(
+            "line 2: enable.auto.commit=true contradicts processor.delivery.mode=at-least-once (offsets are committed by kafka-json-processor).",
+            error("processor.delivery.mode=at-least-once\nconsumer.enable.auto.commit=true")
+        );
+        assert_eq!(
+            "line 1: producer.transactional.id requires processor.delivery.mode=exactly-once (current mode: auto-commit).",
+            error("producer.transactional.id=kjp")
+        );
+        assert_eq!("line 1: stream.in_out.concurrency must be greater than 0.", error("stream.in_out.concurrency=0"));
+        assert!(error("stream.in_out.unknown=1").starts_with("line 1: Unknown property stream.in_out.unknown."));
+        assert_eq!(
+            "line 3: stream.in_out.producer.acks contradicts processor.delivery.mode=exactly-once (all streams must share the transactional producer).",
+            error("processor.delivery.mode=exactly-once\nproducer.transactional.id=kjp\nstream.in_out.producer.acks=1")
+        );
+    }
+}
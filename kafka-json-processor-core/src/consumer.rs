@@ -1,64 +1,676 @@
 use std::collections::HashMap;
-use crossbeam_channel::Sender;
-use log::{debug, error, trace, warn};
-use rdkafka::consumer::StreamConsumer;
-use rdkafka::Message;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use log::{debug, error, info, trace, warn};
 use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::mpsc::Sender;
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, timeout};
+use serde_json::Value;
 use crate::{MessageOffset, PendingMessage, Stream};
-use crate::processor::{process_payload, ProcessingResult};
+use crate::aggregation::{Aggregated, Aggregation};
+use crate::dead_letter::{DeadLetter, DeadLetterTarget, PROCESSING_ATTEMPTS_HEADER};
+use crate::delivery::OffsetTracker;
+use crate::error::{DetachedMessageError, ErrorKind, MessageError};
+use crate::error_policy::ErrorPolicy;
+use crate::metrics::metrics;
+use crate::ordering::OrderingPolicy;
+use crate::payload::PayloadPolicy;
+use crate::processor::{process_payload_with_progress, MessageContext, ProcessingResult, Progress, SerializedOutputMessage};
+use crate::shutdown::shutdown_requested;
+use crate::source::{Source, SourceMessage};
+use crate::state::{PartitionState, State, StateStore};
 
-pub async fn consumer_loop(consumer: StreamConsumer, tx: Sender<PendingMessage>, runtime: &Runtime, streams: HashMap<String, Stream>)
-                           -> ProcessingResult<()>
+/// A stream with the channel to its producer.
+pub struct StreamWorker {
+    pub stream: Stream,
+    pub tx: Sender<PendingMessage>,
+    pub state: Arc<StateStore>,
+    /// Limits the number of messages of this stream processed at once (`None` if not limited).
+    pub permits: Option<Arc<Semaphore>>,
+    /// Lanes processing messages in order (`None` if the stream is unordered).
+    pub lanes: Option<OrderedLanes>,
+}
+
+/// A message waiting in an ordered lane.
+struct Job {
+    key: String,
+    payload: Option<Vec<u8>>,
+    context: MessageContext,
+}
+
+/// Tasks processing messages of an ordered stream. Each lane processes its messages one by one
+/// and sends them to the producer in input order, lanes run in parallel.
+pub struct OrderedLanes {
+    ordering: OrderingPolicy,
+    lanes: Vec<mpsc::Sender<Job>>,
+}
+
+impl OrderedLanes {
+    /// Spawns `count` lanes (ordered by the ordering policy of the stream), each with a queue of `capacity` messages.
+    pub fn spawn(runtime: &Runtime, count: usize, capacity: usize, stream: &Stream, state: &Arc<StateStore>, tx: &Sender<PendingMessage>, tracker: &Arc<OffsetTracker>) -> OrderedLanes {
+        let lanes = (0..count)
+            .map(|_| {
+                let (lane_tx, mut lane_rx) = mpsc::channel::<Job>(capacity);
+                let stream = stream.clone();
+                let state = state.clone();
+                let tx = tx.clone();
+                let tracker = tracker.clone();
+                runtime.spawn(async move {
+                    // lane stops when consumer stops (and drops senders)
+                    while let Some(job) = lane_rx.recv().await {
+                        process_and_send(&tx, job.key, job.payload, &stream, &state, job.context, &tracker).await;
+                    }
+                });
+                lane_tx
+            })
+            .collect();
+
+        OrderedLanes { ordering: stream.ordering.unwrap_or(OrderingPolicy::Unordered), lanes }
+    }
+
+    async fn dispatch(&self, job: Job) -> ProcessingResult<()> {
+        let lane = self.ordering.lane(&job.context, self.lanes.len());
+        self.lanes[lane].send(job).await
+            .map_err(|_| "Ordered processing task stopped, cannot continue consuming messages.".into())
+    }
+}
+
+/// Consumes messages and spawns processing tasks until shutdown is requested or the source ends (then returns `Ok`).
+///
+/// Consuming is paused while a stream of the topic has as many messages in processing as its concurrency allows.
+///
+/// When the number of messages in flight reaches `processor.max.in.flight`, the source is paused
+/// (a Kafka consumer pauses assigned partitions, so it stays in the group) until half of those messages are completed.
+pub async fn consumer_loop<S: Source>(source: &S, runtime: &Runtime, streams: HashMap<String, Vec<StreamWorker>>, tracker: Arc<OffsetTracker>, mut shutdown: watch::Receiver<bool>)
+                                      -> ProcessingResult<()>
 {
-    loop {
-        match consumer.recv().await {
-            Ok(message) => {
-                tx.send(PendingMessage::Received).unwrap();
-
-                let payload = message.payload().unwrap().to_vec();
-                let key = format!("{}:{}@{}({})",
-                                  message.topic(),
-                                  message.partition(),
-                                  message.offset(),
-                                  message.timestamp().to_millis().unwrap_or(0)
-                );
-                let message_offset = MessageOffset {
-                    topic: message.topic().to_string(),
-                    partition: message.partition(),
-                    offset: message.offset(),
-                };
+    let mut paused = false;
+    // sources that cannot be paused are not read while paused
+    let mut read_while_paused = false;
 
-                debug!("[{key}] Received message.");
-                trace!("[{key}] Message: {}", String::from_utf8_lossy(&payload));
+    loop {
+        if tracker.in_flight().is_full() {
+            // partitions assigned during a rebalance must be paused too
+            read_while_paused = source.pause();
+            if !paused {
+                debug!("In-flight limit reached, consumer paused.");
+                metrics().consumer_paused(true);
+                paused = true;
+            }
+        }
 
-                if let Some(stream) = streams.get(message.topic()) {
-                    spawn_task(runtime, tx.clone(), key, payload, stream.clone(), message_offset);
-                } else {
-                    warn!("[{key}] Topic {} is unsupported! Ignoring message.", message.topic());
-                }
+        let received = tokio::select! {
+            _ = shutdown_requested(&mut shutdown) => {
+                info!("Consumer stopped.");
+                return Ok(());
+            }
+            offset = tracker.wait_for_undelivered() => {
+                error!("Message from [Topic: {}] [Partition: {}] [Offset: {}] was not delivered, consumer stopped.", offset.topic, offset.partition, offset.offset);
+                return Err("Producer stopped, cannot continue consuming messages.".into());
             }
-            Err(e) => {
+            _ = tracker.in_flight().wait_for_resume(), if paused => {
+                source.resume();
+                debug!("Consumer resumed.");
+                metrics().consumer_paused(false);
+                paused = false;
+                continue;
+            }
+            // messages fetched before pausing can still be received
+            received = source.recv(), if !paused || read_while_paused => received,
+        };
+
+        let SourceMessage { context, payload } = match received {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
                 error!("Cannot consume message! Reason: {e}");
+                continue;
             }
+            None => {
+                info!("End of input, consumer stopped.");
+                return Ok(());
+            }
+        };
+
+        let key = format!("{}:{}@{}({})",
+                          context.topic,
+                          context.partition,
+                          context.offset,
+                          context.timestamp.unwrap_or(0)
+        );
+        let message_offset = MessageOffset {
+            topic: context.topic.clone(),
+            partition: context.partition,
+            offset: context.offset,
+        };
+
+        debug!("[{key}] Received message.");
+        trace!("[{key}] Message: {}", payload.as_deref().map(String::from_utf8_lossy).unwrap_or_else(|| "<tombstone>".into()));
+
+        if let Some(workers) = streams.get(&context.topic) {
+            // every stream consuming this topic produces its own output message
+            tracker.received(&message_offset, workers.len());
+            for worker in workers {
+                if worker.tx.is_closed() {
+                    return Err("Producer stopped, cannot continue consuming messages.".into());
+                }
+
+                if let Some(lanes) = &worker.lanes {
+                    lanes.dispatch(Job { key: key.clone(), payload: payload.clone(), context: context.clone() }).await?;
+                    continue;
+                }
+
+                let permit = match &worker.permits {
+                    Some(permits) => Some(permits.clone().acquire_owned().await?),
+                    None => None,
+                };
+                spawn_task(runtime, worker, key.clone(), payload.clone(), context.clone(), tracker.clone(), permit);
+            }
+        } else {
+            warn!("[{key}] Topic {} is unsupported! Ignoring message.", context.topic);
+            tracker.received(&message_offset, 1);
+            tracker.skipped(message_offset);
         }
     }
 }
 
-fn spawn_task(runtime: &Runtime, tx: Sender<PendingMessage>, key: String, payload: Vec<u8>, stream: Stream, message_offset: MessageOffset) {
+/// Spawns a task processing the message. The permit (if any) is released when the task finishes.
+fn spawn_task(runtime: &Runtime, worker: &StreamWorker, key: String, payload: Option<Vec<u8>>, context: MessageContext, tracker: Arc<OffsetTracker>, permit: Option<OwnedSemaphorePermit>) {
+    let tx = worker.tx.clone();
+    let stream = worker.stream.clone();
+    let state = worker.state.clone();
     runtime.spawn(async move {
-        match process_payload(key.clone(), &payload, stream.processors) {
-            Ok(processed) => {
-                trace!("[{key}] Output: {}", processed.message);
-                tx.send(PendingMessage::Processed {
-                    id: key,
-                    topic: stream.target_topic.clone(),
-                    offset: message_offset,
-                    message: processed,
-                }).unwrap();
+        let _permit = permit;
+        process_and_send(&tx, key, payload, &stream, &state, context, &tracker).await;
+    });
+}
+
+/// Processes the message and sends the results to the producer (unless nothing should be produced).
+async fn process_and_send(tx: &Sender<PendingMessage>, key: String, payload: Option<Vec<u8>>, stream: &Stream, state: &Arc<StateStore>, context: MessageContext, tracker: &OffsetTracker) {
+    let id = key.clone();
+    for pending in process(key, payload, stream, state, context, tracker).await {
+        if tx.send(pending).await.is_err() {
+            error!("[{id}] Producer stopped, message will not be produced.");
+        }
+    }
+}
+
+/// Processes the message. Returns output messages to produce: one (or none) for a processed message,
+/// any number of summaries for a message of an aggregating stream.
+async fn process(key: String, payload: Option<Vec<u8>>, stream: &Stream, state: &Arc<StateStore>, context: MessageContext, tracker: &OffsetTracker) -> Vec<PendingMessage> {
+    let message_offset = MessageOffset {
+        topic: context.topic.clone(),
+        partition: context.partition,
+        offset: context.offset,
+    };
+    let input_key = context.key.clone();
+    let input_headers = if stream.propagate_headers.unwrap_or_default() {
+        context.headers.clone()
+    } else {
+        vec![]
+    };
+
+    metrics().consumed(stream);
+    let dedup_claim = match state.dedup().and_then(|dedup| dedup.id_of(payload.as_deref(), &context).map(|id| dedup.claim(id))) {
+        Some(Err(id)) => {
+            debug!("[{key}] Duplicate of a message with id [{}]. Nothing will be produced to [{}].", id.id, stream.target_topic);
+            metrics().duplicate(stream);
+            tracker.skipped(message_offset);
+            return vec![];
+        }
+        Some(Ok(claim)) => Some(claim),
+        None => None,
+    };
+
+    // windows are loaded before processing, because errors of processing cannot be kept while waiting
+    let windows = match state.aggregation() {
+        Some(aggregation) => Some((aggregation, state.partition(context.partition).await)),
+        None => None,
+    };
+
+    let processing_start = Instant::now();
+    let (result, attempts) = match &payload {
+        Some(payload) => process_with_retries(&key, payload, &context, stream, state).await,
+        None => (Err(MessageError::new(ErrorKind::NullPayload)), 1),
+    };
+    metrics().processing_time(stream, processing_start.elapsed());
+    // every outcome from here on is final (the message is produced, filtered out, skipped or dead-lettered),
+    // so the id is remembered - the claim is released only if processing is cancelled
+    if let Some(claim) = dedup_claim {
+        claim.confirm();
+    }
+    // attempts are reported only by streams that retry messages
+    let attempts = Some(attempts).filter(|_| matches!(stream.error_policy, Some(ErrorPolicy::Retry { .. })));
+
+    // aggregating streams produce summaries of closed windows instead of processed messages
+    let result = match (result, windows, &payload) {
+        (Ok(_), Some((aggregation, Ok(partition))), Some(payload)) => match aggregate(&key, payload, aggregation, stream, &partition, &context) {
+            Ok(summaries) => {
+                return summarized(&key, summaries, stream, message_offset, tracker);
             }
-            Err(e) => {
-                error!("[{key}] Processing error: {e}. Message will be ignored and lost.");
+            Err(e) => Err(e),
+        },
+        (Ok(_), Some((_, Err(e))), _) => Err(MessageError::new(ErrorKind::OtherError { err: e.into() })),
+        (result, _, _) => result,
+    };
+
+    let pending = match result {
+        Ok(mut processed) => {
+            if let Some(attempts) = attempts {
+                processed.headers.push((PROCESSING_ATTEMPTS_HEADER.to_string(), attempts.to_string().into_bytes()));
+            }
+            processed.headers = merge_headers(input_headers, processed.headers);
+            metrics().produced(stream);
+            trace!("[{key}] Output: {}", processed.payload.as_deref().map(String::from_utf8_lossy).unwrap_or_else(|| "<tombstone>".into()));
+            PendingMessage::Processed {
+                id: key.clone(),
+                topic: processed.topic.clone().unwrap_or_else(|| stream.target_topic.clone()),
+                offset: message_offset,
+                message: processed,
+                dead_letter: stream.dead_letter_topic.clone().map(|topic| DeadLetterTarget {
+                    topic,
+                    payload: payload.unwrap_or_default(),
+                }),
+            }
+        }
+        Err(MessageError { inner: ErrorKind::MessageFiltered { reason }, .. }) => {
+            debug!("[{key}] Message filtered out: {reason}. Nothing will be produced to [{}].", stream.target_topic);
+            metrics().dropped(stream);
+            tracker.skipped(message_offset);
+            return vec![];
+        }
+        Err(e) => {
+            let policy = match e.inner {
+                ErrorKind::NullPayload => stream.null_payload_policy,
+                ErrorKind::InvalidPayload { .. } => stream.invalid_payload_policy,
+                _ => None,
+            }.unwrap_or(PayloadPolicy::DeadLetter);
+
+            match (policy, stream.dead_letter_topic.clone()) {
+                (PayloadPolicy::Forward, dead_letter_topic) => {
+                    debug!("[{key}] {e} Message will be forwarded to [{}] as it is.", stream.target_topic);
+                    metrics().produced(stream);
+                    PendingMessage::Processed {
+                        id: key.clone(),
+                        topic: stream.target_topic.clone(),
+                        offset: message_offset,
+                        message: SerializedOutputMessage {
+                            key: input_key,
+                            topic: None,
+                            headers: input_headers,
+                            payload: payload.clone(),
+                        },
+                        dead_letter: dead_letter_topic.map(|topic| DeadLetterTarget {
+                            topic,
+                            payload: payload.unwrap_or_default(),
+                        }),
+                    }
+                }
+                (PayloadPolicy::Skip, _) => {
+                    debug!("[{key}] {e} Message will be skipped.");
+                    metrics().dropped(stream);
+                    tracker.skipped(message_offset);
+                    return vec![];
+                }
+                (PayloadPolicy::DeadLetter, Some(topic)) => {
+                    warn!("[{key}] Processing error: {e}. Message will be sent to dead-letter topic [{topic}].");
+                    metrics().failed(stream);
+                    PendingMessage::DeadLetter {
+                        id: key.clone(),
+                        offset: message_offset,
+                        message: DeadLetter {
+                            topic,
+                            payload: payload.unwrap_or_default(),
+                            error_kind: e.inner.name().to_string(),
+                            error: e.inner.to_string(),
+                            processor: e.processor,
+                            attempts,
+                        },
+                    }
+                }
+                (PayloadPolicy::DeadLetter, None) => {
+                    error!("[{key}] Processing error: {e}. Message will be ignored and lost.");
+                    metrics().failed(stream);
+                    tracker.skipped(message_offset);
+                    return vec![];
+                }
+            }
+        }
+    };
+
+    vec![pending]
+}
+
+/// Adds the input message to windows of its partition. Returns summaries (with their keys) of windows closed by it.
+fn aggregate(key: &str, payload: &[u8], aggregation: &Aggregation, stream: &Stream, partition: &PartitionState, context: &MessageContext) -> Result<Vec<(String, Value)>, MessageError> {
+    // processors have already parsed the payload, so it's valid JSON
+    let input: Value = serde_json::from_slice(payload)
+        .map_err(|e| MessageError::new(ErrorKind::InvalidPayload { err: e.into() }))?;
+
+    match partition.aggregate(aggregation, &input, context.timestamp, context.offset).map_err(|e| MessageError::new(e.inner))? {
+        Aggregated::Closed(summaries) => Ok(summaries),
+        Aggregated::Late { timestamp, watermark } => {
+            debug!("[{key}] Late message (timestamp {timestamp}, watermark {watermark}), its windows are closed. Nothing will be produced to [{}].", stream.target_topic);
+            metrics().late(stream);
+            Ok(vec![])
+        }
+        Aggregated::Replayed => {
+            debug!("[{key}] Message was aggregated before the last checkpoint, skipping.");
+            Ok(vec![])
+        }
+    }
+}
+
+/// Creates output messages with summaries of closed windows (keyed by their group keys).
+fn summarized(key: &str, summaries: Vec<(String, Value)>, stream: &Stream, offset: MessageOffset, tracker: &OffsetTracker) -> Vec<PendingMessage> {
+    if summaries.is_empty() {
+        tracker.skipped(offset);
+        return vec![];
+    }
+
+    // the message was registered with a single output
+    tracker.additional_outputs(&offset, summaries.len() - 1);
+    summaries.into_iter()
+        .map(|(group, summary)| {
+            let payload = serde_json::to_vec(&summary).expect("summaries are valid JSON-s");
+            metrics().produced(stream);
+            trace!("[{key}] Summary: {}", String::from_utf8_lossy(&payload));
+            PendingMessage::Processed {
+                id: key.to_string(),
+                topic: stream.target_topic.clone(),
+                offset: offset.clone(),
+                message: SerializedOutputMessage {
+                    key: Some(group.into_bytes()),
+                    topic: None,
+                    headers: vec![],
+                    payload: Some(payload.clone()),
+                },
+                dead_letter: stream.dead_letter_topic.clone().map(|topic| DeadLetterTarget { topic, payload }),
+            }
+        })
+        .collect()
+}
+
+/// Processes the payload. Transient errors are retried with a backoff if the error policy of the stream allows it.
+///
+/// Returns the result of the last attempt and the number of attempts.
+async fn process_with_retries(key: &str, payload: &[u8], context: &MessageContext, stream: &Stream, state: &Arc<StateStore>) -> (Result<SerializedOutputMessage, MessageError>, u32) {
+    let partition = match state.partition(context.partition).await {
+        Ok(partition) => partition,
+        Err(e) => return (Err(MessageError::new(ErrorKind::OtherError { err: e.into() })), 1),
+    };
+    let mut attempt = 1;
+
+    loop {
+        // errors cannot be sent between threads, so the result must not be kept while waiting for a retry
+        let backoff = {
+            // changes of failed attempts are discarded
+            let result = run_processors(key, payload, context, stream, State::new(partition.clone())).await;
+
+            match (&result, stream.error_policy) {
+                (Err(e @ MessageError { inner: ErrorKind::Transient { .. }, .. }), Some(policy @ ErrorPolicy::Retry { max_attempts, .. })) => {
+                    if attempt >= max_attempts {
+                        warn!("[{key}] {e}. Giving up after {attempt} attempt(s).");
+                        return (result, attempt);
+                    }
+
+                    let backoff = policy.backoff(attempt);
+                    warn!("[{key}] {e}. Retrying in {}ms (attempt {} of {max_attempts})...", backoff.as_millis(), attempt + 1);
+                    backoff
+                }
+                (Ok(_), _) if attempt > 1 => {
+                    info!("[{key}] Message processed after {attempt} attempts.");
+                    return (result, attempt);
+                }
+                _ => return (result, attempt),
             }
         };
-    });
-}
\ No newline at end of file
+
+        metrics().retried(stream);
+        sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// Processes the payload. If the stream has a time budget, processors run on a blocking thread, so a stuck processor
+/// does not block a worker - the message is abandoned when it is not processed within the budget of all processors
+/// (the processor cannot be stopped, so it keeps its thread until it returns).
+async fn run_processors(key: &str, payload: &[u8], context: &MessageContext, stream: &Stream, state: State) -> Result<SerializedOutputMessage, MessageError> {
+    let Some(budget) = stream.time_budget() else {
+        return process_payload_with_progress(key.to_string(), payload, context.clone(), stream, stream.processors, state, &Progress::default());
+    };
+
+    let progress = Arc::new(Progress::default());
+    let mut task = {
+        let (id, payload, context, stream, progress) = (key.to_string(), payload.to_vec(), context.clone(), stream.clone(), progress.clone());
+        spawn_blocking(move || process_payload_with_progress(id, &payload, context, &stream, stream.processors, state, &progress)
+            .map_err(DetachedMessageError::from))
+    };
+
+    let limit = budget.saturating_mul(stream.processors.len().max(1) as u32);
+    let result = match timeout(limit, &mut task).await {
+        Ok(result) => result,
+        // the abandoned task keeps running, but its state changes are not committed
+        Err(_) if progress.abandon() => {
+            let processor = progress.processor.load(Ordering::Relaxed);
+            error!("[{key}]#{processor} Processing did not finish within {}ms. Message is abandoned.", limit.as_millis());
+            metrics().slow_processor(stream, processor);
+            return Err(MessageError { inner: ErrorKind::TimedOut { budget, elapsed: None }, processor: Some(processor) });
+        }
+        // processing has just finished (and its changes are committed), so its result must be used
+        Err(_) => task.await,
+    };
+
+    match result {
+        Ok(result) => result.map_err(MessageError::from),
+        // panics of processors are caught, so this is a panic of kafka-json-processor itself
+        Err(e) => Err(MessageError::new(ErrorKind::Panicked { message: e.to_string() })),
+    }
+}
+
+/// Input headers are overridden by output headers with the same name.
+fn merge_headers(input: Vec<(String, Vec<u8>)>, output: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+    let mut headers: Vec<(String, Vec<u8>)> = input.into_iter()
+        .filter(|(name, _)| !output.iter().any(|(output_name, _)| output_name == name))
+        .collect();
+
+    headers.extend(output);
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use serde_json::Value;
+    use crate::consumer::merge_headers;
+    use crate::dead_letter::{ERROR_KIND_HEADER, ERROR_MESSAGE_HEADER, PROCESSING_ATTEMPTS_HEADER, PROCESSOR_INDEX_HEADER};
+    use crate::error::{ErrorKind, ProcessingError};
+    use crate::memory::{MemoryBroker, MemoryMessage};
+    use crate::processor::{ObjectKey, ObjectTree, OutputMessage, Processor};
+    use crate::Stream;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    static FLAKY_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Fails transiently twice, then succeeds.
+    fn flaky(_input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        if FLAKY_CALLS.fetch_add(1, Ordering::SeqCst) < 2 {
+            return Err(ProcessingError::transient("service unavailable"));
+        }
+        message.insert_val(&[ObjectKey::Key("done".to_string())], Value::Bool(true))?;
+        Ok(())
+    }
+
+    fn unavailable(_input: &Value, _message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        Err(ProcessingError::transient("service unavailable"))
+    }
+
+    fn broken(_input: &Value, _message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        Err(ProcessingError::new(ErrorKind::OtherError { err: "broken".into() }))
+    }
+
+    fn copy_id(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        let id = input.get_val(&[ObjectKey::Key("id".to_string())])?.clone();
+        message.insert_val(&[ObjectKey::Key("id".to_string())], id)?;
+        Ok(())
+    }
+
+    fn panicking(_input: &Value, _message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        panic!("unexpected input");
+    }
+
+    fn slow(_input: &Value, _message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        std::thread::sleep(Duration::from_millis(100));
+        Ok(())
+    }
+
+    fn stuck(_input: &Value, _message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        std::thread::sleep(Duration::from_secs(2));
+        Ok(())
+    }
+
+    /// Marks slow messages in the state (then gets stuck) and reports whether a slow message was seen before.
+    fn mark_slow(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        let seen = message.state().get("slow").is_some();
+        if input.get("slow").is_some() {
+            message.state().put("slow", Value::Bool(true));
+            std::thread::sleep(Duration::from_millis(300));
+        }
+        message.insert_val(&[ObjectKey::Key("slow_seen".to_string())], Value::Bool(seen))?;
+        Ok(())
+    }
+
+    fn stream(processors: &'static [Processor], error_policy: &str) -> Stream {
+        Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors,
+            dead_letter_topic: Some("dlq".to_string()),
+            error_policy: Some(error_policy.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Processes a single message with given processors and error policy. Returns the message produced to `topic`.
+    fn process_one(processors: &'static [Processor], error_policy: &str, topic: &str) -> MemoryMessage {
+        process_with_stream(stream(processors, error_policy), topic)
+    }
+
+    fn process_with_stream(stream: Stream, topic: &str) -> MemoryMessage {
+        let broker = MemoryBroker::new();
+        broker.create_topic("in", 1).create_topic("out", 1).create_topic("dlq", 1);
+        broker.publish("in", None, Some(br#"{"id": 1}"#));
+
+        let streams = HashMap::from([("in_out".to_string(), stream)]);
+        let processor = broker.start(streams, "processor.journal.enabled=false".parse().unwrap());
+
+        let message = broker.wait_for_messages(topic, 1, TIMEOUT).remove(0);
+        assert_eq!(Ok(true), processor.stop());
+        assert_eq!(1, broker.messages("out").len() + broker.messages("dlq").len());
+        message
+    }
+
+    #[test]
+    fn should_retry_transient_errors() {
+        let message = process_one(&[&flaky], "retry:3:1", "out");
+
+        assert_eq!(Some(r#"{"done":true}"#), message.payload_str());
+        assert_eq!(Some(b"3".as_slice()), message.header(PROCESSING_ATTEMPTS_HEADER));
+    }
+
+    #[test]
+    fn should_fail_message_after_last_attempt() {
+        let message = process_one(&[&unavailable], "retry:2:1", "dlq");
+
+        assert_eq!(Some(b"Transient".as_slice()), message.header(ERROR_KIND_HEADER));
+        assert_eq!(Some(b"2".as_slice()), message.header(PROCESSING_ATTEMPTS_HEADER));
+    }
+
+    #[test]
+    fn should_fail_or_continue_on_errors_by_policy() {
+        let message = process_one(&[&broken, &copy_id], "fail", "dlq");
+        assert_eq!(Some(b"OtherError".as_slice()), message.header(ERROR_KIND_HEADER));
+        assert_eq!(None, message.header(PROCESSING_ATTEMPTS_HEADER));
+
+        let message = process_one(&[&broken, &copy_id], "continue", "out");
+        assert_eq!(Some(r#"{"id":1}"#), message.payload_str());
+    }
+
+    #[test]
+    fn should_catch_panics_of_processors() {
+        let message = process_one(&[&panicking, &copy_id], "continue", "out");
+        assert_eq!(Some(r#"{"id":1}"#), message.payload_str());
+
+        let message = process_one(&[&panicking, &copy_id], "fail", "dlq");
+        assert_eq!(Some(b"Panicked".as_slice()), message.header(ERROR_KIND_HEADER));
+        assert_eq!(Some(b"Processor panicked: unexpected input".as_slice()), message.header(ERROR_MESSAGE_HEADER));
+    }
+
+    #[test]
+    fn should_fail_processors_exceeding_time_budget() {
+        let message = process_with_stream(Stream {
+            time_budget_ms: Some(20),
+            ..stream(&[&slow, &copy_id, &copy_id, &copy_id, &copy_id, &copy_id, &copy_id, &copy_id, &copy_id, &copy_id], "fail")
+        }, "dlq");
+
+        assert_eq!(Some(b"TimedOut".as_slice()), message.header(ERROR_KIND_HEADER));
+        assert_eq!(Some(b"0".as_slice()), message.header(PROCESSOR_INDEX_HEADER));
+    }
+
+    #[test]
+    fn should_abandon_messages_stuck_in_processors() {
+        let message = process_with_stream(Stream {
+            time_budget_ms: Some(20),
+            ..stream(&[&copy_id, &stuck], "continue")
+        }, "dlq");
+
+        assert_eq!(Some(b"TimedOut".as_slice()), message.header(ERROR_KIND_HEADER));
+        assert_eq!(Some(b"1".as_slice()), message.header(PROCESSOR_INDEX_HEADER));
+    }
+
+    #[test]
+    fn should_not_commit_state_of_abandoned_messages() {
+        let broker = MemoryBroker::new();
+        broker.create_topic("in", 1).create_topic("out", 1).create_topic("dlq", 1).create_topic("changes", 1);
+        broker.publish("in", None, Some(br#"{"id": 1, "slow": true}"#));
+
+        let streams = HashMap::from([("in_out".to_string(), Stream {
+            time_budget_ms: Some(20),
+            state_changelog_topic: Some("changes".to_string()),
+            ..stream(&[&mark_slow], "continue")
+        })]);
+        let processor = broker.start(streams, "processor.journal.enabled=false".parse().unwrap());
+
+        let dead_letter = broker.wait_for_messages("dlq", 1, TIMEOUT).remove(0);
+        // the abandoned processor returns in the meantime
+        std::thread::sleep(Duration::from_millis(500));
+        broker.publish("in", None, Some(br#"{"id": 2}"#));
+        let message = broker.wait_for_messages("out", 1, TIMEOUT).remove(0);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!(Some(b"TimedOut".as_slice()), dead_letter.header(ERROR_KIND_HEADER));
+        assert_eq!(Some(r#"{"slow_seen":false}"#), message.payload_str());
+        assert!(broker.messages("changes").is_empty());
+    }
+
+    #[test]
+    fn should_override_input_headers_with_output_headers() {
+        let input = vec![
+            ("trace-id".to_string(), b"abc".to_vec()),
+            ("content-type".to_string(), b"application/xml".to_vec()),
+        ];
+        let output = vec![
+            ("content-type".to_string(), b"application/json".to_vec()),
+        ];
+
+        assert_eq!(vec![
+            ("trace-id".to_string(), b"abc".to_vec()),
+            ("content-type".to_string(), b"application/json".to_vec()),
+        ], merge_headers(input, output));
+    }
+}
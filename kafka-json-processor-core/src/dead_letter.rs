@@ -0,0 +1,101 @@
+use crate::MessageOffset;
+
+pub const ERROR_KIND_HEADER: &str = "kjp-error-kind";
+pub const ERROR_MESSAGE_HEADER: &str = "kjp-error-message";
+pub const SOURCE_TOPIC_HEADER: &str = "kjp-source-topic";
+pub const SOURCE_PARTITION_HEADER: &str = "kjp-source-partition";
+pub const SOURCE_OFFSET_HEADER: &str = "kjp-source-offset";
+pub const PROCESSOR_INDEX_HEADER: &str = "kjp-processor-index";
+/// Number of attempts of processing the message (set by streams with `retry` error policy).
+pub const PROCESSING_ATTEMPTS_HEADER: &str = "kjp-processing-attempts";
+
+/// Dead-letter topic of a stream, along with the original payload of the message.
+///
+/// This is passed along with processed messages, so the original message can be
+/// sent to the dead-letter topic if the processed message cannot be produced.
+pub struct DeadLetterTarget {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl DeadLetterTarget {
+    pub fn into_dead_letter(self, error_kind: &str, error: String, processor: Option<usize>) -> DeadLetter {
+        DeadLetter {
+            topic: self.topic,
+            payload: self.payload,
+            error_kind: error_kind.to_string(),
+            error,
+            processor,
+            attempts: None,
+        }
+    }
+}
+
+/// A message that could not be processed (or produced).
+///
+/// It contains the original payload and the description of the error.
+pub struct DeadLetter {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub error_kind: String,
+    pub error: String,
+    pub processor: Option<usize>,
+    /// Number of attempts of processing the message (if it was processed with `retry` error policy).
+    pub attempts: Option<u32>,
+}
+
+impl DeadLetter {
+    /// Creates headers describing the error and the source of the message.
+    pub fn headers(&self, source: &MessageOffset) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec![
+            (ERROR_KIND_HEADER.to_string(), self.error_kind.as_bytes().to_vec()),
+            (ERROR_MESSAGE_HEADER.to_string(), self.error.as_bytes().to_vec()),
+            (SOURCE_TOPIC_HEADER.to_string(), source.topic.as_bytes().to_vec()),
+            (SOURCE_PARTITION_HEADER.to_string(), source.partition.to_string().into_bytes()),
+            (SOURCE_OFFSET_HEADER.to_string(), source.offset.to_string().into_bytes()),
+        ];
+
+        if let Some(i) = self.processor {
+            headers.push((PROCESSOR_INDEX_HEADER.to_string(), i.to_string().into_bytes()));
+        }
+
+        if let Some(attempts) = self.attempts {
+            headers.push((PROCESSING_ATTEMPTS_HEADER.to_string(), attempts.to_string().into_bytes()));
+        }
+
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dead_letter::DeadLetterTarget;
+    use crate::MessageOffset;
+
+    #[test]
+    fn should_describe_error_in_headers() {
+        let dead_letter = DeadLetterTarget {
+            topic: "dlq".to_string(),
+            payload: b"{invalid".to_vec(),
+        }.into_dead_letter("InvalidPayload", "Invalid message payload".to_string(), Some(2));
+
+        let headers = dead_letter.headers(&MessageOffset {
+            topic: "in".to_string(),
+            partition: 3,
+            offset: 42,
+        });
+
+        let headers: Vec<(&str, &[u8])> = headers.iter()
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+            .collect();
+
+        assert_eq!(vec![
+            ("kjp-error-kind", "InvalidPayload".as_bytes()),
+            ("kjp-error-message", "Invalid message payload".as_bytes()),
+            ("kjp-source-topic", "in".as_bytes()),
+            ("kjp-source-partition", "3".as_bytes()),
+            ("kjp-source-offset", "42".as_bytes()),
+            ("kjp-processor-index", "2".as_bytes()),
+        ], headers);
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, error, warn};
+use serde_json::{json, Value};
+use crate::error::{ErrorKind, ProcessingError};
+use crate::processor::{json_path_to_object_keys, MessageContext, ObjectKey, ObjectTree, OutputMessage};
+
+/// Checkpoint of seen ids (in the state directory of the stream).
+const CHECKPOINT_FILE: &str = "dedup.json";
+
+/// Where the dedup id of a message is taken from.
+#[derive(Clone, Debug)]
+pub enum DedupPolicy {
+    /// Key of the input message.
+    Key,
+    /// Value of a header of the input message.
+    Header(String),
+    /// A field of the input message.
+    JsonPath(Vec<ObjectKey>),
+}
+
+impl DedupPolicy {
+    /// Returns the dedup id of the message (`None` if the message has no such key, header or field).
+    pub fn id_of(&self, payload: Option<&[u8]>, context: &MessageContext) -> Option<String> {
+        match self {
+            DedupPolicy::Key => context.key.as_deref()
+                .map(|key| String::from_utf8_lossy(key).into_owned()),
+            DedupPolicy::Header(name) => context.headers.iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| String::from_utf8_lossy(value).into_owned()),
+            DedupPolicy::JsonPath(path) => {
+                let input: Value = serde_json::from_slice(payload?).ok()?;
+                match input.get_val(path).ok()? {
+                    Value::String(value) => Some(value.clone()),
+                    Value::Null => None,
+                    value => Some(value.to_string()),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for DedupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "key" {
+            return Ok(DedupPolicy::Key);
+        }
+
+        match s.split_once(':') {
+            Some(("header", name)) if !name.trim().is_empty() => Ok(DedupPolicy::Header(name.trim().to_string())),
+            Some(("jsonpath", path)) if !path.trim().is_empty() => Ok(DedupPolicy::JsonPath(json_path_to_object_keys(path.trim()))),
+            _ => Err(format!("Unknown dedup policy: {s}. Available policies: key, header:<name>, jsonpath:$.field.")),
+        }
+    }
+}
+
+impl Display for DedupPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DedupPolicy::Key => write!(f, "key"),
+            DedupPolicy::Header(name) => write!(f, "header:{name}"),
+            DedupPolicy::JsonPath(path) => {
+                let path = path.iter()
+                    .fold("$".to_string(), |acc, key| match key {
+                        ObjectKey::Key(key) => format!("{acc}.{key}"),
+                        ObjectKey::Index(i) => format!("{acc}[{i}]"),
+                    });
+                write!(f, "jsonpath:{path}")
+            }
+        }
+    }
+}
+
+/// Drops the message (with [`ErrorKind::MessageFiltered`]) if another message of its partition with the same id
+/// was processed within the window.
+///
+/// The id is remembered with the state of the partition ([`OutputMessage::state`]) - only if the message
+/// is processed, and at most `processor.dedup.max.entries` ids per partition (the oldest are forgotten first).
+/// Unlike stream deduplication (see [`crate::Stream::dedup`]), ids are scoped by partition, so the id should be
+/// derived from the message key (or the input topic should be partitioned by the id). Prefer the `dedup` option
+/// of the stream - deduplicate in a processor only if the id is known after other processors (or only some messages
+/// of the stream should be deduplicated).
+pub fn deduplicate(id: &str, window: Duration, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+    let context = message.context();
+    let source = format!("{}:{}@{}", context.topic, context.partition, context.offset);
+
+    if message.state().seen(id, &source, window) {
+        return Err(ErrorKind::MessageFiltered {
+            reason: format!("Duplicate of a message with id [{id}]")
+        }.into());
+    }
+    Ok(())
+}
+
+/// Drops messages whose dedup id was already seen within the window of the stream (in any partition).
+///
+/// Seen ids are kept in memory (at most `max_entries` of them, the oldest are forgotten first)
+/// and checkpointed with the state of the stream, so duplicates are detected across restarts too.
+/// Ids of messages being processed are claimed (see [`Deduplicator::claim`]), so duplicates processed
+/// at the same time are dropped as well.
+pub(crate) struct Deduplicator {
+    policy: DedupPolicy,
+    window_ms: u64,
+    checkpoint: Option<PathBuf>,
+    ids: Mutex<Ids>,
+    /// Whether there are ids that are not saved in the checkpoint.
+    dirty: AtomicBool,
+}
+
+impl Deduplicator {
+    /// Creates the deduplicator of a stream, loading seen ids from the checkpoint in `dir` (if there is one).
+    pub(crate) fn new(policy: DedupPolicy, window_ms: u64, max_entries: usize, dir: Option<&Path>) -> Deduplicator {
+        let checkpoint = dir.map(|dir| dir.join(CHECKPOINT_FILE));
+        let seen = checkpoint.as_deref()
+            .filter(|path| path.exists())
+            .map(|path| SeenIds::load(path, max_entries).unwrap_or_else(|e| {
+                warn!("{e} Duplicates of messages seen before will not be detected.");
+                SeenIds::new(max_entries)
+            }))
+            .unwrap_or_else(|| SeenIds::new(max_entries));
+
+        Deduplicator {
+            policy,
+            window_ms,
+            checkpoint,
+            ids: Mutex::new(Ids { seen, claimed: HashMap::new() }),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the dedup id of the message (`None` if the message has no id).
+    pub(crate) fn id_of(&self, payload: Option<&[u8]>, context: &MessageContext) -> Option<DedupId> {
+        Some(DedupId {
+            id: self.policy.id_of(payload, context)?,
+            source: format!("{}:{}@{}", context.topic, context.partition, context.offset),
+        })
+    }
+
+    /// Claims the id for the message, or returns it back if another message has already seen (or claimed)
+    /// the id within the window - the message is a duplicate then.
+    ///
+    /// The id is checked and claimed at once. It's remembered when the claim is confirmed
+    /// (see [`DedupClaim::confirm`]) and released if the claim is dropped without that.
+    pub(crate) fn claim(&self, id: DedupId) -> Result<DedupClaim<'_>, DedupId> {
+        self.claim_at(id, now())
+    }
+
+    fn claim_at(&self, id: DedupId, now: u64) -> Result<DedupClaim<'_>, DedupId> {
+        let mut ids = self.ids.lock().unwrap();
+        // the same message consumed again is not a duplicate of itself
+        let seen = ids.seen.seen_by(&id.id, now).is_some_and(|seen_in| seen_in != id.source);
+        let claimed = ids.claimed.get(&id.id).is_some_and(|claimed_by| *claimed_by != id.source);
+        if seen || claimed {
+            return Err(id);
+        }
+
+        ids.claimed.insert(id.id.clone(), id.source.clone());
+        Ok(DedupClaim { dedup: self, id: Some(id) })
+    }
+
+    fn remember_at(&self, id: DedupId, now: u64) {
+        let mut ids = self.ids.lock().unwrap();
+        ids.release(&id);
+        // expired ids are forgotten first, so the id is remembered again
+        ids.seen.seen_by(&id.id, now);
+        ids.seen.remember(id.id, id.source, now.saturating_add(self.window_ms));
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Saves seen ids if they changed since the last flush.
+    pub(crate) fn flush(&self) {
+        let Some(path) = &self.checkpoint else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let content = self.ids.lock().unwrap().seen.to_json();
+        let result = path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| write_checkpoint(path, content));
+        match result {
+            Ok(()) => debug!("Saved seen ids {path:?}."),
+            Err(e) => {
+                error!("Failed to save seen ids {path:?}. Reason: {e}");
+                self.dirty.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Dedup id of a message with the message it was taken from (`<topic>:<partition>@<offset>`).
+pub(crate) struct DedupId {
+    pub(crate) id: String,
+    source: String,
+}
+
+/// Id claimed by a message that is being processed.
+pub(crate) struct DedupClaim<'a> {
+    dedup: &'a Deduplicator,
+    id: Option<DedupId>,
+}
+
+impl DedupClaim<'_> {
+    /// Remembers the id once its message reaches a final outcome (it's produced, filtered out, skipped
+    /// or sent to the dead-letter topic), so later messages with the id are duplicates.
+    pub(crate) fn confirm(self) {
+        self.confirm_at(now())
+    }
+
+    fn confirm_at(mut self, now: u64) {
+        if let Some(id) = self.id.take() {
+            self.dedup.remember_at(id, now);
+        }
+    }
+}
+
+impl Drop for DedupClaim<'_> {
+    fn drop(&mut self) {
+        // processing was cancelled, the message will be consumed again
+        if let Some(id) = &self.id {
+            self.dedup.ids.lock().unwrap().release(id);
+        }
+    }
+}
+
+/// Ids seen by a deduplicated stream and ids claimed by messages being processed (by the message that claimed them).
+struct Ids {
+    seen: SeenIds,
+    claimed: HashMap<String, String>,
+}
+
+impl Ids {
+    fn release(&mut self, id: &DedupId) {
+        if self.claimed.get(&id.id).is_some_and(|claimed_by| *claimed_by == id.source) {
+            self.claimed.remove(&id.id);
+        }
+    }
+}
+
+/// Seen ids with the time they expire (epoch millis) and the message they were seen in.
+pub(crate) struct SeenIds {
+    ids: HashMap<String, (u64, String)>,
+    /// Ids in the order they were seen - each id is here exactly once.
+    order: VecDeque<String>,
+    max_entries: usize,
+}
+
+impl SeenIds {
+    pub(crate) fn new(max_entries: usize) -> SeenIds {
+        SeenIds { ids: HashMap::new(), order: VecDeque::new(), max_entries: max_entries.max(1) }
+    }
+
+    /// Returns the message the id was seen in (if it has not expired yet).
+    pub(crate) fn seen_by(&mut self, id: &str, now: u64) -> Option<&str> {
+        while self.order.front()
+            .and_then(|id| self.ids.get(id))
+            .is_some_and(|(expires_at, _)| *expires_at <= now) {
+            self.forget_oldest();
+        }
+
+        self.ids.get(id)
+            .map(|(_, source)| source.as_str())
+    }
+
+    /// Remembers the id (if it's not remembered yet), forgetting the oldest id if the limit is reached.
+    pub(crate) fn remember(&mut self, id: String, source: String, expires_at: u64) {
+        if self.ids.contains_key(&id) {
+            return;
+        }
+        if self.ids.len() >= self.max_entries {
+            self.forget_oldest();
+        }
+        self.order.push_back(id.clone());
+        self.ids.insert(id, (expires_at, source));
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some(id) = self.order.pop_front() {
+            self.ids.remove(&id);
+        }
+    }
+
+    /// Serializes ids (in the order they were seen) as an array of `[id, expires_at, source]`.
+    pub(crate) fn to_json(&self) -> Vec<u8> {
+        let ids: Vec<Value> = self.order.iter()
+            .filter_map(|id| self.ids.get(id).map(|(expires_at, source)| json!([id, expires_at, source])))
+            .collect();
+        serde_json::to_vec(&ids).expect("seen ids are valid JSON-s")
+    }
+
+    pub(crate) fn load(path: &Path, max_entries: usize) -> Result<SeenIds, String> {
+        let content = fs::read(path)
+            .map_err(|e| format!("Cannot read seen ids {path:?}: {e}."))?;
+        let ids: Vec<(String, u64, String)> = serde_json::from_slice(&content)
+            .map_err(|e| format!("Invalid seen ids {path:?}: {e}."))?;
+
+        let mut seen = SeenIds::new(max_entries);
+        // the limit could have been lowered since the checkpoint was saved
+        for (id, expires_at, source) in ids {
+            seen.remember(id, source, expires_at);
+        }
+        debug!("Loaded {} seen id(s) from {path:?}.", seen.ids.len());
+        Ok(seen)
+    }
+}
+
+/// Writes the file through a temporary file, so a crash does not leave a partial checkpoint.
+pub(crate) fn write_checkpoint(path: &Path, content: Vec<u8>) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, content)
+        .and_then(|_| fs::rename(&tmp_path, path))
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::{DedupId, DedupPolicy, Deduplicator};
+    use crate::processor::MessageContext;
+
+    #[test]
+    fn should_take_dedup_id_from_key_header_or_field() {
+        let context = MessageContext {
+            key: Some(b"k1".to_vec()),
+            headers: vec![("event-id".to_string(), b"e1".to_vec())],
+            ..Default::default()
+        };
+        let payload = Some(br#"{"event": {"id": 42}}"#.as_slice());
+
+        assert_eq!(Some("k1".to_string()), "key".parse::<DedupPolicy>().unwrap().id_of(payload, &context));
+        assert_eq!(Some("e1".to_string()), "header:event-id".parse::<DedupPolicy>().unwrap().id_of(payload, &context));
+        let policy: DedupPolicy = "jsonpath:$.event.id".parse().unwrap();
+        assert_eq!("jsonpath:$.event.id", policy.to_string());
+        assert_eq!(Some("42".to_string()), policy.id_of(payload, &context));
+        assert_eq!(None, policy.id_of(Some(b"not json"), &context));
+        assert!("header:".parse::<DedupPolicy>().is_err());
+    }
+
+    #[test]
+    fn should_detect_duplicates_within_window_with_bounded_memory() {
+        let dir = std::env::temp_dir().join(format!("kjp-dedup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dedup = Deduplicator::new(DedupPolicy::Key, 1000, 2, Some(&dir));
+        let id = |id: &str, source: &str| DedupId { id: id.to_string(), source: source.to_string() };
+        let check = |id: DedupId, now: u64| dedup.claim_at(id, now)
+            .map(|claim| claim.confirm_at(now))
+            .is_err();
+
+        let claim = dedup.claim_at(id("a", "in:0@0"), 0).ok().unwrap();
+        // the id is claimed by another message (also in another partition)
+        assert!(dedup.claim_at(id("a", "in:1@0"), 0).is_err());
+        // the claim of a cancelled message is released
+        drop(claim);
+        let claim = dedup.claim_at(id("a", "in:1@0"), 0).ok().unwrap();
+        drop(claim);
+        assert!(!check(id("a", "in:0@0"), 0));
+        assert!(check(id("a", "in:0@1"), 500));
+        // the same message consumed again is not a duplicate
+        assert!(!check(id("a", "in:0@0"), 500));
+        // the window has passed
+        assert!(!check(id("a", "in:0@2"), 1000));
+        assert!(!check(id("b", "in:0@3"), 1001));
+        // the oldest id is forgotten when the limit is reached
+        assert!(!check(id("c", "in:0@4"), 1002));
+        assert!(!check(id("a", "in:0@5"), 1003));
+
+        dedup.flush();
+        let restored = Deduplicator::new(DedupPolicy::Key, 1000, 2, Some(&dir));
+        assert!(restored.claim_at(id("c", "in:0@6"), 1500).is_err());
+        assert!(restored.claim_at(id("b", "in:0@7"), 1500).is_ok());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
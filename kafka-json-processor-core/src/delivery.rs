@@ -0,0 +1,512 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use log::{debug, error, trace, warn};
+use rdkafka::{ClientContext, Message, Offset, TopicPartitionList};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::consumer::CommitMode;
+use rdkafka::producer::{DeliveryResult, ProducerContext};
+use tokio::sync::watch;
+use crate::config::DeliveryMode;
+use crate::journal::{MessageOffsetHolder, OffsetKey};
+use crate::health::health;
+use crate::in_flight::InFlight;
+use crate::metrics::metrics;
+use crate::MessageOffset;
+use crate::sink::Delivery;
+use crate::source::Source;
+
+/// Tracks offsets of messages that are being processed and decides which offsets can be committed.
+///
+/// In [`DeliveryMode::AutoCommit`], an offset is saved in journal as soon as the output message is enqueued in the producer.
+/// In [`DeliveryMode::AtLeastOnce`], an offset is saved in journal (and committed to Kafka) only if all messages
+/// up to this offset (in given partition) were acknowledged by the broker (or skipped).
+/// In [`DeliveryMode::ExactlyOnce`], an offset is committed with a transaction if all messages
+/// up to this offset were enqueued in this (or previous) transaction.
+///
+/// In every mode, it counts output messages in flight (until they are delivered, skipped or failed).
+pub struct OffsetTracker {
+    mode: DeliveryMode,
+    offset_holder: Arc<MessageOffsetHolder>,
+    partitions: Mutex<HashMap<OffsetKey, PartitionProgress>>,
+    in_flight: InFlight,
+    /// The first message that was not delivered in at-least-once or exactly-once delivery mode
+    /// (its offset can never be committed, nor can the transaction with it).
+    undelivered: watch::Sender<Option<MessageOffset>>,
+    max_attempts: usize,
+    attempts: Arc<DeliveryAttempts>,
+}
+
+/// Counts failed deliveries of consumed messages in at-least-once delivery mode.
+///
+/// A message that was not delivered is consumed again after the runtime is restarted, so attempts are counted
+/// across restarts (the same instance is passed to every [`OffsetTracker`]). When a message runs out of attempts,
+/// the producer sends it to the dead-letter topic (or stops kafka-json-processor), instead of restarting forever.
+#[derive(Default)]
+pub struct DeliveryAttempts {
+    failed: Mutex<HashMap<(OffsetKey, i64), usize>>,
+}
+
+impl DeliveryAttempts {
+    /// Number of failed deliveries of (output messages of) the message.
+    fn failed(&self, offset: &MessageOffset) -> usize {
+        self.failed.lock().unwrap()
+            .get(&(OffsetKey(offset.topic.clone(), offset.partition), offset.offset))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn add_failure(&self, offset: &MessageOffset) {
+        *self.failed.lock().unwrap()
+            .entry((OffsetKey(offset.topic.clone(), offset.partition), offset.offset))
+            .or_default() += 1;
+    }
+
+    /// Forgets failures of all messages of the partition up to given offset (they were acknowledged).
+    fn acknowledged(&self, offset_key: &OffsetKey, last_acknowledged: i64) {
+        let mut failed = self.failed.lock().unwrap();
+        if !failed.is_empty() {
+            failed.retain(|(key, offset), _| key != offset_key || *offset > last_acknowledged);
+        }
+    }
+}
+
+#[derive(Default)]
+struct PartitionProgress {
+    /// Offsets that were received, but are not acknowledged yet (with the number of output messages to acknowledge).
+    pending: BTreeMap<i64, usize>,
+    last_received: Option<i64>,
+    highest_acknowledged: Option<i64>,
+    last_committed: Option<i64>,
+}
+
+impl PartitionProgress {
+    /// Returns the lowest offset such that this offset and all previous ones are acknowledged.
+    fn last_acknowledged(&self) -> Option<i64> {
+        match self.pending.keys().next() {
+            Some(lowest_pending) => Some(lowest_pending - 1).filter(|offset| *offset >= 0),
+            None => self.last_received,
+        }
+    }
+}
+
+impl OffsetTracker {
+    pub fn new(mode: DeliveryMode, offset_holder: Arc<MessageOffsetHolder>, max_in_flight: usize, max_attempts: usize, attempts: Arc<DeliveryAttempts>) -> OffsetTracker {
+        OffsetTracker {
+            mode,
+            offset_holder,
+            partitions: Mutex::new(HashMap::new()),
+            in_flight: InFlight::new(max_in_flight),
+            undelivered: watch::channel(None).0,
+            max_attempts,
+            attempts,
+        }
+    }
+
+    pub fn in_flight(&self) -> &InFlight {
+        &self.in_flight
+    }
+
+    /// Maximum number of attempts to deliver a message in at-least-once delivery mode (`processor.delivery.max.attempts`).
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    /// Number of failed attempts to deliver (output messages of) the message in at-least-once delivery mode.
+    pub fn failed_attempts(&self, offset: &MessageOffset) -> usize {
+        self.attempts.failed(offset)
+    }
+
+    /// Registers a message that was just consumed.
+    ///
+    /// The message is acknowledged when all of its `outputs` are acknowledged
+    /// (a message is processed by every stream consuming its topic).
+    pub fn received(&self, offset: &MessageOffset, outputs: usize) {
+        self.in_flight.acquire(outputs);
+        if self.mode == DeliveryMode::AutoCommit {
+            return;
+        }
+
+        let mut partitions = self.partitions.lock().unwrap();
+        let progress = partitions.entry(OffsetKey(offset.topic.clone(), offset.partition))
+            .or_default();
+
+        progress.pending.insert(offset.offset, outputs);
+        progress.last_received = progress.last_received.max(Some(offset.offset));
+    }
+
+    /// Processing of the message produced `extra` output messages more than registered when it was received
+    /// (eg. summaries of several windows closed by the message).
+    pub fn additional_outputs(&self, offset: &MessageOffset, extra: usize) {
+        if extra == 0 {
+            return;
+        }
+        self.in_flight.acquire(extra);
+        if self.mode == DeliveryMode::AutoCommit {
+            return;
+        }
+
+        let mut partitions = self.partitions.lock().unwrap();
+        if let Some(outputs) = partitions.get_mut(&OffsetKey(offset.topic.clone(), offset.partition))
+            .and_then(|progress| progress.pending.get_mut(&offset.offset)) {
+            *outputs += extra;
+        }
+    }
+
+    /// The output message was enqueued in the producer.
+    pub fn enqueued(&self, offset: MessageOffset) {
+        match self.mode {
+            DeliveryMode::AutoCommit => self.offset_holder.update(offset),
+            DeliveryMode::AtLeastOnce => {}
+            DeliveryMode::ExactlyOnce => self.acknowledge(offset),
+        }
+    }
+
+    /// The output message was acknowledged by the broker.
+    pub fn delivered(&self, offset: MessageOffset) {
+        self.in_flight.release();
+        if self.mode == DeliveryMode::AtLeastOnce {
+            self.acknowledge(offset);
+        }
+    }
+
+    /// The output message was not delivered (or could not be sent at all).
+    ///
+    /// In at-least-once delivery mode, its offset stays pending, so neither it nor any later offset of the partition
+    /// can be committed - the producer loop stops (see [`OffsetTracker::undelivered`]) and the message is consumed again
+    /// after restart (see [`DeliveryAttempts`]). In auto-commit mode, the message is lost. In exactly-once mode, the transaction
+    /// cannot be committed - the producer loop aborts it and the message is consumed again after restart, too.
+    pub fn failed(&self, offset: MessageOffset) {
+        self.in_flight.release();
+        if self.mode == DeliveryMode::AtLeastOnce {
+            self.attempts.add_failure(&offset);
+        }
+        if self.mode != DeliveryMode::AutoCommit {
+            self.undelivered.send_if_modified(|undelivered| match undelivered {
+                Some(_) => false,
+                None => {
+                    *undelivered = Some(offset);
+                    true
+                }
+            });
+        }
+    }
+
+    /// The first message that was not delivered in at-least-once or exactly-once delivery mode (if any).
+    pub fn undelivered(&self) -> Option<MessageOffset> {
+        self.undelivered.borrow().clone()
+    }
+
+    /// Waits until a message is not delivered in at-least-once or exactly-once delivery mode.
+    pub async fn wait_for_undelivered(&self) -> MessageOffset {
+        let mut undelivered = self.undelivered.subscribe();
+        // the sender lives as long as the tracker
+        let offset = undelivered.wait_for(Option::is_some).await
+            .map(|offset| offset.clone());
+        match offset {
+            Ok(Some(offset)) => offset,
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Message will not be produced at all (eg. it could not be processed), but it should not block committing offsets.
+    pub fn skipped(&self, offset: MessageOffset) {
+        self.in_flight.release();
+        match self.mode {
+            DeliveryMode::AutoCommit => self.offset_holder.update(offset),
+            DeliveryMode::AtLeastOnce | DeliveryMode::ExactlyOnce => self.acknowledge(offset),
+        }
+    }
+
+    fn acknowledge(&self, offset: MessageOffset) {
+        let mut partitions = self.partitions.lock().unwrap();
+        let offset_key = OffsetKey(offset.topic, offset.partition);
+
+        let last_acknowledged = partitions.get_mut(&offset_key)
+            .and_then(|progress| {
+                if let Some(outputs) = progress.pending.get_mut(&offset.offset) {
+                    *outputs = outputs.saturating_sub(1);
+                    if *outputs == 0 {
+                        progress.pending.remove(&offset.offset);
+                    }
+                }
+                progress.highest_acknowledged = progress.highest_acknowledged.max(Some(offset.offset));
+                progress.last_acknowledged()
+            });
+
+        if self.mode != DeliveryMode::AtLeastOnce {
+            // in exactly-once mode, offsets are saved when the transaction is committed
+            return;
+        }
+
+        if let Some(last_acknowledged) = last_acknowledged {
+            trace!("Acknowledged offset [Topic: {}] [Partition: {}] is now {}", &offset_key.0, offset_key.1, last_acknowledged);
+            self.attempts.acknowledged(&offset_key, last_acknowledged);
+            self.offset_holder.update(MessageOffset {
+                topic: offset_key.0,
+                partition: offset_key.1,
+                offset: last_acknowledged,
+            });
+        }
+    }
+
+    /// Commits acknowledged offsets to Kafka (only in [`DeliveryMode::AtLeastOnce`]).
+    pub fn commit<S: Source>(&self, source: &S, commit_mode: CommitMode) {
+        if self.mode != DeliveryMode::AtLeastOnce {
+            return;
+        }
+
+        let mut partitions = self.partitions.lock().unwrap();
+        let to_commit = uncommitted_offsets(&partitions);
+
+        if to_commit.is_empty() {
+            return;
+        }
+
+        debug!("Committing offsets: {to_commit:?}");
+        match source.commit(&to_commit, commit_mode) {
+            Ok(_) => {
+                to_commit.into_iter()
+                    .for_each(|(offset_key, offset)| {
+                        if let Some(progress) = partitions.get_mut(&offset_key) {
+                            progress.last_committed = Some(offset);
+                        }
+                    });
+            }
+            Err(e) => {
+                warn!("Cannot commit offsets, will retry later. Reason: {e}");
+            }
+        }
+    }
+
+    /// Commits offsets of processed messages before shutdown (synchronously).
+    /// Offsets in exactly-once delivery mode are committed in the last transaction.
+    pub fn commit_on_shutdown<S: Source>(&self, source: &S) {
+        match self.mode {
+            DeliveryMode::AutoCommit => {
+                if let Err(e) = source.commit_received() {
+                    warn!("Cannot commit offsets before shutdown. Reason: {e}");
+                }
+            }
+            DeliveryMode::AtLeastOnce => self.commit(source, CommitMode::Sync),
+            DeliveryMode::ExactlyOnce => {}
+        }
+    }
+
+    /// Returns acknowledged offsets that were not committed yet.
+    pub fn uncommitted(&self) -> Vec<(OffsetKey, i64)> {
+        uncommitted_offsets(&self.partitions.lock().unwrap())
+    }
+
+    /// Saves offsets that were committed (eg. in a transaction).
+    pub fn committed(&self, offsets: Vec<(OffsetKey, i64)>) {
+        let mut partitions = self.partitions.lock().unwrap();
+
+        offsets.into_iter()
+            .for_each(|(offset_key, offset)| {
+                if let Some(progress) = partitions.get_mut(&offset_key) {
+                    progress.last_committed = Some(offset);
+                }
+
+                self.offset_holder.update(MessageOffset {
+                    topic: offset_key.0,
+                    partition: offset_key.1,
+                    offset,
+                });
+            });
+    }
+
+    /// Checks whether all acknowledged messages can be committed,
+    /// that is - there are no pending messages before any acknowledged message.
+    pub fn is_consistent(&self) -> bool {
+        self.partitions.lock().unwrap()
+            .values()
+            .all(|progress| progress.highest_acknowledged <= progress.last_acknowledged())
+    }
+
+    /// Checks whether given message is one of pending messages before (or at) an already acknowledged message.
+    pub fn fills_gap(&self, offset: &MessageOffset) -> bool {
+        self.partitions.lock().unwrap()
+            .get(&OffsetKey(offset.topic.clone(), offset.partition))
+            .map(|progress| Some(offset.offset) <= progress.highest_acknowledged)
+            .unwrap_or(false)
+    }
+}
+
+fn uncommitted_offsets(partitions: &HashMap<OffsetKey, PartitionProgress>) -> Vec<(OffsetKey, i64)> {
+    partitions.iter()
+        .filter_map(|(offset_key, progress)| {
+            let last_acknowledged = progress.last_acknowledged()?;
+            if progress.last_committed < Some(last_acknowledged) {
+                Some((offset_key.clone(), last_acknowledged))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Creates a list of offsets to commit. Kafka expects the offset of the next message to consume.
+pub fn to_topic_partition_list(offsets: &[(OffsetKey, i64)]) -> TopicPartitionList {
+    let mut topic_list = TopicPartitionList::new();
+
+    for (offset_key, offset) in offsets {
+        if let Err(e) = topic_list.add_partition_offset(&offset_key.0, offset_key.1, Offset::Offset(offset + 1)) {
+            error!("Cannot commit offset {offset} [Topic: {}] [Partition: {}]. Reason: {e}", &offset_key.0, offset_key.1);
+        }
+    }
+
+    topic_list
+}
+
+/// Producer context that completes [`Delivery`] of sent messages (see [`crate::sink::KafkaSink`]).
+pub struct DeliveryContext;
+
+impl ClientContext for DeliveryContext {
+    fn error(&self, error: KafkaError, reason: &str) {
+        if error.rdkafka_error_code() == Some(RDKafkaErrorCode::AllBrokersDown) {
+            health().producer_connected(false);
+        }
+        error!("librdkafka: {error}: {reason}");
+    }
+}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = Box<Delivery>;
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, delivery: Self::DeliveryOpaque) {
+        match delivery_result {
+            Ok(_) => {
+                metrics().delivery_report(true);
+                health().producer_connected(true);
+                delivery.delivered();
+            }
+            Err((e, message)) => {
+                metrics().delivery_report(false);
+                match delivery.offset() {
+                    Some(offset) => error!("Message from [Topic: {}] [Partition: {}] [Offset: {}] was not delivered to [{}]. Reason: {e}. \
+                        This offset will not be committed.",
+                        offset.topic, offset.partition, offset.offset, message.topic()),
+                    None => error!("Message was not delivered to [{}]. Reason: {e}.", message.topic()),
+                }
+                delivery.failed();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::config::DeliveryMode;
+    use crate::delivery::{OffsetTracker, PartitionProgress};
+    use crate::journal::{MessageOffsetHolder, OffsetKey};
+    use crate::MessageOffset;
+
+    #[test]
+    fn should_acknowledge_contiguous_offsets_only() {
+        let mut progress = PartitionProgress::default();
+        for offset in 10..15 {
+            progress.pending.insert(offset, 1);
+            progress.last_received = Some(offset);
+        }
+
+        progress.pending.remove(&11);
+        progress.pending.remove(&12);
+        assert_eq!(Some(9), progress.last_acknowledged());
+
+        progress.pending.remove(&10);
+        assert_eq!(Some(12), progress.last_acknowledged());
+
+        progress.pending.remove(&14);
+        progress.pending.remove(&13);
+        assert_eq!(Some(14), progress.last_acknowledged());
+    }
+
+    #[test]
+    fn should_commit_transaction_only_without_gaps() {
+        let offset_holder = MessageOffsetHolder::new("./kjp_journal".to_string(), false).unwrap();
+        let tracker = OffsetTracker::new(DeliveryMode::ExactlyOnce, Arc::new(offset_holder), 100, 3, Arc::default());
+        let offset = |offset| MessageOffset {
+            topic: "in".to_string(),
+            partition: 0,
+            offset,
+        };
+
+        (0..3).for_each(|i| tracker.received(&offset(i), 1));
+        tracker.enqueued(offset(0));
+        tracker.enqueued(offset(2));
+
+        assert!(!tracker.is_consistent());
+        assert!(tracker.fills_gap(&offset(1)));
+        assert_eq!(vec![(OffsetKey("in".to_string(), 0), 0)], tracker.uncommitted());
+
+        tracker.skipped(offset(1));
+
+        assert!(tracker.is_consistent());
+        assert_eq!(vec![(OffsetKey("in".to_string(), 0), 2)], tracker.uncommitted());
+
+        tracker.committed(tracker.uncommitted());
+
+        assert!(tracker.uncommitted().is_empty());
+    }
+
+    #[test]
+    fn should_acknowledge_message_after_all_outputs() {
+        let offset_holder = MessageOffsetHolder::new("./kjp_journal".to_string(), false).unwrap();
+        let tracker = OffsetTracker::new(DeliveryMode::AtLeastOnce, Arc::new(offset_holder), 100, 3, Arc::default());
+        let offset = MessageOffset {
+            topic: "in".to_string(),
+            partition: 0,
+            offset: 0,
+        };
+
+        tracker.received(&offset, 2);
+        tracker.additional_outputs(&offset, 1);
+        tracker.delivered(offset.clone());
+        tracker.delivered(offset.clone());
+
+        assert!(tracker.uncommitted().is_empty());
+
+        tracker.delivered(offset);
+
+        assert_eq!(vec![(OffsetKey("in".to_string(), 0), 0)], tracker.uncommitted());
+    }
+
+    #[test]
+    fn should_not_acknowledge_undelivered_message() {
+        let offset_holder = MessageOffsetHolder::new("./kjp_journal".to_string(), false).unwrap();
+        let tracker = OffsetTracker::new(DeliveryMode::AtLeastOnce, Arc::new(offset_holder), 100, 3, Arc::default());
+        let offset = |offset| MessageOffset {
+            topic: "in".to_string(),
+            partition: 0,
+            offset,
+        };
+
+        (0..3).for_each(|i| tracker.received(&offset(i), 1));
+        tracker.delivered(offset(0));
+        tracker.failed(offset(1));
+        tracker.delivered(offset(2));
+
+        assert_eq!(vec![(OffsetKey("in".to_string(), 0), 0)], tracker.uncommitted());
+        assert_eq!(Some(1), tracker.undelivered().map(|offset| offset.offset));
+        assert!(!tracker.is_consistent());
+        assert_eq!(1, tracker.failed_attempts(&offset(1)));
+    }
+
+    #[test]
+    fn should_report_undelivered_message_of_transaction() {
+        let offset_holder = MessageOffsetHolder::new("./kjp_journal".to_string(), false).unwrap();
+        let tracker = OffsetTracker::new(DeliveryMode::ExactlyOnce, Arc::new(offset_holder), 100, 3, Arc::default());
+        let offset = MessageOffset {
+            topic: "in".to_string(),
+            partition: 0,
+            offset: 0,
+        };
+
+        tracker.received(&offset, 1);
+        tracker.enqueued(offset.clone());
+        tracker.failed(offset);
+
+        assert_eq!(Some(0), tracker.undelivered().map(|offset| offset.offset));
+    }
+}
@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::time::Duration;
 use crate::processor::ObjectKey;
 
 #[repr(transparent)]
@@ -11,6 +12,12 @@ impl ProcessingError {
     pub fn new(inner: ErrorKind) -> ProcessingError {
         ProcessingError { inner }
     }
+
+    /// Error that may not happen again (eg. a timeout of an external service).
+    /// Such messages are processed again if the error policy of the stream is `retry`.
+    pub fn transient<E: Into<Box<dyn Error>>>(err: E) -> ProcessingError {
+        ProcessingError { inner: ErrorKind::Transient { err: err.into() } }
+    }
 }
 
 #[derive(Debug)]
@@ -31,6 +38,23 @@ pub enum ErrorKind {
         err: Box<dyn Error>
     },
 
+    /// Error that may not happen again if the message is processed again (see [`ProcessingError::transient`]).
+    Transient {
+        err: Box<dyn Error>
+    },
+
+    /// Processor panicked. The panic was caught, so other messages are not affected.
+    Panicked {
+        message: String
+    },
+
+    /// Processor did not finish within the time budget of the stream (`processor.time.budget.ms`).
+    TimedOut {
+        budget: Duration,
+        /// How long the processor ran - `None` if it did not finish at all (the message was abandoned).
+        elapsed: Option<Duration>,
+    },
+
     /// Low priority error - Field not found and thus, cannot use this message processor.
     /// Example: requested to copy $.foo into $.bar, but no $.foo field was present.
     FieldNotFound {
@@ -41,6 +65,40 @@ pub enum ErrorKind {
     /// Example: some processor condition was not met.
     ProcessorSkipped {
         reason: String
+    },
+
+    /// Input message is not a valid JSON (or cannot be read at all).
+    /// Such message cannot be passed to any processor.
+    InvalidPayload {
+        err: Box<dyn Error>
+    },
+
+    /// Input message has no payload (it is a tombstone).
+    NullPayload,
+
+    /// Message was filtered out by a processor - nothing will be produced for this message.
+    /// This is not a failure: the following processors are not run and the offset is committed as usual.
+    MessageFiltered {
+        reason: String
+    },
+}
+
+impl ErrorKind {
+    /// Short name of the error kind, used eg. in dead-letter headers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidObjectTree { .. } => "InvalidObjectTree",
+            ErrorKind::EmptyKey => "EmptyKey",
+            ErrorKind::OtherError { .. } => "OtherError",
+            ErrorKind::Transient { .. } => "Transient",
+            ErrorKind::Panicked { .. } => "Panicked",
+            ErrorKind::TimedOut { .. } => "TimedOut",
+            ErrorKind::FieldNotFound { .. } => "FieldNotFound",
+            ErrorKind::ProcessorSkipped { .. } => "ProcessorSkipped",
+            ErrorKind::InvalidPayload { .. } => "InvalidPayload",
+            ErrorKind::NullPayload => "NullPayload",
+            ErrorKind::MessageFiltered { .. } => "MessageFiltered",
+        }
     }
 }
 
@@ -55,8 +113,22 @@ impl Display for ErrorKind {
                 write!(f, "No key in object: [{key:?}]"),
             ErrorKind::OtherError { err } =>
                 write!(f, "Unexpected error while processing: {err}"),
+            ErrorKind::Transient { err } =>
+                write!(f, "Transient error while processing: {err}"),
+            ErrorKind::Panicked { message } =>
+                write!(f, "Processor panicked: {message}"),
+            ErrorKind::TimedOut { budget, elapsed: Some(elapsed) } =>
+                write!(f, "Processor exceeded time budget of {}ms (took {}ms)", budget.as_millis(), elapsed.as_millis()),
+            ErrorKind::TimedOut { budget, elapsed: None } =>
+                write!(f, "Processor did not finish within time budget of {}ms", budget.as_millis()),
             ErrorKind::ProcessorSkipped { reason } => 
                 write!(f, "{reason}"),
+            ErrorKind::InvalidPayload { err } =>
+                write!(f, "Invalid message payload: {err}"),
+            ErrorKind::NullPayload =>
+                write!(f, "Message has no payload (tombstone)."),
+            ErrorKind::MessageFiltered { reason } =>
+                write!(f, "Message filtered out: {reason}"),
         }
     }
 }
@@ -75,6 +147,111 @@ impl Debug for ProcessingError {
 
 impl Error for ProcessingError {}
 
+/// Error that prevented the whole message from being processed.
+///
+/// Unlike [`ProcessingError`] (returned by a single processor), this error means that
+/// there is no output message at all.
+pub struct MessageError {
+    pub inner: ErrorKind,
+    /// Index of the processor that caused the failure (if the failure was caused by a processor).
+    pub processor: Option<usize>,
+}
+
+impl MessageError {
+    pub fn new(inner: ErrorKind) -> MessageError {
+        MessageError { inner, processor: None }
+    }
+}
+
+impl Display for MessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.processor {
+            Some(i) => write!(f, "#{i} {}", self.inner),
+            None => Display::fmt(&self.inner, f),
+        }
+    }
+}
+
+impl Debug for MessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageError")
+            .field("inner", &self.inner)
+            .field("processor", &self.processor)
+            .finish()
+    }
+}
+
+impl Error for MessageError {}
+
+impl From<ErrorKind> for MessageError {
+    fn from(e: ErrorKind) -> Self {
+        MessageError::new(e)
+    }
+}
+
+/// [`MessageError`] that can be sent between threads (eg. returned from a blocking task).
+/// Wrapped errors are kept only as their messages.
+pub(crate) struct DetachedMessageError {
+    rebuild: Box<dyn FnOnce() -> ErrorKind + Send>,
+    processor: Option<usize>,
+}
+
+impl From<MessageError> for DetachedMessageError {
+    fn from(e: MessageError) -> Self {
+        let rebuild: Box<dyn FnOnce() -> ErrorKind + Send> = match e.inner {
+            ErrorKind::OtherError { err } => {
+                let err = err.to_string();
+                Box::new(move || ErrorKind::OtherError { err: err.into() })
+            }
+            ErrorKind::Transient { err } => {
+                let err = err.to_string();
+                Box::new(move || ErrorKind::Transient { err: err.into() })
+            }
+            ErrorKind::InvalidPayload { err } => {
+                let err = err.to_string();
+                Box::new(move || ErrorKind::InvalidPayload { err: err.into() })
+            }
+            ErrorKind::InvalidObjectTree { invalid_key, reason } =>
+                Box::new(move || ErrorKind::InvalidObjectTree { invalid_key, reason }),
+            ErrorKind::EmptyKey =>
+                Box::new(|| ErrorKind::EmptyKey),
+            ErrorKind::Panicked { message } =>
+                Box::new(move || ErrorKind::Panicked { message }),
+            ErrorKind::TimedOut { budget, elapsed } =>
+                Box::new(move || ErrorKind::TimedOut { budget, elapsed }),
+            ErrorKind::FieldNotFound { key } =>
+                Box::new(move || ErrorKind::FieldNotFound { key }),
+            ErrorKind::ProcessorSkipped { reason } =>
+                Box::new(move || ErrorKind::ProcessorSkipped { reason }),
+            ErrorKind::NullPayload =>
+                Box::new(|| ErrorKind::NullPayload),
+            ErrorKind::MessageFiltered { reason } =>
+                Box::new(move || ErrorKind::MessageFiltered { reason }),
+        };
+        DetachedMessageError { rebuild, processor: e.processor }
+    }
+}
+
+impl From<DetachedMessageError> for MessageError {
+    fn from(e: DetachedMessageError) -> Self {
+        MessageError { inner: (e.rebuild)(), processor: e.processor }
+    }
+}
+
+/// Error after which kafka-json-processor cannot continue (and restarting will not help).
+#[derive(Debug)]
+pub struct FatalError {
+    pub reason: String,
+}
+
+impl Display for FatalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Error for FatalError {}
+
 impl<T: Error + 'static> From<T> for ErrorKind {
     fn from(err: T) -> Self {
         ErrorKind::OtherError { err: Box::new(err) }
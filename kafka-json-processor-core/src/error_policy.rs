@@ -0,0 +1,108 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Attempts of processing a message with [`ErrorPolicy::Retry`] (if not set).
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Backoff before the first retry with [`ErrorPolicy::Retry`] (if not set).
+const DEFAULT_BACKOFF_MS: u64 = 100;
+/// Backoff does not grow above this value.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Decides what happens with a message when a processor returns an error.
+///
+/// Errors that only skip a processor (`FieldNotFound`, `ProcessorSkipped`) and filtered messages are not failures,
+/// so they are not affected by this policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    /// The error is logged and the next processor is run - the output message may be partially built.
+    Continue,
+    /// Processing stops and the message is sent to the dead-letter topic (or lost if there is no dead-letter topic).
+    Fail,
+    /// Transient errors (see [`crate::error::ProcessingError::transient`]) are retried - the message is processed again
+    /// from the first processor, after a backoff doubled with every attempt. If it still fails after `max_attempts`,
+    /// or the error is not transient, the message fails as with [`ErrorPolicy::Fail`].
+    Retry {
+        max_attempts: u32,
+        backoff_ms: u64,
+    },
+}
+
+impl ErrorPolicy {
+    /// Backoff before given retry (counted from 1).
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        match self {
+            ErrorPolicy::Retry { backoff_ms, .. } => {
+                let multiplier = 1u64.checked_shl(retry.saturating_sub(1)).unwrap_or(u64::MAX);
+                Duration::from_millis(backoff_ms.saturating_mul(multiplier)).min(MAX_BACKOFF)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continue" => Ok(ErrorPolicy::Continue),
+            "fail" => Ok(ErrorPolicy::Fail),
+            _ if s == "retry" || s.starts_with("retry:") => {
+                let mut options = s.split(':').skip(1);
+                let max_attempts = match options.next() {
+                    Some(attempts) => attempts.parse()
+                        .map_err(|e| format!("Invalid number of attempts in error policy {s}: {e}"))?,
+                    None => DEFAULT_MAX_ATTEMPTS,
+                };
+                let backoff_ms = match options.next() {
+                    Some(backoff) => backoff.parse()
+                        .map_err(|e| format!("Invalid backoff in error policy {s}: {e}"))?,
+                    None => DEFAULT_BACKOFF_MS,
+                };
+
+                if max_attempts == 0 || options.next().is_some() {
+                    return Err(format!("Invalid error policy: {s}. Expected retry[:<max attempts greater than 0>[:<backoff ms>]]."));
+                }
+                Ok(ErrorPolicy::Retry { max_attempts, backoff_ms })
+            }
+            _ => Err(format!("Unknown error policy: {s}. Available policies: continue, fail, retry[:<max attempts>[:<backoff ms>]].")),
+        }
+    }
+}
+
+impl Display for ErrorPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorPolicy::Continue => write!(f, "continue"),
+            ErrorPolicy::Fail => write!(f, "fail"),
+            ErrorPolicy::Retry { max_attempts, backoff_ms } => write!(f, "retry:{max_attempts}:{backoff_ms}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::error_policy::ErrorPolicy;
+
+    #[test]
+    fn should_parse_retry_policy_with_defaults() {
+        assert_eq!(Ok(ErrorPolicy::Retry { max_attempts: 3, backoff_ms: 100 }), "retry".parse());
+        assert_eq!(Ok(ErrorPolicy::Retry { max_attempts: 5, backoff_ms: 100 }), "retry:5".parse());
+        assert_eq!(Ok(ErrorPolicy::Retry { max_attempts: 5, backoff_ms: 20 }), "retry:5:20".parse());
+        assert!("retry:0".parse::<ErrorPolicy>().is_err());
+        assert!("retry:5:20:1".parse::<ErrorPolicy>().is_err());
+        assert!("retries".parse::<ErrorPolicy>().is_err());
+    }
+
+    #[test]
+    fn should_double_backoff_up_to_limit() {
+        let policy = ErrorPolicy::Retry { max_attempts: 100, backoff_ms: 100 };
+
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(400), policy.backoff(3));
+        assert_eq!(Duration::from_secs(60), policy.backoff(20));
+        assert_eq!(Duration::from_secs(60), policy.backoff(99));
+    }
+}
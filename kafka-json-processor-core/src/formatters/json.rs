@@ -89,6 +89,7 @@ pub fn pretty_json(source: String) -> String {
 }
 
 #[derive(Eq, PartialEq, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum JsonSymbol {
     ObjectOrArrayStart,
     ObjectOrArrayEnd,
@@ -0,0 +1,149 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+
+lazy_static! {
+    static ref HEALTH: Health = Health::default();
+}
+
+/// Global health state of kafka-json-processor.
+///
+/// It outlives the runtime, so it can report that the runtime is restarting (or retrying to connect).
+pub fn health() -> &'static Health {
+    &HEALTH
+}
+
+#[derive(Default)]
+pub struct Health {
+    state: Mutex<HealthState>,
+}
+
+#[derive(Default)]
+struct HealthState {
+    /// Whether all processing tasks are running.
+    running: bool,
+    /// Start of the current series of restarts/retries (`None` if the last start was successful).
+    failing_since: Option<Instant>,
+    consumer_assigned: bool,
+    producer_connected: bool,
+    /// Last iteration of the producer loop.
+    producer_heartbeat: Option<Instant>,
+}
+
+/// Result of a health check with results of partial checks.
+pub struct HealthStatus {
+    pub up: bool,
+    pub checks: Vec<(&'static str, bool)>,
+}
+
+impl HealthStatus {
+    fn new(checks: Vec<(&'static str, bool)>) -> HealthStatus {
+        HealthStatus {
+            up: checks.iter().all(|(_, up)| *up),
+            checks,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let checks: Map<String, Value> = self.checks.iter()
+            .map(|(name, up)| (name.to_string(), Value::Bool(*up)))
+            .collect();
+
+        json!({
+            "status": if self.up { "UP" } else { "DOWN" },
+            "checks": checks,
+        })
+    }
+}
+
+impl Health {
+    /// All processing tasks were started.
+    pub fn started(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = true;
+        state.failing_since = None;
+        // there is no better signal until the first delivery report or global error
+        state.producer_connected = true;
+        state.producer_heartbeat = Some(Instant::now());
+    }
+
+    /// The runtime failed and is going to be restarted (or Kafka clients cannot be created and will be retried).
+    pub fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.consumer_assigned = false;
+        state.failing_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Shutdown was requested - processing tasks are being stopped.
+    pub fn stopped(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.consumer_assigned = false;
+    }
+
+    pub fn consumer_assigned(&self, assigned: bool) {
+        self.state.lock().unwrap().consumer_assigned = assigned;
+    }
+
+    pub fn producer_connected(&self, connected: bool) {
+        self.state.lock().unwrap().producer_connected = connected;
+    }
+
+    pub fn producer_heartbeat(&self) {
+        self.state.lock().unwrap().producer_heartbeat = Some(Instant::now());
+    }
+
+    /// kafka-json-processor is live unless the runtime keeps failing or the producer loop is stuck
+    /// for longer than `timeout`.
+    pub fn liveness(&self, timeout: Duration) -> HealthStatus {
+        let state = self.state.lock().unwrap();
+        let runtime = state.failing_since
+            .map(|since| since.elapsed() < timeout)
+            .unwrap_or(true);
+        let producer = !state.running || state.producer_heartbeat
+            .map(|heartbeat| heartbeat.elapsed() < timeout)
+            .unwrap_or(true);
+
+        HealthStatus::new(vec![
+            ("runtime", runtime),
+            ("producer_loop", producer),
+        ])
+    }
+
+    /// kafka-json-processor is ready when it's running, consumer has partitions assigned and producer is connected.
+    pub fn readiness(&self) -> HealthStatus {
+        let state = self.state.lock().unwrap();
+
+        HealthStatus::new(vec![
+            ("running", state.running),
+            ("consumer_assigned", state.consumer_assigned),
+            ("producer_connected", state.producer_connected),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::health::Health;
+
+    #[test]
+    fn should_report_readiness_and_liveness() {
+        let health = Health::default();
+        assert!(health.liveness(Duration::ZERO).up);
+        assert!(!health.readiness().up);
+
+        health.started();
+        health.consumer_assigned(true);
+        assert!(health.readiness().up);
+        assert!(health.liveness(Duration::from_secs(60)).up);
+        assert!(!health.liveness(Duration::ZERO).up);
+
+        health.failed();
+        assert!(!health.readiness().up);
+        assert!(health.liveness(Duration::from_secs(60)).up);
+        assert_eq!("{\"checks\":{\"producer_loop\":true,\"runtime\":false},\"status\":\"DOWN\"}", health.liveness(Duration::ZERO).to_json().to_string());
+    }
+}
@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+use crate::health::{health, HealthStatus};
+use crate::metrics::metrics;
+
+/// Maximum size of a request head. Requests are not expected to have a body.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Time the client has to send the whole request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts the HTTP server in a separate thread (with its own runtime).
+///
+/// The server is independent of the processing runtime, so it keeps responding while the runtime is restarted.
+pub fn start_http_server(bind: String, liveness_timeout: Duration) {
+    std::thread::spawn(move || {
+        let runtime = match Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("Cannot start HTTP server runtime: {e}");
+                return;
+            }
+        };
+
+        if let Err(e) = runtime.block_on(serve(bind, liveness_timeout)) {
+            error!("HTTP server stopped: {e}");
+        }
+    });
+}
+
+/// Runs a minimal HTTP server with kafka-json-processor endpoints:
+/// * `/metrics` - metrics in Prometheus text format,
+/// * `/health/live` - liveness (whether the runtime is not stuck restarting),
+/// * `/health/ready` - readiness (whether messages are being consumed and produced).
+async fn serve(bind: String, liveness_timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(&bind).await
+        .map_err(|e| format!("Cannot bind HTTP server to {bind}: {e}"))?;
+    info!("HTTP server listening on {bind}.");
+
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Cannot accept HTTP connection: {e}");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, liveness_timeout).await {
+                debug!("HTTP connection with {address} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, liveness_timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(format!("Request not received within {REQUEST_TIMEOUT:?}.").into()),
+    };
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, content_type, body) = route(method, path, liveness_timeout);
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the request head (up to [MAX_REQUEST_SIZE] bytes). Returns `None` if the connection was closed before.
+async fn read_request(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err("Request too large.".into());
+        }
+    }
+
+    Ok(Some(request))
+}
+
+/// Returns status, content type and body of the response.
+fn route(method: &str, path: &str, liveness_timeout: Duration) -> (&'static str, &'static str, String) {
+    // query parameters are not supported, but should not prevent scraping
+    let path = path.split('?').next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/metrics") =>
+            ("200 OK", "text/plain; version=0.0.4", metrics().render()),
+        ("GET", "/health/live") =>
+            health_response(health().liveness(liveness_timeout)),
+        ("GET", "/health/ready") =>
+            health_response(health().readiness()),
+        (_, "/metrics" | "/health/live" | "/health/ready") =>
+            ("405 Method Not Allowed", "text/plain", "Method not allowed.\n".to_string()),
+        _ =>
+            ("404 Not Found", "text/plain", "Not found.\n".to_string()),
+    }
+}
+
+fn health_response(status: HealthStatus) -> (&'static str, &'static str, String) {
+    let code = if status.up { "200 OK" } else { "503 Service Unavailable" };
+    (code, "application/json", status.to_json().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::http::route;
+
+    #[test]
+    fn should_route_requests() {
+        let timeout = Duration::from_secs(60);
+        assert_eq!("200 OK", route("GET", "/metrics?format=text", timeout).0);
+        assert_eq!("200 OK", route("GET", "/health/live", timeout).0);
+        assert_eq!("405 Method Not Allowed", route("POST", "/metrics", timeout).0);
+        assert_eq!("405 Method Not Allowed", route("PUT", "/health/ready", timeout).0);
+        assert_eq!("404 Not Found", route("GET", "/", timeout).0);
+    }
+}
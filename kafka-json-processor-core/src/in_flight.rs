@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
+use crate::metrics::metrics;
+
+/// Limits the number of output messages in flight - consumed, but not completed yet
+/// (delivered, skipped or failed).
+///
+/// When the limit is reached, the consumer pauses its partitions until half of the messages in flight are completed.
+pub struct InFlight {
+    limit: usize,
+    count: AtomicUsize,
+    released: Notify,
+}
+
+impl InFlight {
+    pub fn new(limit: usize) -> InFlight {
+        InFlight {
+            limit,
+            count: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Registers `outputs` new messages in flight.
+    pub fn acquire(&self, outputs: usize) {
+        let count = self.count.fetch_add(outputs, Ordering::AcqRel) + outputs;
+        metrics().in_flight(count, self.limit);
+    }
+
+    /// A message in flight was completed.
+    pub fn release(&self) {
+        let previous = self.count.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| Some(count.saturating_sub(1)))
+            .unwrap_or_default();
+        let count = previous.saturating_sub(1);
+        metrics().in_flight(count, self.limit);
+
+        if count <= self.resume_threshold() {
+            self.released.notify_one();
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count.load(Ordering::Acquire) >= self.limit
+    }
+
+    /// Waits until the number of messages in flight drops to half of the limit.
+    pub async fn wait_for_resume(&self) {
+        while self.count.load(Ordering::Acquire) > self.resume_threshold() {
+            self.released.notified().await;
+        }
+    }
+
+    fn resume_threshold(&self) -> usize {
+        self.limit / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::runtime::Builder;
+    use tokio::time::timeout;
+    use crate::in_flight::InFlight;
+
+    #[test]
+    fn should_resume_when_half_of_messages_are_completed() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let in_flight = Arc::new(InFlight::new(4));
+
+        in_flight.acquire(3);
+        assert!(!in_flight.is_full());
+        in_flight.acquire(1);
+        assert!(in_flight.is_full());
+
+        runtime.block_on(async {
+            in_flight.release();
+            assert!(timeout(Duration::from_millis(10), in_flight.wait_for_resume()).await.is_err());
+
+            let releasing = in_flight.clone();
+            tokio::spawn(async move {
+                releasing.release();
+            });
+            assert!(timeout(Duration::from_millis(100), in_flight.wait_for_resume()).await.is_ok());
+        });
+        assert!(!in_flight.is_full());
+    }
+}
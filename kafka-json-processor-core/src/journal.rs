@@ -70,6 +70,8 @@ fn read_offsets_from<P: AsRef<Path>>(directory: P) -> Result<HashMap<OffsetKey,
                 error!("Error reading file: {e}")
             }).ok()
         })
+        // the journal directory also has state checkpoints of streams (in subdirectories)
+        .filter(|file| file.path().is_file())
         .filter_map(|file| {
             let path = file.path();
 
@@ -79,7 +81,7 @@ fn read_offsets_from<P: AsRef<Path>>(directory: P) -> Result<HashMap<OffsetKey,
                 })
                 .ok()
                 .and_then(|content| {
-                    let offset_key = file_name_to_offset_key(path.file_name(), path.file_stem())?;
+                    let offset_key = file_name_to_offset_key(path.file_name())?;
                     Some((offset_key, content.parse().unwrap()))
                 })
         })
@@ -89,13 +91,11 @@ fn read_offsets_from<P: AsRef<Path>>(directory: P) -> Result<HashMap<OffsetKey,
 }
 
 /// Parses file_name as "$topic.$partition" (eg. sampletopic.1) and returns OffsetKey if successful
-fn file_name_to_offset_key(file_name: Option<&OsStr>, file_stem: Option<&OsStr>) -> Option<OffsetKey> {
-    let file_name = file_name?.to_str()?;
-    let file_stem = file_stem?.to_str()?;
+fn file_name_to_offset_key(file_name: Option<&OsStr>) -> Option<OffsetKey> {
+    // topic names can contain dots, partition is after the last one
+    let (topic, partition) = file_name?.to_str()?.rsplit_once('.')?;
 
-    let topic = file_name.strip_suffix(&file_stem)?.to_string();
-
-    Some(OffsetKey(topic, file_stem.parse().ok()?))
+    Some(OffsetKey(topic.to_string(), partition.parse().ok()?))
 }
 
 fn save_offsets_in<P: AsRef<Path>>(offsets: HashMap<OffsetKey, Offset>, directory: P) {
@@ -149,4 +149,16 @@ impl Drop for MessageOffsetHolder {
         // when json-processor finishes, we should save current offsets
         self.flush()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use crate::journal::{file_name_to_offset_key, OffsetKey};
+
+    #[test]
+    fn should_parse_topic_and_partition_from_file_name() {
+        assert_eq!(Some(OffsetKey("sampletopic".to_string(), 1)), file_name_to_offset_key(Some(OsStr::new("sampletopic.1"))));
+        assert_eq!(Some(OffsetKey("com.example.events".to_string(), 12)), file_name_to_offset_key(Some(OsStr::new("com.example.events.12"))));
+        assert_eq!(None, file_name_to_offset_key(Some(OsStr::new("sampletopic"))));
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use log::{debug, info};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use crate::processor::MessageContext;
+use crate::sink::{Delivery, SendError, Sink, SinkMessage};
+use crate::source::{Source, SourceMessage, SourceResult};
+
+/// How many lines are read ahead of processing.
+const READ_AHEAD: usize = 1000;
+
+/// Reads messages from JSON-lines files (eg. exported topic dumps) or stdin, one message per line:
+///
+/// ```text
+/// {"topic": "in", "partition": 0, "offset": 42, "timestamp": 1690000000000, "key": "abc", "headers": {"trace-id": "1"}, "payload": {"field": "value"}}
+/// ```
+///
+/// Only `payload` is required - `null` is a tombstone, a string is read as raw text (as in `kcat -J` dumps),
+/// any other value is the JSON message itself. Other fields default to:
+/// * `topic` - for files in a directory: file name without extension, for a single file or stdin: the only source topic
+///   (file name without extension if there are several source topics),
+/// * `partition` - 0,
+/// * `offset` - line number (counted from 0).
+///
+/// `headers` can also be an array of names and values (`["trace-id", "1"]`).
+///
+/// Files are read in a separate thread, the source ends after the last line.
+pub struct JsonLinesSource {
+    inputs: Vec<Input>,
+    tx: Mutex<Option<mpsc::Sender<SourceResult<SourceMessage>>>>,
+    rx: tokio::sync::Mutex<mpsc::Receiver<SourceResult<SourceMessage>>>,
+}
+
+enum Input {
+    File(PathBuf),
+    /// A file of a directory - it contains messages of the topic named after the file.
+    TopicFile(PathBuf),
+    Stdin,
+}
+
+impl JsonLinesSource {
+    /// Reads a single JSON-lines file.
+    pub fn file<P: AsRef<Path>>(path: P) -> JsonLinesSource {
+        JsonLinesSource::new(vec![Input::File(path.as_ref().to_path_buf())])
+    }
+
+    /// Reads all files in the directory (except hidden ones) ordered by name, eg. `in.jsonl` and `other.jsonl`.
+    pub fn directory<P: AsRef<Path>>(path: P) -> io::Result<JsonLinesSource> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(path)?
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+
+        Ok(JsonLinesSource::new(files.into_iter().map(Input::TopicFile).collect()))
+    }
+
+    /// Reads the standard input.
+    pub fn stdin() -> JsonLinesSource {
+        JsonLinesSource::new(vec![Input::Stdin])
+    }
+
+    /// Reads stdin (`-`), a directory or a file.
+    pub fn open(path: &str) -> io::Result<JsonLinesSource> {
+        if path == "-" {
+            Ok(JsonLinesSource::stdin())
+        } else if Path::new(path).is_dir() {
+            JsonLinesSource::directory(path)
+        } else {
+            Ok(JsonLinesSource::file(path))
+        }
+    }
+
+    fn new(inputs: Vec<Input>) -> JsonLinesSource {
+        let (tx, rx) = mpsc::channel(READ_AHEAD);
+        JsonLinesSource {
+            inputs,
+            tx: Mutex::new(Some(tx)),
+            rx: tokio::sync::Mutex::new(rx),
+        }
+    }
+}
+
+impl Source for JsonLinesSource {
+    fn subscribe(&self, topics: &[&str]) -> SourceResult<()> {
+        let tx = self.tx.lock().unwrap().take()
+            .ok_or("JSON-lines source can be read only once.")?;
+
+        // topic of lines without one
+        let only_topic = match topics {
+            [topic] => Some(topic.to_string()),
+            _ => None,
+        };
+        let file_topic = |path: &Path| path.file_stem().map(|stem| stem.to_string_lossy().to_string());
+        let inputs: Vec<(String, Option<PathBuf>, Option<String>)> = self.inputs.iter()
+            .map(|input| match input {
+                Input::File(path) => (path.display().to_string(), Some(path.clone()), only_topic.clone().or_else(|| file_topic(path))),
+                Input::TopicFile(path) => (path.display().to_string(), Some(path.clone()), file_topic(path)),
+                Input::Stdin => ("stdin".to_string(), None, only_topic.clone()),
+            })
+            .collect();
+
+        std::thread::Builder::new()
+            .name("kjp-json-lines".to_string())
+            .spawn(move || {
+                for (name, path, default_topic) in inputs {
+                    info!("Reading messages from {name}...");
+                    let reader: Box<dyn BufRead> = match path {
+                        Some(path) => match File::open(&path) {
+                            Ok(file) => Box::new(BufReader::new(file)),
+                            Err(e) => {
+                                let _ = tx.blocking_send(Err(format!("Cannot read {name}: {e}").into()));
+                                continue;
+                            }
+                        },
+                        None => Box::new(io::stdin().lock()),
+                    };
+
+                    if !read_lines(reader, &name, default_topic.as_deref(), &tx) {
+                        // processing was stopped
+                        return;
+                    }
+                }
+                debug!("All input files were read.");
+            })?;
+
+        Ok(())
+    }
+
+    async fn recv(&self) -> Option<SourceResult<SourceMessage>> {
+        self.rx.lock().await.recv().await
+    }
+}
+
+/// Sends parsed lines to the channel. Returns `false` if the channel was closed.
+fn read_lines(reader: Box<dyn BufRead>, name: &str, default_topic: Option<&str>, tx: &mpsc::Sender<SourceResult<SourceMessage>>) -> bool {
+    for (number, line) in reader.lines().enumerate() {
+        let message = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => parse_line(&line, number, default_topic)
+                .map_err(|e| format!("{name}:{}: {e}", number + 1).into()),
+            Err(e) => Err(format!("Cannot read {name}: {e}").into()),
+        };
+
+        if tx.blocking_send(message).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+fn parse_line(line: &str, number: usize, default_topic: Option<&str>) -> Result<SourceMessage, String> {
+    let mut fields = match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(fields)) => fields,
+        Ok(_) => return Err("Line is not a JSON object.".to_string()),
+        Err(e) => return Err(format!("Line is not a valid JSON: {e}")),
+    };
+
+    let payload = match fields.remove("payload") {
+        None => return Err("There is no payload (lines must have the form {\"topic\": ..., \"payload\": ...}).".to_string()),
+        Some(Value::Null) => None,
+        Some(Value::String(text)) => Some(text.into_bytes()),
+        Some(value) => Some(value.to_string().into_bytes()),
+    };
+
+    let topic = match fields.get("topic") {
+        Some(Value::String(topic)) => topic.clone(),
+        Some(_) => return Err("Topic is not a string.".to_string()),
+        None => default_topic
+            .ok_or("There is no topic (it's required when reading messages of several topics from stdin).")?
+            .to_string(),
+    };
+
+    let integer = |name: &str| match fields.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_i64()
+            .map(Some)
+            .ok_or(format!("{name} is not an integer.")),
+    };
+
+    let headers = match fields.get("headers") {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Object(headers)) => headers.iter()
+            .map(|(name, value)| (name.clone(), to_bytes(value)))
+            .collect(),
+        Some(Value::Array(headers)) => headers.chunks(2)
+            .map(|header| match header {
+                [Value::String(name), value] => Ok((name.clone(), to_bytes(value))),
+                _ => Err("Headers must be an object or an array of names and values.".to_string()),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err("Headers must be an object or an array of names and values.".to_string()),
+    };
+
+    Ok(SourceMessage {
+        context: MessageContext {
+            topic,
+            partition: integer("partition")?.unwrap_or(0) as i32,
+            offset: integer("offset")?.unwrap_or(number as i64),
+            key: fields.get("key")
+                .filter(|key| !key.is_null())
+                .map(to_bytes),
+            timestamp: integer("timestamp")?,
+            headers,
+        },
+        payload,
+    })
+}
+
+/// Strings are taken as they are, other values are serialized.
+fn to_bytes(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(text) => text.as_bytes().to_vec(),
+        value => value.to_string().into_bytes(),
+    }
+}
+
+/// Writes messages as JSON lines (in the format read by [`JsonLinesSource`]) to a file, a directory or stdout:
+///
+/// ```text
+/// {"headers":{"trace-id":"1"},"key":"abc","payload":{"field":"value"},"topic":"out"}
+/// ```
+///
+/// Payloads that are not valid JSON-s (or are JSON strings) are written as strings.
+/// A message is delivered as soon as it's written.
+pub struct JsonLinesSink {
+    output: Output,
+}
+
+enum Output {
+    Writer(Mutex<Box<dyn Write + Send>>),
+    /// One file per topic (`<topic>.jsonl`), created when the first message is written.
+    Directory {
+        path: PathBuf,
+        files: Mutex<HashMap<String, BufWriter<File>>>,
+    },
+}
+
+impl JsonLinesSink {
+    /// Writes all messages to a single file (truncated if it exists).
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<JsonLinesSink> {
+        Ok(JsonLinesSink::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Writes messages of each topic to its own file - `<topic>.jsonl` in the directory (created if needed).
+    /// Such directory can be read with [`JsonLinesSource::directory`].
+    pub fn directory<P: AsRef<Path>>(path: P) -> io::Result<JsonLinesSink> {
+        std::fs::create_dir_all(path.as_ref())?;
+
+        Ok(JsonLinesSink {
+            output: Output::Directory {
+                path: path.as_ref().to_path_buf(),
+                files: Mutex::new(HashMap::new()),
+            },
+        })
+    }
+
+    /// Writes messages to the standard output (line by line, so it can be used in shell pipelines).
+    pub fn stdout() -> JsonLinesSink {
+        JsonLinesSink::new(LineWriter::new(io::stdout()))
+    }
+
+    pub fn new<W: Write + Send + 'static>(writer: W) -> JsonLinesSink {
+        JsonLinesSink {
+            output: Output::Writer(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    /// Writes to stdout (`-`), a directory (if it exists or the path ends with `/`) or a file.
+    pub fn open(path: &str) -> io::Result<JsonLinesSink> {
+        if path == "-" {
+            Ok(JsonLinesSink::stdout())
+        } else if path.ends_with('/') || Path::new(path).is_dir() {
+            JsonLinesSink::directory(path)
+        } else {
+            JsonLinesSink::file(path)
+        }
+    }
+
+    fn write(&self, topic: &str, line: &str) -> Result<(), SendError> {
+        let fatal = |e: io::Error| SendError::Fatal(e.into());
+
+        match &self.output {
+            Output::Writer(writer) => writeln!(writer.lock().unwrap(), "{line}").map_err(fatal),
+            Output::Directory { path, files } => {
+                if !is_valid_topic(topic) {
+                    return Err(SendError::InvalidTopic);
+                }
+
+                let mut files = files.lock().unwrap();
+                if !files.contains_key(topic) {
+                    let file = File::create(path.join(format!("{topic}.jsonl"))).map_err(fatal)?;
+                    files.insert(topic.to_string(), BufWriter::new(file));
+                }
+                writeln!(files.get_mut(topic).unwrap(), "{line}").map_err(fatal)
+            }
+        }
+    }
+}
+
+impl Sink for JsonLinesSink {
+    async fn send(&self, message: SinkMessage<'_>, delivery: Delivery) -> Result<(), SendError> {
+        self.write(message.topic, &to_line(&message))?;
+        delivery.delivered();
+        Ok(())
+    }
+
+    fn flush(&self, _timeout: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.output {
+            Output::Writer(writer) => writer.lock().unwrap().flush()?,
+            Output::Directory { files, .. } => {
+                for file in files.lock().unwrap().values_mut() {
+                    file.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_line(message: &SinkMessage) -> String {
+    let mut line = Map::new();
+    line.insert("topic".to_string(), Value::String(message.topic.to_string()));
+
+    if let Some(partition) = message.partition {
+        line.insert("partition".to_string(), Value::from(partition));
+    }
+
+    if let Some(key) = message.key {
+        line.insert("key".to_string(), Value::String(String::from_utf8_lossy(key).to_string()));
+    }
+
+    if !message.headers.is_empty() {
+        let headers = message.headers.iter()
+            .map(|(name, value)| (name.clone(), Value::String(String::from_utf8_lossy(value).to_string())))
+            .collect();
+        line.insert("headers".to_string(), Value::Object(headers));
+    }
+
+    let payload = match message.payload {
+        None => Value::Null,
+        Some(payload) => match serde_json::from_slice::<Value>(payload) {
+            Ok(value) if !value.is_string() => value,
+            _ => Value::String(String::from_utf8_lossy(payload).to_string()),
+        },
+    };
+    line.insert("payload".to_string(), payload);
+
+    Value::Object(line).to_string()
+}
+
+/// Topic names are used as file names, so only names valid in Kafka are accepted.
+fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= 249
+        && topic != "."
+        && topic != ".."
+        && topic.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_lines::{is_valid_topic, parse_line, to_line};
+    use crate::sink::SinkMessage;
+
+    #[test]
+    fn should_read_and_write_json_lines() {
+        let message = parse_line(
+            r#"{"topic": "in", "partition": 2, "offset": 42, "key": "abc", "headers": ["trace-id", "1"], "payload": {"field": "value"}}"#,
+            0,
+            None,
+        ).unwrap();
+
+        assert_eq!("in", message.context.topic);
+        assert_eq!(2, message.context.partition);
+        assert_eq!(42, message.context.offset);
+        assert_eq!(Some(b"abc".to_vec()), message.context.key);
+        assert_eq!(Some("1"), message.context.header_str("trace-id"));
+        assert_eq!(Some(br#"{"field":"value"}"#.to_vec()), message.payload);
+
+        let headers = message.context.headers.clone();
+        let line = to_line(&SinkMessage {
+            topic: "out",
+            partition: None,
+            key: message.context.key.as_deref(),
+            payload: message.payload.as_deref(),
+            headers: &headers,
+        });
+        assert_eq!(r#"{"headers":{"trace-id":"1"},"key":"abc","payload":{"field":"value"},"topic":"out"}"#, line);
+    }
+
+    #[test]
+    fn should_use_defaults_of_missing_fields() {
+        let message = parse_line(r#"{"payload": "<xml/>"}"#, 7, Some("in")).unwrap();
+        assert_eq!("in", message.context.topic);
+        assert_eq!(0, message.context.partition);
+        assert_eq!(7, message.context.offset);
+        assert_eq!(None, message.context.key);
+        assert_eq!(Some(b"<xml/>".to_vec()), message.payload);
+
+        let tombstone = parse_line(r#"{"key": "abc", "payload": null}"#, 0, Some("in")).unwrap();
+        assert_eq!(None, tombstone.payload);
+
+        assert!(parse_line(r#"{"field": "value"}"#, 0, Some("in")).is_err());
+        assert!(parse_line(r#"{"payload": {}}"#, 0, None).is_err());
+        assert!(parse_line("[]", 0, Some("in")).is_err());
+    }
+
+    #[test]
+    fn should_accept_only_valid_topics_as_file_names() {
+        assert!(is_valid_topic("orders.v1_dead-letter"));
+        assert!(!is_valid_topic("../orders"));
+        assert!(!is_valid_topic(".."));
+        assert!(!is_valid_topic(""));
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use log::warn;
+use serde_json::Value;
+use crate::processor::{json_path_to_object_keys, MessageContext, ObjectKey, ObjectTree};
+
+/// Decides what key an output message has (if the key was not set by any processor).
+#[derive(Clone, Debug)]
+pub enum KeyPolicy {
+    /// Key of the input message is used.
+    Preserve,
+    /// Synthetic id of the input message: `topic:partition@offset(timestamp)`.
+    Synthetic,
+    /// Output message has no key.
+    Null,
+    /// Key is built from fields of the input message (joined with `:`).
+    /// If any of the fields is missing, synthetic id is used.
+    JsonPath(Vec<Vec<ObjectKey>>),
+}
+
+impl KeyPolicy {
+    /// Creates the key of the output message.
+    pub fn key_for(&self, id: &str, source: &Value, context: &MessageContext) -> Option<Vec<u8>> {
+        match self {
+            KeyPolicy::Preserve => context.key.clone(),
+            KeyPolicy::Synthetic => Some(id.as_bytes().to_vec()),
+            KeyPolicy::Null => None,
+            KeyPolicy::JsonPath(paths) => {
+                let parts: Result<Vec<String>, _> = paths.iter()
+                    .map(|path| source.get_val(path).map(value_to_key_part))
+                    .collect();
+
+                match parts {
+                    Ok(parts) => Some(parts.join(":").into_bytes()),
+                    Err(e) => {
+                        warn!("[{id}] Cannot derive key ({e}), using synthetic id.");
+                        Some(id.as_bytes().to_vec())
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn value_to_key_part(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+impl FromStr for KeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(KeyPolicy::Preserve),
+            "synthetic" => Ok(KeyPolicy::Synthetic),
+            "null" => Ok(KeyPolicy::Null),
+            _ => match s.strip_prefix("jsonpath:") {
+                Some(paths) if !paths.trim().is_empty() => Ok(KeyPolicy::JsonPath(
+                    paths.split(',')
+                        .map(|path| json_path_to_object_keys(path.trim()))
+                        .collect()
+                )),
+                _ => Err(format!("Unknown key policy: {s}. Available policies: preserve, synthetic, null, jsonpath:$.field[,$.other_field].")),
+            }
+        }
+    }
+}
+
+impl Display for KeyPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyPolicy::Preserve => write!(f, "preserve"),
+            KeyPolicy::Synthetic => write!(f, "synthetic"),
+            KeyPolicy::Null => write!(f, "null"),
+            KeyPolicy::JsonPath(paths) => {
+                let paths: Vec<String> = paths.iter()
+                    .map(|path| {
+                        path.iter()
+                            .fold("$".to_string(), |acc, key| match key {
+                                ObjectKey::Key(key) => format!("{acc}.{key}"),
+                                ObjectKey::Index(i) => format!("{acc}[{i}]"),
+                            })
+                    })
+                    .collect();
+                write!(f, "jsonpath:{}", paths.join(","))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::key::KeyPolicy;
+    use crate::processor::MessageContext;
+
+    #[test]
+    fn should_derive_key_from_fields() {
+        let policy: KeyPolicy = "jsonpath:$.customer.id, $.items[1]".parse().unwrap();
+        let source = json!({"customer": {"id": "c1"}, "items": [1, 2]});
+
+        assert_eq!("jsonpath:$.customer.id,$.items[1]", policy.to_string());
+        assert_eq!(Some(b"c1:2".to_vec()), policy.key_for("id", &source, &MessageContext::default()));
+        assert_eq!(Some(b"id".to_vec()), policy.key_for("id", &json!({}), &MessageContext::default()));
+    }
+
+    #[test]
+    fn should_preserve_input_key() {
+        let context = MessageContext {
+            key: Some(b"input".to_vec()),
+            ..Default::default()
+        };
+
+        assert_eq!(Some(b"input".to_vec()), KeyPolicy::Preserve.key_for("id", &json!({}), &context));
+        assert_eq!(None, KeyPolicy::Null.key_for("id", &json!({}), &context));
+        assert!("unknown".parse::<KeyPolicy>().is_err());
+    }
+}
@@ -1,19 +1,44 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
-use crossbeam_channel::bounded;
-use log::{info, warn, error, debug, trace};
-use rdkafka::consumer::{Consumer, StreamConsumer};
+use log::{info, warn, error, debug};
+use std::path::Path;
+use rdkafka::consumer::{BaseConsumer, CommitMode};
 use rdkafka::producer::BaseProducer;
-use rdkafka::{Offset, TopicPartitionList};
+use rdkafka::ClientConfig;
 use tokio::runtime::{Builder, Runtime};
-use tokio::time::interval;
-use crate::config::Config;
-use crate::consumer::consumer_loop;
-use crate::journal::{MessageOffsetHolder, OffsetKey};
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, interval_at, timeout, Instant};
+use crate::aggregation::Aggregation;
+use crate::config::{Config, ConfigError, DeliveryMode, StreamConfig};
+use crate::consumer::{consumer_loop, OrderedLanes, StreamWorker};
+use crate::dead_letter::{DeadLetter, DeadLetterTarget};
+use crate::dedup::DedupPolicy;
+use crate::delivery::{DeliveryAttempts, OffsetTracker};
+use crate::error::FatalError;
+use crate::error_policy::ErrorPolicy;
+use crate::health::health;
+use crate::http::start_http_server;
+use crate::journal::MessageOffsetHolder;
+use crate::json_lines::{JsonLinesSink, JsonLinesSource};
+use crate::key::KeyPolicy;
+use crate::lookup::{lookup_consumer_config, lookup_loop, LookupTables};
+use crate::ordering::OrderingPolicy;
+use crate::payload::PayloadPolicy;
 use crate::processor::{Processor, SerializedOutputMessage};
 use crate::producer::producer_loop;
+use crate::shutdown::{listen_for_shutdown, shutdown_requested};
+use crate::sink::{KafkaSink, Sink};
+use crate::source::{KafkaSource, Source};
+use crate::state::{changelog_channel, changelog_loop, kafka_changelog_reader, ChangelogReader, StateStore};
+use crate::transaction::{transactional_producer_loop, TransactionSettings};
+
+type ProducerResult = Result<(), Box<dyn Error + Send + Sync>>;
 
 pub mod config;
 mod consumer;
@@ -23,45 +48,214 @@ pub mod formatters;
 pub mod simulation;
 pub mod error;
 pub mod journal;
+pub mod key;
+pub mod payload;
+pub mod ordering;
+pub mod error_policy;
+pub mod source;
+pub mod sink;
+pub mod json_lines;
+pub mod state;
+pub mod dedup;
+pub mod aggregation;
+pub mod lookup;
+mod dead_letter;
+mod delivery;
+mod transaction;
+mod metrics;
+mod http;
+mod health;
+mod shutdown;
+mod in_flight;
+#[cfg(any(test, feature = "memory-broker"))]
+pub mod memory;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Stream {
     pub source_topic: String,
     pub target_topic: String,
     pub processors: &'static [Processor],
+    /// Topic for messages that cannot be processed or produced.
+    /// If not set, `processor.dead.letter.topic` from config is used.
+    pub dead_letter_topic: Option<String>,
+    /// Whether headers of the input message are added to the output message.
+    /// If not set, `processor.headers.propagate` from config is used.
+    pub propagate_headers: Option<bool>,
+    /// Key of output messages (if not set by a processor).
+    /// If not set, `processor.key.policy` from config is used.
+    pub key_policy: Option<KeyPolicy>,
+    /// What to do with messages without payload (tombstones).
+    /// If not set, `processor.null.payload.policy` from config is used.
+    pub null_payload_policy: Option<PayloadPolicy>,
+    /// What to do with messages that are not valid JSON-s.
+    /// If not set, `processor.invalid.payload.policy` from config is used.
+    pub invalid_payload_policy: Option<PayloadPolicy>,
+    /// Whether output messages are produced in the order of input messages (per partition or per key).
+    /// If not set, `processor.ordering` from config is used.
+    pub ordering: Option<OrderingPolicy>,
+    /// What to do with a message when a processor returns an error (continue, fail or retry).
+    /// If not set, `processor.error.policy` from config is used.
+    pub error_policy: Option<ErrorPolicy>,
+    /// Maximum number of messages of this stream processed at once.
+    /// If not set, it's limited only by `processor.worker.threads` and `processor.channel.capacity`
+    /// (ordered streams are processed by `processor.worker.threads` lanes).
+    pub concurrency: Option<usize>,
+    /// Maximum time of running a single processor in milliseconds (0 - no limit). A processor exceeding it fails
+    /// with [`error::ErrorKind::TimedOut`] and a message stuck in a processor for longer than the budget
+    /// of all processors is abandoned. If not set, `processor.time.budget.ms` from config is used.
+    pub time_budget_ms: Option<usize>,
+    /// Topic backing up the state of this stream (see [`state::StateStore`]). State changes are produced to it
+    /// (it should be compacted and have at least as many partitions as the source topic) and state is restored from it
+    /// when there is no local checkpoint. Streams with a changelog topic are ordered by partition (unless they are
+    /// ordered by key). If not set, state is kept only in `processor.journal.path`.
+    pub state_changelog_topic: Option<String>,
+    /// Where the dedup id of a message is taken from. Messages with an id seen within the dedup window
+    /// (in any partition of the source topic) are dropped (see [`dedup::DedupPolicy`]). Deduplicated streams are
+    /// ordered by partition (or by key, with [`DedupPolicy::Key`]), so the first of duplicates in a partition is kept.
+    /// If not set, messages are not deduplicated.
+    pub dedup: Option<DedupPolicy>,
+    /// How long (in milliseconds) an id is remembered. If not set, `processor.dedup.window.ms` from config is used.
+    pub dedup_window_ms: Option<usize>,
+    /// Maximum number of remembered ids (the oldest are forgotten first).
+    /// If not set, `processor.dedup.max.entries` from config is used.
+    pub dedup_max_entries: Option<usize>,
+    /// Windowed aggregation of this stream (see [`aggregation::Aggregation`]). Instead of an output message for every
+    /// input message, a summary is produced when a window closes (processors can still filter out messages before
+    /// they are aggregated). Aggregating streams are ordered by partition. If not set, messages are not aggregated.
+    pub aggregation: Option<Aggregation>,
+    /// Topics of lookup tables available to processors of this stream (see [`lookup::LookupTable`]). They should be
+    /// compacted and keyed by the lookup key. Messages are not consumed until all tables are loaded.
+    /// Can be replaced with `stream.<name>.lookup.tables` from config.
+    pub lookup_tables: Vec<String>,
+    /// rdkafka properties overriding `producer.*` properties from config for this stream (eg. `compression.codec`, `acks`).
+    /// Streams with different producer properties are produced by separate producers.
+    pub producer_config: Vec<(String, String)>,
+}
+
+impl Stream {
+    /// Time budget of a single processor (if the stream has one).
+    pub(crate) fn time_budget(&self) -> Option<Duration> {
+        self.time_budget_ms
+            .filter(|ms| *ms > 0)
+            .map(|ms| Duration::from_millis(ms as u64))
+    }
 }
 
 pub enum PendingMessage {
-    Received,
     Processed {
         id: String,
         topic: String,
         offset: MessageOffset,
         message: SerializedOutputMessage,
+        dead_letter: Option<DeadLetterTarget>,
+    },
+    DeadLetter {
+        id: String,
+        offset: MessageOffset,
+        message: DeadLetter,
     },
 }
 
+#[derive(Clone, Debug)]
 pub struct MessageOffset {
     topic: String,
     partition: i32,
     offset: i64,
 }
 
+/// Reason why processing tasks stopped without an error.
+enum Stopped {
+    /// Kafka clients could not be created - the runtime should be restarted.
+    Retry,
+    /// Shutdown was requested and all messages were delivered.
+    Shutdown,
+    /// Shutdown was requested, but not all messages were delivered before `processor.shutdown.timeout.ms`.
+    ShutdownIncomplete,
+}
+
+/// Creates clients used by [run_processor] - Kafka clients, or clients of the in-memory broker in tests
+/// (see [`memory::MemoryBroker`]).
+trait Broker {
+    type Source: Source;
+    type Sink: Sink;
+
+    fn source(&self, config: &ClientConfig) -> Result<Self::Source, Box<dyn Error + Send + Sync>>;
+
+    fn sink(&self, config: &ClientConfig) -> Result<Self::Sink, Box<dyn Error + Send + Sync>>;
+
+    /// Reads changelog topics to restore state of streams (see [`state::StateStore`]).
+    fn changelog_reader(&self, config: &ClientConfig) -> ChangelogReader;
+
+    /// Producer loop of exactly-once delivery mode (see [`transactional_producer_loop`]).
+    fn transactional_producer(
+        &self,
+        sink: Self::Sink,
+        rx: Receiver<PendingMessage>,
+        source: &Arc<Self::Source>,
+        queue_size: usize,
+        tracker: Arc<OffsetTracker>,
+        settings: TransactionSettings,
+    ) -> impl Future<Output = ProducerResult> + Send + 'static;
+}
+
+struct KafkaBroker;
+
+impl Broker for KafkaBroker {
+    type Source = KafkaSource;
+    type Sink = KafkaSink;
+
+    fn source(&self, config: &ClientConfig) -> Result<KafkaSource, Box<dyn Error + Send + Sync>> {
+        Ok(KafkaSource::new(config)?)
+    }
+
+    fn sink(&self, config: &ClientConfig) -> Result<KafkaSink, Box<dyn Error + Send + Sync>> {
+        Ok(KafkaSink::new(config)?)
+    }
+
+    fn changelog_reader(&self, config: &ClientConfig) -> ChangelogReader {
+        kafka_changelog_reader(config)
+    }
+
+    fn transactional_producer(
+        &self,
+        sink: KafkaSink,
+        rx: Receiver<PendingMessage>,
+        source: &Arc<KafkaSource>,
+        queue_size: usize,
+        tracker: Arc<OffsetTracker>,
+        settings: TransactionSettings,
+    ) -> impl Future<Output = ProducerResult> + Send + 'static {
+        transactional_producer_loop(sink, rx, source.consumer().clone(), queue_size, tracker, settings)
+    }
+}
+
+/// Runs kafka-json-processor with given streams (stream name -> stream).
+///
+/// Several streams can consume the same source topic - every message is processed by each of them.
+///
+/// Returns after a graceful shutdown (on SIGTERM or SIGINT). If not all messages could be delivered
+/// before shutdown timeout, exits with status code 2. Exits with status code 1 on fatal errors.
 pub fn run_processor(streams: HashMap<String, Stream>) {
     info!("Starting kafka-json-processor...");
+    let config = read_valid_config(&streams, |config_path| Config::read_from(config_path));
 
-    let config_env = "KAFKA_PROCESSOR_CONFIG_PATH";
-    let default_config_path = "./processor.properties";
-    let config_path = std::env::var(config_env)
-        .unwrap_or_else(|_| {
-            info!("Environment variable {} not found, using default config path.", config_env);
-            default_config_path.to_string()
-        });
+    if config.internal_config.http_enabled {
+        start_http_server(
+            config.internal_config.http_bind.clone(),
+            Duration::from_millis(config.internal_config.http_liveness_timeout_ms as u64),
+        );
+    }
 
-    info!("Reading config from {}", config_path);
-    let config = Config::read_from(default_config_path).unwrap();
+    let shutdown = listen_for_shutdown();
+    // messages that were not delivered are consumed again after restart
+    let attempts = Arc::new(DeliveryAttempts::default());
 
     loop {
+        if *shutdown.borrow() {
+            info!("kafka-json-processor stopped.");
+            return;
+        }
+
         debug!("Starting runtime...");
 
         let config = config.clone();
@@ -72,121 +266,767 @@ pub fn run_processor(streams: HashMap<String, Stream>) {
             .build()
             .unwrap();
 
-        if let Err(e) = runtime.block_on(async {
+        let result = runtime.block_on(async {
             run_processing_tasks(
+                &KafkaBroker,
                 &runtime,
                 config,
                 streams.clone(),
+                attempts.clone(),
+                shutdown.clone(),
             ).await
-        }) {
-            warn!("RUNTIME ERROR: {:?}. Restarting...", e);
+        });
+        // processors abandoned after exceeding their time budget may still be running - do not wait for them
+        runtime.shutdown_background();
+
+        match result {
+            Ok(Stopped::Retry) => {}
+            Ok(Stopped::Shutdown) => {
+                info!("All messages were delivered, kafka-json-processor stopped.");
+                return;
+            }
+            Ok(Stopped::ShutdownIncomplete) => {
+                error!("Not all messages were delivered before shutdown. Exiting...");
+                std::process::exit(2);
+            }
+            Err(e) => {
+                health().failed();
+
+                if let Some(fatal) = e.downcast_ref::<FatalError>() {
+                    error!("FATAL ERROR: {fatal}. Exiting...");
+                    std::process::exit(1);
+                }
+
+                if *shutdown.borrow() {
+                    error!("RUNTIME ERROR during shutdown: {:?}. Exiting...", e);
+                    std::process::exit(2);
+                }
+
+                warn!("RUNTIME ERROR: {:?}. Restarting...", e);
+            }
+        }
+    }
+}
+
+/// Runs kafka-json-processor with given streams, reading input messages from `source` and writing output messages to `sink`
+/// (eg. [`JsonLinesSource`] and [`JsonLinesSink`] to process exported topic dumps without a broker).
+///
+/// Processing options are read from config as in [run_processor] (if the config does not exist, defaults are used).
+/// Offsets are not committed by kafka-json-processor and the journal is not used - delivery modes are available
+/// in [run_processor] only. All streams share the sink, so their producer properties are ignored.
+///
+/// Returns at the end of input or after a graceful shutdown. If not all messages could be delivered,
+/// exits with status code 2. Exits with status code 1 on errors.
+pub fn run_processor_with<S: Source, K: Sink>(streams: HashMap<String, Stream>, source: S, sink: K) {
+    info!("Starting kafka-json-processor...");
+    let config = read_valid_config(&streams, read_config_or_defaults);
+    process_with(config, streams, source, sink);
+}
+
+/// [run_processor_with] with a config that was already read (and validated).
+fn process_with<S: Source, K: Sink>(config: Config, streams: HashMap<String, Stream>, source: S, sink: K) {
+    let shutdown = listen_for_shutdown();
+
+    let runtime = Builder::new_multi_thread()
+        .enable_all()
+        .worker_threads(config.internal_config.worker_threads)
+        .build()
+        .unwrap();
+
+    let result = runtime.block_on(async {
+        run_pipeline(&runtime, config, streams, source, sink, shutdown).await
+    });
+    // processors abandoned after exceeding their time budget may still be running - do not wait for them
+    runtime.shutdown_background();
+
+    match result {
+        Ok(true) => info!("All messages were delivered, kafka-json-processor stopped."),
+        Ok(false) => {
+            error!("Not all messages were delivered. Exiting...");
+            std::process::exit(2);
+        }
+        Err(e) => {
+            error!("ERROR: {e}. Exiting...");
+            std::process::exit(1);
         }
     }
 }
 
+/// Runs kafka-json-processor with the source and the sink chosen by command line arguments (used by generated projects):
+/// * `--input <path>` - reads messages from a JSON-lines file, a directory of such files or stdin (`-`) instead of Kafka,
+/// * `--output <path>` - writes messages to a JSON-lines file, a directory (a file per topic) or stdout (`-`) instead of Kafka.
+///
+/// See [`JsonLinesSource`] and [`JsonLinesSink`] for the format of messages. With any of those arguments, the config is read
+/// as in [run_processor_with] (defaults are used if it does not exist) - also for the Kafka consumer or producer.
+/// Without those arguments, it's the same as [run_processor].
+pub fn run_processor_from_args(streams: HashMap<String, Stream>) {
+    let (input, output) = match io_args(std::env::args().skip(1)) {
+        Ok(io) => io,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Kafka clients (if any) are created with the same config as the one used for processing
+    let config = || {
+        info!("Starting kafka-json-processor...");
+        read_valid_config(&streams, read_config_or_defaults)
+    };
+
+    match (input, output) {
+        (None, None) => run_processor(streams),
+        (Some(input), Some(output)) => {
+            let config = config();
+            process_with(config, streams, or_exit(JsonLinesSource::open(&input)), or_exit(JsonLinesSink::open(&output)))
+        }
+        (Some(input), None) => {
+            let config = config();
+            let sink = or_exit(KafkaSink::new(&config.producer_config));
+            process_with(config, streams, or_exit(JsonLinesSource::open(&input)), sink)
+        }
+        (None, Some(output)) => {
+            let config = config();
+            let source = or_exit(KafkaSource::new(&config.consumer_config));
+            process_with(config, streams, source, or_exit(JsonLinesSink::open(&output)))
+        }
+    }
+}
+
+/// Returns paths of `--input` and `--output` arguments (`--input path` or `--input=path`).
+fn io_args(args: impl Iterator<Item = String>) -> Result<(Option<String>, Option<String>), String> {
+    let mut input = None;
+    let mut output = None;
+    let mut args = args;
+
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let target = match name.as_str() {
+            "--input" => &mut input,
+            "--output" => &mut output,
+            _ => continue,
+        };
+        let path = value.or_else(|| args.next())
+            .ok_or(format!("Option {name} requires a path (or - for stdin/stdout)."))?;
+        *target = Some(path);
+    }
+
+    Ok((input, output))
+}
+
+fn or_exit<T, E: Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("{e}. Exiting...");
+        std::process::exit(1);
+    })
+}
+
+/// Reads the config with `read` and validates it against the streams. Exits if the config is invalid.
+fn read_valid_config(streams: &HashMap<String, Stream>, read: impl Fn(&str) -> Result<Config, ConfigError>) -> Config {
+    let config_path = config_path();
+    info!("Reading config from {}", config_path);
+    let config = match read(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid config {config_path}: {e}. Exiting...");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = validate_streams(&config, streams) {
+        error!("Invalid config {config_path}: {e}. Exiting...");
+        std::process::exit(1);
+    }
+
+    config
+}
+
+/// Reads the config, or uses defaults (with environment overrides) if the config does not exist.
+fn read_config_or_defaults(config_path: &str) -> Result<Config, ConfigError> {
+    if Path::new(config_path).exists() {
+        Config::read_from(config_path)
+    } else {
+        info!("Config {config_path} does not exist, using defaults.");
+        Config::from_env()
+    }
+}
+
+/// Checks the config without starting kafka-json-processor (`--check-config` option of generated projects).
+///
+/// The config is read and validated, then Kafka clients are created (without connecting) to validate rdkafka properties.
+/// Prints the result and returns `true` if the config is valid.
+pub fn check_config() -> bool {
+    let config_path = config_path();
+    let result = Config::read_from(&config_path)
+        .map_err(|e| e.to_string())
+        .and_then(|config| {
+            config.consumer_config.create::<BaseConsumer>()
+                .map_err(|e| format!("Invalid consumer config: {e}"))?;
+            config.producer_config.create::<BaseProducer>()
+                .map_err(|e| format!("Invalid producer config: {e}"))?;
+            for (name, stream_config) in &config.stream_configs {
+                producer_config(&config, &stream_config.producer_config).create::<BaseProducer>()
+                    .map_err(|e| format!("Invalid producer config of stream {name}: {e}"))?;
+            }
+            Ok(())
+        });
+
+    match result {
+        Ok(()) => {
+            println!("Config {config_path} is valid.");
+            true
+        }
+        Err(e) => {
+            eprintln!("Invalid config {config_path}: {e}");
+            false
+        }
+    }
+}
+
+/// Rejects stream overrides in config that do not match any stream, per-stream producer properties
+/// and state changelog topics in exactly-once delivery mode.
+fn validate_streams(config: &Config, streams: &HashMap<String, Stream>) -> Result<(), String> {
+    let matched: Vec<&StreamConfig> = streams.keys()
+        .filter_map(|name| config.stream_config(name))
+        .collect();
+    if let Some(name) = config.stream_configs.iter()
+        .find(|(_, stream_config)| !matched.iter().any(|matched| std::ptr::eq(*matched, *stream_config)))
+        .map(|(name, _)| name) {
+        let mut names: Vec<&str> = streams.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        return Err(format!("There is no stream {name} (configured by stream.{name}.* properties). Available streams: {}.", names.join(", ")));
+    }
+
+    if config.internal_config.delivery_mode == DeliveryMode::ExactlyOnce {
+        if let Some((name, _)) = streams.iter().find(|(_, stream)| !stream.producer_config.is_empty()) {
+            return Err(format!("Stream {name} has its own producer properties, which contradicts processor.delivery.mode=exactly-once \
+                (all streams must share the transactional producer)."));
+        }
+
+        let has_changelog = |(name, stream): &(&String, &Stream)| config.stream_config(name)
+            .and_then(|overrides| overrides.state_changelog_topic.clone())
+            .unwrap_or_else(|| stream.state_changelog_topic.clone())
+            .is_some();
+        if let Some((name, _)) = streams.iter().find(has_changelog) {
+            return Err(format!("Stream {name} has a state changelog topic, which contradicts processor.delivery.mode=exactly-once \
+                (changelog records cannot be produced in transactions)."));
+        }
+    }
+
+    Ok(())
+}
+
+/// Producer config with given rdkafka properties overriding `producer.*` properties.
+fn producer_config(config: &Config, overrides: &[(String, String)]) -> ClientConfig {
+    let mut producer_config = config.producer_config.clone();
+    overrides.iter()
+        .for_each(|(key, value)| {
+            producer_config.set(key, value);
+        });
+    producer_config
+}
+
+/// Path of the config - `KAFKA_PROCESSOR_CONFIG_PATH` or `./processor.properties` by default.
+fn config_path() -> String {
+    let config_env = "KAFKA_PROCESSOR_CONFIG_PATH";
+    let default_config_path = "./processor.properties";
+    std::env::var(config_env)
+        .unwrap_or_else(|_| {
+            info!("Environment variable {} not found, using default config path.", config_env);
+            default_config_path.to_string()
+        })
+}
+
 macro_rules! exec_or_retry_in_10s {
-    ($supplier:expr) => {
+    ($supplier:expr, $shutdown:expr) => {
         match $supplier {
             Ok(value) => value,
             Err(e) => {
+                health().failed();
                 error!("Connection error: [{}], retrying in 10 seconds...", e);
-                std::thread::sleep(Duration::from_secs(10));
-                return Ok(());
+                // retry is cancelled if shutdown is requested in the meantime
+                let _ = timeout(Duration::from_secs(10), shutdown_requested(&mut $shutdown)).await;
+                return Ok(Stopped::Retry);
             }
         }
     };
 }
 
-async fn run_processing_tasks(
+async fn run_processing_tasks<B: Broker>(
+    broker: &B,
     runtime: &Runtime,
     config: Config,
     streams: HashMap<String, Stream>,
-) -> Result<(), Box<dyn Error>> {
-    let consumer: StreamConsumer = exec_or_retry_in_10s!(config.consumer_config.create());
-    let producer: BaseProducer = exec_or_retry_in_10s!(config.producer_config.create());
-
+    attempts: Arc<DeliveryAttempts>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<Stopped, Box<dyn Error>> {
     let offset_holder = MessageOffsetHolder::new(
-        config.internal_config.journal_path,
+        config.internal_config.journal_path.clone(),
         config.internal_config.journal_enabled,
     )?;
 
     let offset_holder = Arc::new(offset_holder);
-    let producer_offset_holder = offset_holder.clone();
+    let tracker = Arc::new(OffsetTracker::new(
+        config.internal_config.delivery_mode,
+        offset_holder.clone(),
+        config.internal_config.max_in_flight,
+        config.internal_config.delivery_max_attempts,
+        attempts,
+    ));
+    info!("Delivery mode: {}", config.internal_config.delivery_mode);
+
+    let source = Arc::new(exec_or_retry_in_10s!(broker.source(&config.consumer_config), shutdown));
+
+    let streams = with_defaults(streams, &config);
+    // streams with the same producer properties share a producer
+    let mut sinks: BTreeMap<Vec<(String, String)>, B::Sink> = BTreeMap::new();
+    for stream in streams.values() {
+        if !sinks.contains_key(&stream.producer_config) {
+            let sink = exec_or_retry_in_10s!(broker.sink(&producer_config(&config, &stream.producer_config)), shutdown);
+            sinks.insert(stream.producer_config.clone(), sink);
+        }
+    }
+
+    let streams = group_by_source_topic(streams);
+    let offsets = if config.internal_config.delivery_mode == DeliveryMode::ExactlyOnce {
+        // offsets committed in transactions are the only source of truth
+        HashMap::new()
+    } else {
+        offset_holder.offsets()
+    };
+    show_streams(&streams);
+
+    // tables are loaded before subscribing, so the consumer does not leave its group while waiting
+    let lookups = LookupTables::new(streams.values().flatten());
+    if !lookups.is_empty() {
+        let lookup_source = exec_or_retry_in_10s!(broker.source(&lookup_consumer_config(&config.consumer_config)), shutdown);
+        let end_offsets = exec_or_retry_in_10s!(lookup_source.read_all(&lookups.topics()), shutdown);
+        let (loaded_tx, loaded_rx) = oneshot::channel();
+        runtime.spawn(lookup_loop(lookup_source, lookups.clone(), end_offsets, loaded_tx));
+        info!("Loading lookup tables: {}...", lookups.topics().join(", "));
+        tokio::select! {
+            _ = shutdown_requested(&mut shutdown) => {
+                info!("Shutdown requested while loading lookup tables.");
+                return Ok(Stopped::Shutdown);
+            }
+            _ = loaded_rx => {}
+        }
+    }
+
+    source.subscribe_from(&source_topics(&streams), offsets)
+        .map_err(|e| e as Box<dyn Error>)?;
 
-    show_streams_and_subscribe(&consumer, &streams, offset_holder.offsets())?;
+    let shutdown_timeout = Duration::from_millis(config.internal_config.shutdown_timeout_ms as u64);
+    let mut senders: BTreeMap<Vec<(String, String)>, Sender<PendingMessage>> = BTreeMap::new();
+    let mut producer_handles = vec![];
+    for (overrides, sink) in sinks {
+        if !overrides.is_empty() {
+            info!("Starting producer with properties: {}.", overrides.iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<String>>()
+                .join(", "));
+        }
+        let (tx, rx) = mpsc::channel(config.internal_config.channel_capacity);
+        producer_handles.push(spawn_producer(broker, runtime, sink, rx, &source, tracker.clone(), &config));
+        senders.insert(overrides, tx);
+    }
+    let (changelog_tx, changelog_rx) = changelog_channel();
+    let changelog_handle = if streams.values().flatten().any(|stream| stream.state_changelog_topic.is_some()) {
+        // there are no changelog topics in exactly-once delivery mode, so the producer is never transactional
+        let sink = exec_or_retry_in_10s!(broker.sink(&producer_config(&config, &[])), shutdown);
+        Some(runtime.spawn(changelog_loop(sink, changelog_rx, shutdown_timeout)))
+    } else {
+        None
+    };
+    let changelog_reader = broker.changelog_reader(&config.consumer_config);
+    let journal_dir = Some(config.internal_config.journal_path.as_str())
+        .filter(|_| config.internal_config.journal_enabled);
+    let streams = with_workers(runtime, streams, &senders, &tracker, &config, |stream| {
+        StateStore::new(stream, journal_dir, Some((changelog_reader.clone(), &changelog_tx)), lookups.of_stream(stream))
+    });
+    // consumer loop holds only senders of its workers, so producers can detect the end of processing
+    drop(senders);
+    drop(changelog_tx);
+    let state_stores = state_stores(&streams);
 
-    let (tx, rx) = bounded(config.internal_config.channel_capacity);
+    let journal_offset_holder = offset_holder.clone();
+    let journal_state_stores = state_stores.clone();
     runtime.spawn(async move {
-        producer_loop(
-            producer,
-            rx,
-            config.internal_config.queue_size,
-            Duration::from_millis(config.internal_config.queue_slowdown_ms as u64),
-            producer_offset_holder,
+        journal_flush_loop(journal_offset_holder, journal_state_stores).await;
+    });
+
+    let commit_source = source.clone();
+    let commit_tracker = tracker.clone();
+    runtime.spawn(async move {
+        commit_loop(
+            commit_source,
+            commit_tracker,
+            Duration::from_millis(config.internal_config.commit_interval_ms as u64),
         ).await;
     });
 
+    let health_source = source.clone();
     runtime.spawn(async move {
-        journal_flush_loop(offset_holder).await;
+        health_loop(health_source).await;
     });
 
-    consumer_loop(consumer, tx, runtime, streams).await
+    health().started();
+    if let Err(e) = consumer_loop(source.as_ref(), runtime, streams, tracker.clone(), shutdown).await {
+        // consumer stops if a producer stopped, so the reason is in producer result
+        for producer_handle in producer_handles {
+            producer_handle.await?
+                .map_err(|e| e as Box<dyn Error>)?;
+        }
+
+        return Err(e);
+    }
+
+    // Shutdown requested. Consumer has stopped and dropped its senders - once all processing tasks finish,
+    // channels are disconnected and producer loops deliver remaining messages and stop.
+    health().stopped();
+    info!("Waiting for in-flight messages to be delivered (timeout: {}ms)...", shutdown_timeout.as_millis());
+    let delivered = match timeout(shutdown_timeout, join_producers(producer_handles)).await {
+        Ok(result) => match result? {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Producer failed during shutdown: {e}");
+                false
+            }
+        },
+        Err(_) => {
+            error!("Processing tasks did not finish in {}ms.", shutdown_timeout.as_millis());
+            false
+        }
+    };
+
+    // all messages were processed, so state will not change anymore
+    state_stores.iter().for_each(|store| store.close_changelog());
+    if let Some(changelog_handle) = changelog_handle {
+        if timeout(shutdown_timeout, changelog_handle).await.is_err() {
+            error!("State changes were not written to changelog topics in {}ms.", shutdown_timeout.as_millis());
+        }
+    }
+
+    tracker.commit_on_shutdown(source.as_ref());
+    offset_holder.flush();
+    state_stores.iter().for_each(|store| store.flush());
+
+    Ok(if delivered { Stopped::Shutdown } else { Stopped::ShutdownIncomplete })
 }
 
-fn show_streams_and_subscribe(consumer: &StreamConsumer, streams: &HashMap<String, Stream>, offsets: HashMap<OffsetKey, i64>) -> Result<(), Box<dyn Error>> {
-    streams.values()
-        .for_each(|stream| {
-            info!("Stream [{}] --> [{}]: {} processor(s).", stream.source_topic, stream.target_topic, stream.processors.len());
+/// Processing tasks of [run_processor_with]. Returns `false` if not all messages were delivered.
+async fn run_pipeline<S: Source, K: Sink>(
+    runtime: &Runtime,
+    config: Config,
+    streams: HashMap<String, Stream>,
+    source: S,
+    sink: K,
+    shutdown: watch::Receiver<bool>,
+) -> Result<bool, Box<dyn Error>> {
+    if config.internal_config.delivery_mode != DeliveryMode::AutoCommit {
+        warn!("processor.delivery.mode={} is ignored, offsets are not committed by kafka-json-processor \
+            with custom source and sink.", config.internal_config.delivery_mode);
+    }
+    // offsets are not committed, so they are not saved in journal either
+    let offset_holder = MessageOffsetHolder::new(config.internal_config.journal_path.clone(), false)?;
+    let tracker = Arc::new(OffsetTracker::new(
+        DeliveryMode::AutoCommit,
+        Arc::new(offset_holder),
+        config.internal_config.max_in_flight,
+        config.internal_config.delivery_max_attempts,
+        Arc::default(),
+    ));
+
+    let mut streams = with_defaults(streams, &config);
+    streams.iter_mut()
+        .filter(|(_, stream)| !stream.producer_config.is_empty())
+        .for_each(|(name, stream)| {
+            warn!("Producer properties of stream {name} are ignored, all streams share the sink.");
+            stream.producer_config.clear();
         });
+    let streams = group_by_source_topic(streams);
+    show_streams(&streams);
+    source.subscribe(&source_topics(&streams))
+        .map_err(|e| e as Box<dyn Error>)?;
 
-    let topics: Vec<&str> = streams.keys()
-        .map(|key| key.as_str())
-        .collect();
+    let shutdown_timeout = Duration::from_millis(config.internal_config.shutdown_timeout_ms as u64);
+    let queue_size = config.internal_config.queue_size;
+    let (tx, rx) = mpsc::channel(config.internal_config.channel_capacity);
+    let producer_tracker = tracker.clone();
+    let producer_handle = runtime.spawn(async move {
+        producer_loop(sink, rx, queue_size, producer_tracker, shutdown_timeout).await
+    });
+    let senders = BTreeMap::from([(vec![], tx)]);
+    // offsets are not saved, so state is not saved either (it would not match the offsets)
+    let streams = with_workers(runtime, streams, &senders, &tracker, &config, |stream| {
+        if stream.state_changelog_topic.is_some() {
+            warn!("State changelog topic of stream [{}] --> [{}] is ignored with custom source and sink.", stream.source_topic, stream.target_topic);
+        }
+        if !stream.lookup_tables.is_empty() {
+            warn!("Lookup tables of stream [{}] --> [{}] are not loaded with custom source and sink (lookups find nothing).", stream.source_topic, stream.target_topic);
+        }
+        StateStore::new(stream, None, None, LookupTables::default())
+    });
+    drop(senders);
 
-    consumer.subscribe(&topics)?;
+    health().started();
+    if let Err(e) = consumer_loop(&source, runtime, streams, tracker.clone(), shutdown.clone()).await {
+        // consumer stops if the producer stopped, so the reason is in producer result
+        producer_handle.await?
+            .map_err(|e| e as Box<dyn Error>)?;
+        return Err(e);
+    }
+    health().stopped();
+
+    // Consumer has stopped (end of input or shutdown) and dropped its senders - once all processing tasks finish,
+    // the producer loop writes remaining messages, flushes the sink and stops.
+    let result = if *shutdown.borrow() {
+        info!("Waiting for in-flight messages to be delivered (timeout: {}ms)...", shutdown_timeout.as_millis());
+        match timeout(shutdown_timeout, producer_handle).await {
+            Ok(result) => result?,
+            Err(_) => {
+                error!("Processing tasks did not finish in {}ms.", shutdown_timeout.as_millis());
+                return Ok(false);
+            }
+        }
+    } else {
+        producer_handle.await?
+    };
 
-    let mut topic_list = TopicPartitionList::new();
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.is::<FatalError>() => Err(e),
+        Err(e) => {
+            error!("Producer failed: {e}");
+            Ok(false)
+        }
+    }
+}
 
-    // add topics with saved offsets to topic_list and store those topics in a vec
-    let topics_with_offsets: Vec<String> = offsets.into_iter()
-        .filter(|(offset_key, _)| {
-            // topic is valid if it's associated with known stream
-            topics.contains(&offset_key.0.as_str())
+type ProducerHandle = JoinHandle<ProducerResult>;
+
+/// Spawns the producer loop (transactional in exactly-once delivery mode).
+fn spawn_producer<B: Broker>(
+    broker: &B,
+    runtime: &Runtime,
+    sink: B::Sink,
+    rx: Receiver<PendingMessage>,
+    source: &Arc<B::Source>,
+    tracker: Arc<OffsetTracker>,
+    config: &Config,
+) -> ProducerHandle {
+    let queue_size = config.internal_config.queue_size;
+    let shutdown_timeout = Duration::from_millis(config.internal_config.shutdown_timeout_ms as u64);
+
+    if config.internal_config.delivery_mode == DeliveryMode::ExactlyOnce {
+        let settings = TransactionSettings {
+            batch_size: config.internal_config.transaction_batch_size,
+            interval: Duration::from_millis(config.internal_config.transaction_interval_ms as u64),
+            timeout: Duration::from_millis(config.internal_config.transaction_timeout_ms as u64),
+            max_deferred: config.internal_config.max_in_flight,
+        };
+        runtime.spawn(broker.transactional_producer(sink, rx, source, queue_size, tracker, settings))
+    } else {
+        runtime.spawn(async move {
+            producer_loop(
+                sink,
+                rx,
+                queue_size,
+                tracker,
+                shutdown_timeout,
+            ).await
         })
-        .filter_map(|(offset_key, offset)| {
-            trace!("Adding topic [Topic: {}] [Partition: {}] with offset {}", &offset_key.0, offset_key.1, offset);
+    }
+}
 
-            topic_list.add_partition_offset(&offset_key.0, offset_key.1, Offset::Offset(offset))
-                .map_err(|e| {
-                    error!("Cannot assign topic with offset {offset} [Topic: {}] [Partition: {}]. Reason: {e}", &offset_key.0, offset_key.1)
-                })
-                .map(|_| {
-                    offset_key.0
+/// Waits for all producer loops. Returns the first error (after all loops finished).
+async fn join_producers(producer_handles: Vec<ProducerHandle>) -> Result<ProducerResult, Box<dyn Error>> {
+    let mut result = Ok(());
+    for producer_handle in producer_handles {
+        let producer_result = producer_handle.await?;
+        if result.is_ok() {
+            result = producer_result;
+        }
+    }
+    Ok(result)
+}
+
+/// Fills stream options with overrides from `stream.<name>.*` properties,
+/// then fills options that were still not set with defaults from config.
+fn with_defaults(streams: HashMap<String, Stream>, config: &Config) -> HashMap<String, Stream> {
+    let defaults = &config.internal_config;
+
+    streams.into_iter()
+        .map(|(name, mut stream)| {
+            let overrides = config.stream_config(&name).cloned().unwrap_or_default();
+
+            stream.dead_letter_topic = match overrides.dead_letter_topic {
+                // can be overridden with an empty value to disable dead-letter topic of this stream
+                Some(topic) => topic,
+                None => stream.dead_letter_topic.or_else(|| defaults.dead_letter_topic.clone()),
+            };
+            stream.propagate_headers = overrides.propagate_headers.or(stream.propagate_headers).or(Some(defaults.propagate_headers));
+            stream.key_policy = overrides.key_policy.or(stream.key_policy).or_else(|| Some(defaults.key_policy.clone()));
+            stream.null_payload_policy = overrides.null_payload_policy.or(stream.null_payload_policy).or(Some(defaults.null_payload_policy));
+            stream.invalid_payload_policy = overrides.invalid_payload_policy.or(stream.invalid_payload_policy).or(Some(defaults.invalid_payload_policy));
+            stream.ordering = overrides.ordering.or(stream.ordering).or(Some(defaults.ordering));
+            stream.error_policy = overrides.error_policy.or(stream.error_policy).or(Some(defaults.error_policy));
+            stream.concurrency = overrides.concurrency.or(stream.concurrency);
+            stream.time_budget_ms = overrides.time_budget_ms.or(stream.time_budget_ms).or(Some(defaults.time_budget_ms));
+            stream.state_changelog_topic = match overrides.state_changelog_topic {
+                // can be overridden with an empty value to disable the changelog of this stream
+                Some(topic) => topic,
+                None => stream.state_changelog_topic,
+            };
+            stream.dedup = match overrides.dedup {
+                // can be overridden with an empty value to disable deduplication of this stream
+                Some(policy) => policy,
+                None => stream.dedup,
+            };
+            stream.dedup_window_ms = overrides.dedup_window_ms.or(stream.dedup_window_ms).or(Some(defaults.dedup_window_ms));
+            stream.dedup_max_entries = overrides.dedup_max_entries.or(stream.dedup_max_entries).or(Some(defaults.dedup_max_entries));
+            stream.aggregation = match overrides.aggregation {
+                // can be overridden with an empty value to disable aggregation of this stream
+                Some(aggregation) => aggregation,
+                None => stream.aggregation,
+            };
+            if let Some(topics) = overrides.lookup_tables {
+                stream.lookup_tables = topics;
+            }
+            if stream.aggregation.is_some() && stream.ordering != Some(OrderingPolicy::Partition) {
+                // windows of a partition must see its messages in order
+                warn!("Stream [{}] --> [{}] aggregates messages, so it will be ordered by partition (instead of {}).",
+                    stream.source_topic, stream.target_topic, stream.ordering.unwrap_or(OrderingPolicy::Unordered));
+                stream.ordering = Some(OrderingPolicy::Partition);
+            }
+            if stream.state_changelog_topic.is_some() && stream.ordering == Some(OrderingPolicy::Unordered) {
+                // concurrent updates of the same state would overwrite each other
+                warn!("Stream [{}] --> [{}] keeps state (backed up to a changelog topic), so it will be ordered by partition (instead of {}).",
+                    stream.source_topic, stream.target_topic, OrderingPolicy::Unordered);
+                stream.ordering = Some(OrderingPolicy::Partition);
+            }
+            let ordered_by_dedup_id = matches!((&stream.dedup, stream.ordering), (Some(DedupPolicy::Key), Some(OrderingPolicy::Key)));
+            if stream.dedup.is_some() && stream.ordering != Some(OrderingPolicy::Partition) && !ordered_by_dedup_id {
+                // the first of messages with the same id (in a partition) is kept, not the one that's processed first
+                warn!("Stream [{}] --> [{}] deduplicates messages, so it will be ordered by partition (instead of {}).",
+                    stream.source_topic, stream.target_topic, stream.ordering.unwrap_or(OrderingPolicy::Unordered));
+                stream.ordering = Some(OrderingPolicy::Partition);
+            }
+            for (key, value) in overrides.producer_config {
+                stream.producer_config.retain(|(stream_key, _)| *stream_key != key);
+                stream.producer_config.push((key, value));
+            }
+            // producers are shared by streams with the same properties, regardless of their order
+            stream.producer_config.sort();
+            (name, stream)
+        })
+        .collect()
+}
+
+/// Pairs streams with senders of their producers and concurrency limits (or ordered lanes).
+fn with_workers(
+    runtime: &Runtime,
+    streams: HashMap<String, Vec<Stream>>,
+    senders: &BTreeMap<Vec<(String, String)>, Sender<PendingMessage>>,
+    tracker: &Arc<OffsetTracker>,
+    config: &Config,
+    state_store: impl Fn(&Stream) -> StateStore,
+) -> HashMap<String, Vec<StreamWorker>> {
+    streams.into_iter()
+        .map(|(topic, streams)| {
+            let workers = streams.into_iter()
+                .map(|stream| {
+                    let tx = senders[&stream.producer_config].clone();
+                    let state = Arc::new(state_store(&stream));
+                    let (permits, lanes) = match stream.ordering.unwrap_or(OrderingPolicy::Unordered) {
+                        OrderingPolicy::Unordered =>
+                            (stream.concurrency.map(|concurrency| Arc::new(Semaphore::new(concurrency))), None),
+                        _ => {
+                            let count = stream.concurrency.unwrap_or(config.internal_config.worker_threads);
+                            let capacity = config.internal_config.channel_capacity;
+                            (None, Some(OrderedLanes::spawn(runtime, count, capacity, &stream, &state, &tx, tracker)))
+                        }
+                    };
+                    StreamWorker { stream, tx, state, permits, lanes }
                 })
-                .ok()
+                .collect();
+            (topic, workers)
         })
-        .collect();
+        .collect()
+}
+
+fn state_stores(streams: &HashMap<String, Vec<StreamWorker>>) -> Vec<Arc<StateStore>> {
+    streams.values()
+        .flatten()
+        .map(|worker| worker.state.clone())
+        .collect()
+}
 
-    topics.iter()
-        .filter(|topic| !topics_with_offsets.contains(&topic.to_string()))
-        .for_each(|topic| {
-            trace!("Adding unassigned topic [Topic: {topic}]");
-            topic_list.add_topic_unassigned(topic);
+/// Indexes streams by their source topic.
+fn group_by_source_topic(streams: HashMap<String, Stream>) -> HashMap<String, Vec<Stream>> {
+    let mut by_topic: HashMap<String, Vec<Stream>> = HashMap::new();
+
+    streams.into_values()
+        .for_each(|stream| {
+            by_topic.entry(stream.source_topic.clone())
+                .or_default()
+                .push(stream);
         });
 
-    if let Err(e) = consumer.assign(&topic_list) {
-        error!("Cannot assign topics. Topics or offsets are incorrect. Will continue anyway. Reason for failure: {e}");
-    }
+    by_topic
+}
 
-    Ok(())
+fn show_streams(streams: &HashMap<String, Vec<Stream>>) {
+    streams.values()
+        .flatten()
+        .for_each(|stream| {
+            info!("Stream [{}] --> [{}]: {} processor(s).", stream.source_topic, stream.target_topic, stream.processors.len());
+            match &stream.dead_letter_topic {
+                Some(topic) => info!("Stream [{}] --> [{}]: Dead-letter topic: [{topic}].", stream.source_topic, stream.target_topic),
+                None => warn!("Stream [{}] --> [{}]: No dead-letter topic, messages that cannot be processed will be lost.", stream.source_topic, stream.target_topic),
+            }
+            if let Some(concurrency) = stream.concurrency {
+                info!("Stream [{}] --> [{}]: Concurrency: {concurrency}.", stream.source_topic, stream.target_topic);
+            }
+            if let Some(ordering) = stream.ordering.filter(|ordering| *ordering != OrderingPolicy::Unordered) {
+                info!("Stream [{}] --> [{}]: Output is ordered by {ordering}.", stream.source_topic, stream.target_topic);
+            }
+            if let Some(error_policy) = stream.error_policy.filter(|policy| *policy != ErrorPolicy::Continue) {
+                info!("Stream [{}] --> [{}]: Error policy: {error_policy}.", stream.source_topic, stream.target_topic);
+            }
+            if let Some(budget) = stream.time_budget() {
+                info!("Stream [{}] --> [{}]: Time budget of a processor: {}ms.", stream.source_topic, stream.target_topic, budget.as_millis());
+            }
+            if let Some(topic) = &stream.state_changelog_topic {
+                info!("Stream [{}] --> [{}]: State changelog topic: [{topic}].", stream.source_topic, stream.target_topic);
+            }
+            if let Some(dedup) = &stream.dedup {
+                info!("Stream [{}] --> [{}]: Deduplication by {dedup} within {}ms.", stream.source_topic, stream.target_topic, stream.dedup_window_ms.unwrap_or_default());
+            }
+            if let Some(aggregation) = &stream.aggregation {
+                info!("Stream [{}] --> [{}]: Windowed aggregation: {aggregation}.", stream.source_topic, stream.target_topic);
+            }
+            if !stream.lookup_tables.is_empty() {
+                info!("Stream [{}] --> [{}]: Lookup tables: [{}].", stream.source_topic, stream.target_topic, stream.lookup_tables.join("], ["));
+            }
+        });
 }
 
-/// Runs a loop that flushes offsets every 30 seconds.
+fn source_topics(streams: &HashMap<String, Vec<Stream>>) -> Vec<&str> {
+    streams.keys()
+        .map(|key| key.as_str())
+        .collect()
+}
+
+/// Runs a loop that flushes offsets (and state of streams) every 30 seconds.
 ///
 /// Flushing saves current offsets to a journal on disk.
 /// It is used to subscribe to topics from given offsets in case of crash or client id change
 /// (when we cannot be sure where we finished during last run).
-async fn journal_flush_loop(offset_holder: Arc<MessageOffsetHolder>) {
+async fn journal_flush_loop(offset_holder: Arc<MessageOffsetHolder>, state_stores: Vec<Arc<StateStore>>) {
     let mut journal_interval = interval(Duration::from_secs(30));
 
     loop {
@@ -194,5 +1034,62 @@ async fn journal_flush_loop(offset_holder: Arc<MessageOffsetHolder>) {
 
         debug!("Timeout, flushing journal");
         offset_holder.flush();
+        state_stores.iter().for_each(|store| store.flush());
+    }
+}
+
+/// Runs a loop that commits acknowledged offsets to Kafka (in at-least-once delivery mode).
+async fn commit_loop<S: Source>(source: Arc<S>, tracker: Arc<OffsetTracker>, commit_interval: Duration) {
+    // the first tick of an interval completes at once, but offsets are committed only after the first interval
+    let mut commit_interval = interval_at(Instant::now() + commit_interval, commit_interval);
+
+    loop {
+        commit_interval.tick().await;
+        tracker.commit(source.as_ref(), CommitMode::Async);
     }
-}
\ No newline at end of file
+}
+
+/// Runs a loop that checks whether the consumer has partitions assigned (for readiness endpoint).
+async fn health_loop<S: Source>(source: Arc<S>) {
+    let mut health_interval = interval(Duration::from_secs(5));
+
+    loop {
+        health_interval.tick().await;
+        health().consumer_assigned(source.assigned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::config::Config;
+    use crate::{io_args, validate_streams, Stream};
+
+    #[test]
+    fn should_parse_input_and_output_arguments() {
+        let args = |args: &[&str]| io_args(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(Ok((None, None)), args(&["--check-config"]));
+        assert_eq!(Ok((Some("dump.jsonl".to_string()), Some("-".to_string()))), args(&["--input", "dump.jsonl", "--output=-"]));
+        assert!(args(&["--output"]).is_err());
+    }
+
+    #[test]
+    fn should_reject_changelog_topics_in_exactly_once_mode() {
+        let streams = |changelog: Option<&str>| HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            state_changelog_topic: changelog.map(|topic| topic.to_string()),
+            ..Default::default()
+        })]);
+        let config = |properties: &str| format!("processor.delivery.mode=exactly-once\nproducer.transactional.id=kjp\n{properties}")
+            .parse::<Config>()
+            .unwrap();
+
+        assert!(validate_streams(&config(""), &streams(Some("in_out_state")))
+            .is_err_and(|e| e.contains("Stream in_out has a state changelog topic")));
+        assert!(validate_streams(&config("stream.in_out.state.changelog.topic=in_out_state"), &streams(None)).is_err());
+        // the changelog of the template can be disabled in config
+        assert!(validate_streams(&config("stream.in_out.state.changelog.topic="), &streams(Some("in_out_state"))).is_ok());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use log::{debug, error, info, warn};
+use rdkafka::ClientConfig;
+use serde_json::Value;
+use tokio::sync::oneshot;
+use crate::Stream;
+use crate::journal::OffsetKey;
+use crate::source::{Source, SourceMessage};
+
+/// Records of a compacted topic by their keys, materialized in memory.
+///
+/// A stream declares the tables its processors use with [`Stream::lookup_tables`] and processors look records up
+/// with [`crate::processor::OutputMessage::lookup`]. Tables are read by a separate consumer - from the beginning of their topics
+/// when kafka-json-processor starts (messages are not consumed until all tables are loaded), then kept up to date.
+/// The key of a record is the Kafka key (as a UTF-8 string), its value is the JSON payload. A tombstone deletes the record.
+///
+/// Updates are not synchronized with messages of streams - a message is enriched with the latest record read so far.
+pub struct LookupTable {
+    topic: String,
+    records: RwLock<HashMap<String, Value>>,
+}
+
+impl LookupTable {
+    fn new(topic: &str) -> LookupTable {
+        LookupTable {
+            topic: topic.to_string(),
+            records: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the record with given key.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.records.read().unwrap().get(key).cloned()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.records.read().unwrap().len()
+    }
+
+    /// Applies a message of the topic of this table.
+    fn apply(&self, message: &SourceMessage) {
+        let context = &message.context;
+        let Some(key) = &context.key else {
+            warn!("Record of lookup table [{}] without a key (partition {}, offset {}) is ignored.", self.topic, context.partition, context.offset);
+            return;
+        };
+        let key = String::from_utf8_lossy(key).into_owned();
+
+        match message.payload.as_deref().map(serde_json::from_slice::<Value>) {
+            Some(Ok(value)) => {
+                self.records.write().unwrap().insert(key, value);
+            }
+            None => {
+                self.records.write().unwrap().remove(&key);
+            }
+            Some(Err(e)) => warn!("Record [{key}] of lookup table [{}] is not a valid JSON and is ignored: {e}", self.topic),
+        }
+    }
+}
+
+/// Lookup tables by their topics.
+#[derive(Clone, Default)]
+pub(crate) struct LookupTables {
+    tables: HashMap<String, Arc<LookupTable>>,
+}
+
+impl LookupTables {
+    /// Creates (empty) tables of all streams. Streams using the same topic share the table.
+    pub(crate) fn new<'a>(streams: impl IntoIterator<Item = &'a Stream>) -> LookupTables {
+        let tables = streams.into_iter()
+            .flat_map(|stream| stream.lookup_tables.iter())
+            .map(|topic| (topic.clone(), Arc::new(LookupTable::new(topic))))
+            .collect();
+        LookupTables { tables }
+    }
+
+    /// Tables declared by the stream.
+    pub(crate) fn of_stream(&self, stream: &Stream) -> LookupTables {
+        let tables = self.tables.iter()
+            .filter(|(topic, _)| stream.lookup_tables.contains(topic))
+            .map(|(topic, table)| (topic.clone(), table.clone()))
+            .collect();
+        LookupTables { tables }
+    }
+
+    pub(crate) fn get(&self, table: &str, key: &str) -> Option<Value> {
+        self.tables.get(table)?.get(key)
+    }
+
+    pub(crate) fn topics(&self) -> Vec<&str> {
+        self.tables.keys()
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+}
+
+/// Consumer config of lookup tables - tables are read from the beginning every time, so offsets are never committed.
+pub(crate) fn lookup_consumer_config(consumer_config: &ClientConfig) -> ClientConfig {
+    let mut config = consumer_config.clone();
+    config.set("enable.auto.commit", "false");
+    config.set("auto.offset.reset", "earliest");
+    config
+}
+
+/// Reads topics of lookup tables and applies their records. Sends `loaded` when records up to `end_offsets`
+/// (offsets of the end of partitions when reading started) are applied.
+pub(crate) async fn lookup_loop<S: Source>(source: S, tables: LookupTables, mut end_offsets: HashMap<OffsetKey, i64>, loaded: oneshot::Sender<()>) {
+    let mut loaded = Some(loaded);
+
+    loop {
+        if end_offsets.is_empty() {
+            if let Some(loaded) = loaded.take() {
+                for (topic, table) in &tables.tables {
+                    info!("Lookup table [{topic}] loaded: {} record(s).", table.len());
+                }
+                let _ = loaded.send(());
+            }
+        }
+
+        let message = match source.recv().await {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                error!("Cannot consume record of a lookup table! Reason: {e}");
+                continue;
+            }
+            None => {
+                info!("End of lookup tables, they will not be updated anymore.");
+                return;
+            }
+        };
+
+        let context = &message.context;
+        debug!("Lookup table [{}] updated (partition {}, offset {}).", context.topic, context.partition, context.offset);
+        if let Some(table) = tables.tables.get(&context.topic) {
+            table.apply(&message);
+        }
+
+        let partition = OffsetKey(context.topic.clone(), context.partition);
+        if end_offsets.get(&partition).is_some_and(|end| context.offset + 1 >= *end) {
+            end_offsets.remove(&partition);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::json;
+    use tokio::runtime::Builder;
+    use tokio::sync::oneshot;
+    use crate::json_lines::JsonLinesSource;
+    use crate::journal::OffsetKey;
+    use crate::lookup::{lookup_loop, LookupTables};
+    use crate::source::Source;
+    use crate::Stream;
+
+    #[test]
+    fn should_apply_records_and_tombstones() {
+        let stream = Stream {
+            lookup_tables: vec!["customers".to_string()],
+            ..Default::default()
+        };
+        let tables = LookupTables::new([&stream]);
+        let input = r#"{"topic": "customers", "offset": 0, "key": "c1", "payload": {"name": "Alice"}}
+{"topic": "customers", "offset": 1, "key": "c2", "payload": {"name": "Bob"}}
+{"topic": "customers", "offset": 2, "key": "c1", "payload": {"name": "Alice Smith"}}
+{"topic": "customers", "offset": 3, "key": "c2", "payload": null}
+"#;
+        let path = std::env::temp_dir().join(format!("kjp-lookup-{}.jsonl", std::process::id()));
+        std::fs::write(&path, input).unwrap();
+        let source = JsonLinesSource::file(&path);
+        source.subscribe(&["customers"]).unwrap();
+        let (loaded_tx, loaded_rx) = oneshot::channel();
+
+        Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+            lookup_loop(source, tables.clone(), HashMap::from([(OffsetKey("customers".to_string(), 0), 4)]), loaded_tx).await;
+            assert!(loaded_rx.await.is_ok());
+        });
+
+        assert_eq!(Some(json!({"name": "Alice Smith"})), tables.get("customers", "c1"));
+        assert_eq!(None, tables.get("customers", "c2"));
+        assert_eq!(None, tables.of_stream(&Stream::default()).get("customers", "c1"));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,948 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::warn;
+use rdkafka::ClientConfig;
+use rdkafka::consumer::CommitMode;
+use tokio::runtime::Builder;
+use tokio::sync::{oneshot, watch};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::sleep;
+use crate::{run_processing_tasks, validate_streams, Broker, PendingMessage, ProducerResult, Stopped, Stream};
+use crate::config::Config;
+use crate::delivery::{DeliveryAttempts, OffsetTracker};
+use crate::error::FatalError;
+use crate::journal::OffsetKey;
+use crate::processor::MessageContext;
+use crate::sink::{Delivery, SendError, Sink, SinkMessage, QUEUE_FULL_BACKOFF};
+use crate::source::{Source, SourceMessage, SourceResult};
+use crate::state::{ChangelogReader, ChangelogRecord};
+use crate::transaction::TransactionSettings;
+
+/// In-memory stand-in for a Kafka cluster, to test kafka-json-processor end-to-end without a broker
+/// (available with the `memory-broker` feature).
+///
+/// Create topics ([`MemoryBroker::create_topic`]), publish input messages ([`MemoryBroker::publish`]),
+/// start the processor ([`MemoryBroker::start`]) and wait for output messages ([`MemoryBroker::wait_for_messages`]).
+/// Broker errors can be simulated with [`MemoryBroker::fail_next`].
+///
+/// The broker has a single consumer group - offsets committed by a processor are used by the next one started
+/// with the same broker (eg. after a restart). Partitions without committed offsets are read from the beginning.
+/// Processors read all partitions of their topics (there is no rebalancing).
+#[derive(Clone)]
+pub struct MemoryBroker {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    /// Wakes up threads waiting for messages.
+    published: Condvar,
+    /// Wakes up sources waiting for messages (or resumed).
+    changes: watch::Sender<u64>,
+    /// Failed deliveries are counted across processors started with this broker
+    /// (as across restarts of the runtime by [`crate::run_processor`]).
+    attempts: Arc<DeliveryAttempts>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Messages of each partition of a topic.
+    topics: HashMap<String, Vec<Vec<MemoryMessage>>>,
+    /// Offsets committed by the consumer group (offsets of the next messages to read).
+    committed: HashMap<OffsetKey, i64>,
+    /// Errors returned instead of sending next messages to a topic.
+    failures: HashMap<String, VecDeque<BrokerError>>,
+}
+
+/// A message stored in a topic of [`MemoryBroker`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryMessage {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    /// `None` for tombstones.
+    pub payload: Option<Vec<u8>>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Time of publishing in milliseconds.
+    pub timestamp: i64,
+}
+
+impl MemoryMessage {
+    /// Payload as a string (`None` for tombstones and non-UTF-8 payloads).
+    pub fn payload_str(&self) -> Option<&str> {
+        self.payload.as_deref()
+            .and_then(|payload| std::str::from_utf8(payload).ok())
+    }
+
+    /// Value of the header with given name.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+/// Broker error simulated by [`MemoryBroker::fail_next`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BrokerError {
+    /// The producer queue is full - the message is sent again after a while (as with Kafka).
+    QueueFull,
+    /// The topic does not exist - the message is sent to the dead-letter topic (if there is one).
+    UnknownTopic,
+    /// The message is not delivered (its delivery report is an error) - it's lost,
+    /// and its offset is not committed in at-least-once delivery mode.
+    DeliveryFailed,
+}
+
+impl Default for MemoryBroker {
+    fn default() -> Self {
+        MemoryBroker::new()
+    }
+}
+
+impl MemoryBroker {
+    /// Creates a broker without topics.
+    pub fn new() -> MemoryBroker {
+        MemoryBroker {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::default()),
+                published: Condvar::new(),
+                changes: watch::channel(0).0,
+                attempts: Arc::default(),
+            }),
+        }
+    }
+
+    /// Creates a topic with given number of partitions (does nothing if the topic exists).
+    /// Sending messages to a topic that does not exist fails (as with `auto.create.topics.enable=false`).
+    pub fn create_topic(&self, topic: &str, partitions: usize) -> &MemoryBroker {
+        self.inner.state.lock().unwrap()
+            .topics.entry(topic.to_string())
+            .or_insert_with(|| vec![vec![]; partitions.max(1)]);
+        self
+    }
+
+    /// Publishes a message to the topic (partition is chosen by hash of the key, messages without a key go to partition 0).
+    /// Returns the partition and the offset of the message.
+    ///
+    /// Panics if the topic does not exist.
+    pub fn publish(&self, topic: &str, key: Option<&[u8]>, payload: Option<&[u8]>) -> (i32, i64) {
+        self.append(&SinkMessage { topic, partition: None, key, payload, headers: &[] })
+            .unwrap_or_else(|| panic!("Topic [{topic}] does not exist."))
+    }
+
+    /// Makes next `count` attempts to send a message to the topic fail with given error.
+    pub fn fail_next(&self, topic: &str, error: BrokerError, count: usize) {
+        self.inner.state.lock().unwrap()
+            .failures.entry(topic.to_string())
+            .or_default()
+            .extend(std::iter::repeat_n(error, count));
+    }
+
+    /// Messages of the topic (ordered by partition and offset).
+    pub fn messages(&self, topic: &str) -> Vec<MemoryMessage> {
+        self.inner.state.lock().unwrap()
+            .topics.get(topic)
+            .map(|partitions| partitions.concat())
+            .unwrap_or_default()
+    }
+
+    /// Waits until the topic has at least `count` messages and returns all of its messages (as [`MemoryBroker::messages`]).
+    ///
+    /// Panics if there are not enough messages before `timeout`.
+    pub fn wait_for_messages(&self, topic: &str, count: usize, timeout: Duration) -> Vec<MemoryMessage> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.inner.state.lock().unwrap();
+
+        loop {
+            let messages: usize = state.topics.get(topic)
+                .map(|partitions| partitions.iter().map(Vec::len).sum())
+                .unwrap_or(0);
+            if messages >= count {
+                drop(state);
+                return self.messages(topic);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                panic!("Expected {count} message(s) in topic [{topic}], but there were {messages} after {}ms.", timeout.as_millis());
+            }
+            state = self.inner.published.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Offset committed by the consumer group (offset of the next message to read).
+    pub fn committed(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.inner.state.lock().unwrap()
+            .committed.get(&OffsetKey(topic.to_string(), partition))
+            .copied()
+    }
+
+    /// Starts kafka-json-processor with given streams and config in a separate thread, using this broker instead of Kafka
+    /// (as [`crate::run_processor`] would, but without the HTTP server and signal handling).
+    ///
+    /// To test recovery, stop (or crash) the processor and start a new one with the same broker and config.
+    /// Panics if the config is invalid for given streams.
+    pub fn start(&self, streams: HashMap<String, Stream>, config: Config) -> MemoryProcessor {
+        if let Err(e) = validate_streams(&config, &streams) {
+            panic!("Invalid config: {e}");
+        }
+
+        let broker = self.clone();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (crash_tx, crash_rx) = oneshot::channel::<()>();
+
+        let thread = std::thread::spawn(move || {
+            let runtime = Builder::new_multi_thread()
+                .enable_all()
+                .worker_threads(config.internal_config.worker_threads)
+                .build()
+                .unwrap();
+
+            let result = runtime.block_on(async {
+                tokio::select! {
+                    result = run_processing_tasks(&broker, &runtime, config, streams, broker.inner.attempts.clone(), shutdown_rx) => Some(match result {
+                        Ok(Stopped::Shutdown) => Ok(true),
+                        Ok(Stopped::ShutdownIncomplete) => Ok(false),
+                        Ok(Stopped::Retry) => Err("Clients of the in-memory broker could not be created.".to_string()),
+                        Err(e) => Err(e.to_string()),
+                    }),
+                    // the processor is crashed when it's dropped, too
+                    _ = crash_rx => None,
+                }
+            });
+
+            // spawned tasks are dropped (without delivering messages in flight)
+            runtime.shutdown_timeout(Duration::from_secs(10));
+            result
+        });
+
+        MemoryProcessor {
+            shutdown: shutdown_tx,
+            crash: crash_tx,
+            thread,
+        }
+    }
+
+    /// Appends the message to its topic. Returns `None` if the topic (or the partition) does not exist.
+    fn append(&self, message: &SinkMessage<'_>) -> Option<(i32, i64)> {
+        let mut state = self.inner.state.lock().unwrap();
+        let partitions = state.topics.get_mut(message.topic)?;
+
+        let partition = match message.partition {
+            Some(partition) if partition < 0 || partition as usize >= partitions.len() => return None,
+            Some(partition) => partition as usize,
+            None => message.key
+                .map(|key| (hash(key) % partitions.len() as u64) as usize)
+                .unwrap_or(0),
+        };
+        let messages = &mut partitions[partition];
+        let offset = messages.len() as i64;
+        messages.push(MemoryMessage {
+            topic: message.topic.to_string(),
+            partition: partition as i32,
+            offset,
+            key: message.key.map(|key| key.to_vec()),
+            payload: message.payload.map(|payload| payload.to_vec()),
+            headers: message.headers.to_vec(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64,
+        });
+        drop(state);
+
+        self.inner.published.notify_all();
+        self.notify_sources();
+        Some((partition as i32, offset))
+    }
+
+    /// Returns the next simulated error of the topic (if any).
+    fn next_failure(&self, topic: &str) -> Option<BrokerError> {
+        self.inner.state.lock().unwrap()
+            .failures.get_mut(topic)
+            .and_then(VecDeque::pop_front)
+    }
+
+    fn notify_sources(&self) {
+        self.inner.changes.send_modify(|version| *version += 1);
+    }
+}
+
+impl Broker for MemoryBroker {
+    type Source = MemorySource;
+    type Sink = MemorySink;
+
+    fn source(&self, config: &ClientConfig) -> Result<MemorySource, Box<dyn Error + Send + Sync>> {
+        Ok(MemorySource {
+            broker: self.clone(),
+            auto_commit: config.get("enable.auto.commit") != Some("false"),
+            assignment: Mutex::new(vec![]),
+            next_partition: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+        })
+    }
+
+    fn sink(&self, _config: &ClientConfig) -> Result<MemorySink, Box<dyn Error + Send + Sync>> {
+        Ok(MemorySink {
+            broker: self.clone(),
+        })
+    }
+
+    fn changelog_reader(&self, _config: &ClientConfig) -> ChangelogReader {
+        let broker = self.clone();
+        Arc::new(move |topic, partition| {
+            let state = broker.inner.state.lock().unwrap();
+            let messages = state.topics.get(topic)
+                .and_then(|partitions| partitions.get(partition as usize))
+                .ok_or_else(|| format!("Topic [{topic}] (partition {partition}) does not exist."))?;
+
+            messages.iter()
+                .filter_map(|message| Some((message.key.as_ref()?, message.payload.as_ref())))
+                .map(|(key, payload)| Ok(ChangelogRecord {
+                    partition,
+                    key: String::from_utf8_lossy(key).to_string(),
+                    value: payload.map(|payload| serde_json::from_slice(payload)).transpose()?,
+                }))
+                .collect()
+        })
+    }
+
+    fn transactional_producer(
+        &self,
+        _sink: MemorySink,
+        _rx: Receiver<PendingMessage>,
+        _source: &Arc<MemorySource>,
+        _queue_size: usize,
+        _tracker: Arc<OffsetTracker>,
+        _settings: TransactionSettings,
+    ) -> impl Future<Output = ProducerResult> + Send + 'static {
+        std::future::ready(Err(Box::new(FatalError {
+            reason: "Exactly-once delivery mode is not supported by the in-memory broker".to_string(),
+        }) as Box<dyn Error + Send + Sync>))
+    }
+}
+
+/// kafka-json-processor running with [`MemoryBroker`].
+///
+/// Dropping it crashes the processor (see [`MemoryProcessor::crash`]).
+pub struct MemoryProcessor {
+    shutdown: watch::Sender<bool>,
+    crash: oneshot::Sender<()>,
+    thread: JoinHandle<Option<Result<bool, String>>>,
+}
+
+impl MemoryProcessor {
+    /// Requests a graceful shutdown (as SIGTERM does) and waits until the processor stops.
+    ///
+    /// Returns `Ok(true)` if all messages were delivered, `Ok(false)` if not all of them were delivered
+    /// before `processor.shutdown.timeout.ms` and an error if the processor failed.
+    pub fn stop(self) -> Result<bool, String> {
+        let MemoryProcessor { shutdown, crash, thread } = self;
+        let _ = shutdown.send(true);
+
+        let result = thread.join()
+            .map_err(|_| "Processor panicked.".to_string())?;
+        drop(crash);
+        result.ok_or_else(|| "Processor crashed.".to_string())?
+    }
+
+    /// Stops the processor immediately - messages in flight are not delivered and offsets are not committed
+    /// (as if the processor panicked, so the journal is still saved).
+    pub fn crash(self) {
+        let _ = self.crash.send(());
+        if self.thread.join().is_err() {
+            warn!("Processor panicked.");
+        }
+    }
+}
+
+/// Reads messages from [`MemoryBroker`] (as a member of its consumer group).
+pub struct MemorySource {
+    broker: MemoryBroker,
+    /// Whether offsets are committed as soon as messages are received (`enable.auto.commit`).
+    auto_commit: bool,
+    /// Assigned partitions with offsets of the next messages to read.
+    assignment: Mutex<Vec<(OffsetKey, i64)>>,
+    /// Partitions are read in turns.
+    next_partition: AtomicUsize,
+    paused: AtomicBool,
+}
+
+impl MemorySource {
+    /// Returns the next message of assigned partitions (if there is one).
+    fn poll(&self) -> Option<SourceMessage> {
+        if self.paused.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let mut state = self.broker.inner.state.lock().unwrap();
+        let mut assignment = self.assignment.lock().unwrap();
+        let start = self.next_partition.fetch_add(1, Ordering::SeqCst);
+        let partitions = assignment.len();
+
+        for i in 0..partitions {
+            let (offset_key, position) = &mut assignment[(start + i) % partitions];
+            let message = state.topics.get(&offset_key.0)
+                .and_then(|partitions| partitions.get(offset_key.1 as usize))
+                .and_then(|messages| messages.get(*position as usize));
+
+            if let Some(message) = message.cloned() {
+                *position += 1;
+                if self.auto_commit {
+                    state.committed.insert(offset_key.clone(), *position);
+                }
+
+                return Some(SourceMessage {
+                    context: MessageContext {
+                        topic: message.topic,
+                        partition: message.partition,
+                        offset: message.offset,
+                        key: message.key,
+                        timestamp: Some(message.timestamp),
+                        headers: message.headers,
+                    },
+                    payload: message.payload,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+impl Source for MemorySource {
+    fn subscribe(&self, topics: &[&str]) -> SourceResult<()> {
+        self.subscribe_from(topics, HashMap::new())
+    }
+
+    /// All partitions of the topics are assigned. Partitions without given offsets are read from committed offsets.
+    fn subscribe_from(&self, topics: &[&str], offsets: HashMap<OffsetKey, i64>) -> SourceResult<()> {
+        let state = self.broker.inner.state.lock().unwrap();
+        let mut assignment = self.assignment.lock().unwrap();
+
+        for topic in topics {
+            let partitions = match state.topics.get(*topic) {
+                Some(partitions) => partitions.len(),
+                None => {
+                    warn!("Topic [{topic}] does not exist in the in-memory broker.");
+                    continue;
+                }
+            };
+
+            for partition in 0..partitions as i32 {
+                let offset_key = OffsetKey(topic.to_string(), partition);
+                let position = offsets.get(&offset_key)
+                    .map(|offset| offset + 1)
+                    .or_else(|| state.committed.get(&offset_key).copied())
+                    .unwrap_or(0);
+                assignment.push((offset_key, position));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_all(&self, topics: &[&str]) -> SourceResult<HashMap<OffsetKey, i64>> {
+        let state = self.broker.inner.state.lock().unwrap();
+        let mut assignment = self.assignment.lock().unwrap();
+        let mut end_offsets = HashMap::new();
+
+        for topic in topics {
+            let Some(partitions) = state.topics.get(*topic) else {
+                warn!("Topic [{topic}] does not exist in the in-memory broker.");
+                continue;
+            };
+
+            for (partition, messages) in partitions.iter().enumerate() {
+                let offset_key = OffsetKey(topic.to_string(), partition as i32);
+                if !messages.is_empty() {
+                    end_offsets.insert(offset_key.clone(), messages.len() as i64);
+                }
+                assignment.push((offset_key, 0));
+            }
+        }
+
+        Ok(end_offsets)
+    }
+
+    async fn recv(&self) -> Option<SourceResult<SourceMessage>> {
+        loop {
+            // subscribe before polling, so that a message published in the meantime is not missed
+            let mut changes = self.broker.inner.changes.subscribe();
+            if let Some(message) = self.poll() {
+                return Some(Ok(message));
+            }
+
+            // the sender lives as long as the broker (and the broker as long as this source)
+            let _ = changes.changed().await;
+        }
+    }
+
+    fn pause(&self) -> bool {
+        self.paused.store(true, Ordering::SeqCst);
+        true
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.broker.notify_sources();
+    }
+
+    fn commit(&self, offsets: &[(OffsetKey, i64)], _mode: CommitMode) -> SourceResult<()> {
+        let mut state = self.broker.inner.state.lock().unwrap();
+        for (offset_key, offset) in offsets {
+            state.committed.insert(offset_key.clone(), offset + 1);
+        }
+        Ok(())
+    }
+
+    fn commit_received(&self) -> SourceResult<()> {
+        let mut state = self.broker.inner.state.lock().unwrap();
+        for (offset_key, position) in self.assignment.lock().unwrap().iter() {
+            state.committed.insert(offset_key.clone(), *position);
+        }
+        Ok(())
+    }
+
+    fn assigned(&self) -> bool {
+        !self.assignment.lock().unwrap().is_empty()
+    }
+}
+
+/// Sends messages to [`MemoryBroker`]. Messages are delivered as soon as they are appended to their topic.
+pub struct MemorySink {
+    broker: MemoryBroker,
+}
+
+impl Sink for MemorySink {
+    async fn send(&self, message: SinkMessage<'_>, delivery: Delivery) -> Result<(), SendError> {
+        loop {
+            match self.broker.next_failure(message.topic) {
+                Some(BrokerError::QueueFull) => sleep(QUEUE_FULL_BACKOFF).await,
+                Some(BrokerError::UnknownTopic) => return Err(SendError::InvalidTopic),
+                Some(BrokerError::DeliveryFailed) => {
+                    delivery.failed();
+                    return Ok(());
+                }
+                None => {
+                    return match self.broker.append(&message) {
+                        Some(_) => {
+                            delivery.delivered();
+                            Ok(())
+                        }
+                        None => Err(SendError::InvalidTopic),
+                    };
+                }
+            }
+        }
+    }
+
+    fn flush(&self, _timeout: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+/// FNV-1a hash of the key (to choose its partition).
+fn hash(key: &[u8]) -> u64 {
+    key.iter()
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use serde_json::{json, Value};
+    use crate::config::Config;
+    use crate::error::ProcessingError;
+    use crate::memory::{BrokerError, MemoryBroker, MemoryMessage, MemoryProcessor};
+    use crate::ordering::OrderingPolicy;
+    use crate::processor::{ObjectKey, ObjectTree, OutputMessage};
+    use crate::Stream;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn streams(dead_letter_topic: Option<&str>) -> HashMap<String, Stream> {
+        HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors: &[&copy_id],
+            dead_letter_topic: dead_letter_topic.map(|topic| topic.to_string()),
+            ..Default::default()
+        })])
+    }
+
+    fn copy_id(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        let id = input.get_val(&[ObjectKey::Key("id".to_string())])?.clone();
+        message.insert_val(&[ObjectKey::Key("id".to_string())], id)?;
+        message.insert_val(&[ObjectKey::Key("processed".to_string())], Value::Bool(true))?;
+        Ok(())
+    }
+
+    fn count(_input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        let count = message.state().get("count").and_then(|count| count.as_i64()).unwrap_or(0) + 1;
+        message.state().put("count", Value::from(count));
+        message.insert_val(&[ObjectKey::Key("count".to_string())], Value::from(count))?;
+        Ok(())
+    }
+
+    fn enrich(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+        let customer = input.get_val(&[ObjectKey::Key("customer".to_string())])?.as_str().unwrap_or_default();
+        let name = message.lookup("customers", customer)
+            .map(|customer| customer["name"].clone())
+            .unwrap_or(Value::Null);
+        message.insert_val(&[ObjectKey::Key("name".to_string())], name)?;
+        Ok(())
+    }
+
+    fn broker() -> MemoryBroker {
+        let broker = MemoryBroker::new();
+        broker.create_topic("in", 1)
+            .create_topic("out", 1)
+            .create_topic("dlq", 1);
+        broker
+    }
+
+    fn publish(broker: &MemoryBroker, ids: std::ops::Range<i32>) {
+        for id in ids {
+            broker.publish("in", None, Some(format!(r#"{{"id": {id}}}"#).as_bytes()));
+        }
+    }
+
+    fn ids(messages: &[MemoryMessage]) -> Vec<i64> {
+        let mut ids: Vec<i64> = messages.iter()
+            .map(|message| {
+                let payload: Value = serde_json::from_slice(message.payload.as_deref().unwrap()).unwrap();
+                payload["id"].as_i64().unwrap()
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn config(properties: &str) -> Config {
+        properties.parse().unwrap()
+    }
+
+    /// Waits until the processor stops by itself (eg. with an error).
+    fn wait_until_stopped(processor: MemoryProcessor) -> Result<bool, String> {
+        let deadline = Instant::now() + TIMEOUT;
+        while !processor.thread.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        processor.stop()
+    }
+
+    fn journal_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kjp-memory-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn should_process_published_messages() {
+        let broker = broker();
+        publish(&broker, 0..10);
+
+        let processor = broker.start(streams(None), config("processor.journal.enabled=false"));
+        let output = broker.wait_for_messages("out", 10, TIMEOUT);
+
+        assert_eq!((0..10).collect::<Vec<i64>>(), ids(&output));
+        assert!(output.iter().all(|message| message.payload_str().unwrap().contains(r#""processed":true"#)));
+        // synthetic keys (topic:partition@offset(timestamp)) by default
+        assert!(output.iter().all(|message| message.key.as_deref().unwrap().starts_with(b"in:0@")));
+        assert_eq!(Ok(true), processor.stop());
+        assert_eq!(Some(10), broker.committed("in", 0));
+    }
+
+    #[test]
+    fn should_retry_when_queue_is_full() {
+        let broker = broker();
+        broker.fail_next("out", BrokerError::QueueFull, 5);
+        publish(&broker, 0..3);
+
+        let processor = broker.start(streams(None), config("processor.journal.enabled=false"));
+
+        assert_eq!(vec![0, 1, 2], ids(&broker.wait_for_messages("out", 3, TIMEOUT)));
+        assert_eq!(Ok(true), processor.stop());
+    }
+
+    #[test]
+    fn should_send_message_to_dead_letter_topic_if_topic_is_unknown() {
+        let broker = broker();
+        broker.fail_next("out", BrokerError::UnknownTopic, 1);
+        publish(&broker, 0..3);
+
+        let processor = broker.start(streams(Some("dlq")), config("processor.journal.enabled=false"));
+
+        broker.wait_for_messages("out", 2, TIMEOUT);
+        let dead_letters = broker.wait_for_messages("dlq", 1, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!(Some(b"UnknownTopic".as_slice()), dead_letters[0].header("kjp-error-kind"));
+        let mut all = broker.messages("out");
+        all.extend(dead_letters);
+        assert_eq!(vec![0, 1, 2], ids(&all));
+    }
+
+    #[test]
+    fn should_consume_failed_messages_again_after_restart_in_at_least_once_mode() {
+        let broker = broker();
+        broker.fail_next("out", BrokerError::DeliveryFailed, 1);
+        publish(&broker, 0..5);
+        let config = config("processor.journal.enabled=false\nprocessor.delivery.mode=at-least-once\nprocessor.ordering=partition");
+
+        // the first message was not delivered, so no offset can be committed - the processor stops with an error
+        let processor = broker.start(streams(None), config.clone());
+        assert!(wait_until_stopped(processor).is_err_and(|e| e.contains("was not delivered")));
+        assert_eq!(None, broker.committed("in", 0));
+
+        let processor = broker.start(streams(None), config);
+        let output = broker.wait_for_messages("out", 5, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!(vec![0, 1, 2, 3, 4], ids(&output));
+        assert_eq!(Some(5), broker.committed("in", 0));
+    }
+
+    #[test]
+    fn should_send_message_to_dead_letter_topic_after_max_delivery_attempts() {
+        let broker = broker();
+        broker.fail_next("out", BrokerError::DeliveryFailed, 2);
+        publish(&broker, 0..3);
+        let config = config("processor.journal.enabled=false\nprocessor.delivery.mode=at-least-once\n\
+            processor.ordering=partition\nprocessor.delivery.max.attempts=2");
+
+        // the runtime is restarted after each failed attempt (as run_processor does)
+        for _ in 0..2 {
+            let processor = broker.start(streams(Some("dlq")), config.clone());
+            assert!(wait_until_stopped(processor).is_err_and(|e| e.contains("was not delivered")));
+        }
+
+        let processor = broker.start(streams(Some("dlq")), config);
+        let dead_letters = broker.wait_for_messages("dlq", 1, TIMEOUT);
+        let output = broker.wait_for_messages("out", 2, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!(Some(b"DeliveryFailed".as_slice()), dead_letters[0].header("kjp-error-kind"));
+        assert_eq!(vec![0], ids(&dead_letters));
+        assert_eq!(vec![1, 2], ids(&output));
+        assert_eq!(Some(3), broker.committed("in", 0));
+    }
+
+    #[test]
+    fn should_stop_after_max_delivery_attempts_without_dead_letter_topic() {
+        let broker = broker();
+        broker.fail_next("out", BrokerError::DeliveryFailed, 1);
+        publish(&broker, 0..3);
+        let config = config("processor.journal.enabled=false\nprocessor.delivery.mode=at-least-once\n\
+            processor.ordering=partition\nprocessor.delivery.max.attempts=1");
+
+        let processor = broker.start(streams(None), config.clone());
+        assert!(wait_until_stopped(processor).is_err_and(|e| e.contains("was not delivered")));
+
+        // the message is not sent again, the processor stops (run_processor exits on such an error)
+        let processor = broker.start(streams(None), config);
+        assert!(wait_until_stopped(processor).is_err_and(|e| e.contains("was not delivered to [out] in 1 attempt(s)")));
+        assert!(broker.messages("out").is_empty());
+        assert_eq!(None, broker.committed("in", 0));
+    }
+
+    #[test]
+    fn should_resume_from_journal_after_crash() {
+        let journal = journal_dir("resume");
+        let broker = broker();
+        publish(&broker, 0..5);
+        // offsets are not committed to the broker before the crash, so only the journal knows them
+        let config = config(&format!(
+            "processor.journal.path={}\nprocessor.delivery.mode=at-least-once\nprocessor.commit.interval.ms=3600000",
+            journal.display(),
+        ));
+
+        let processor = broker.start(streams(None), config.clone());
+        broker.wait_for_messages("out", 5, TIMEOUT);
+        processor.crash();
+        assert_eq!(None, broker.committed("in", 0));
+
+        publish(&broker, 5..8);
+        let processor = broker.start(streams(None), config);
+        let output = broker.wait_for_messages("out", 8, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!((0..8).collect::<Vec<i64>>(), ids(&output));
+        let _ = std::fs::remove_dir_all(journal);
+    }
+
+    #[test]
+    fn should_restore_state_from_changelog_topic() {
+        let broker = broker();
+        broker.create_topic("counts", 1);
+        let streams = || HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors: &[&count],
+            state_changelog_topic: Some("counts".to_string()),
+            ..Default::default()
+        })]);
+        publish(&broker, 0..3);
+
+        // journal (and with it the state checkpoints) is disabled, so the state can only be restored from the changelog
+        let processor = broker.start(streams(), config("processor.journal.enabled=false"));
+        broker.wait_for_messages("out", 3, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+        assert_eq!(3, broker.messages("counts").len());
+
+        publish(&broker, 3..5);
+        let processor = broker.start(streams(), config("processor.journal.enabled=false"));
+        let output = broker.wait_for_messages("out", 5, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        let mut counts: Vec<i64> = output.iter()
+            .map(|message| serde_json::from_slice::<Value>(message.payload.as_deref().unwrap()).unwrap()["count"].as_i64().unwrap())
+            .collect();
+        counts.sort();
+        assert_eq!(vec![1, 2, 3, 4, 5], counts);
+    }
+
+    #[test]
+    fn should_drop_duplicates_across_restarts() {
+        let journal = journal_dir("dedup");
+        let broker = broker();
+        let streams = || HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors: &[&copy_id],
+            dedup: Some("jsonpath:$.id".parse().unwrap()),
+            ..Default::default()
+        })]);
+        let config = config(&format!("processor.journal.path={}", journal.display()));
+        publish(&broker, 0..3);
+        publish(&broker, 1..3);
+
+        let processor = broker.start(streams(), config.clone());
+        broker.wait_for_messages("out", 3, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        // ids seen before the restart are remembered
+        publish(&broker, 2..5);
+        let processor = broker.start(streams(), config);
+        broker.wait_for_messages("out", 5, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!((0..5).collect::<Vec<i64>>(), ids(&broker.messages("out")));
+        assert_eq!(Some(8), broker.committed("in", 0));
+        let _ = std::fs::remove_dir_all(journal);
+    }
+
+    #[test]
+    fn should_drop_duplicates_of_dead_lettered_messages() {
+        let broker = broker();
+        let streams = HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors: &[&copy_id],
+            dead_letter_topic: Some("dlq".to_string()),
+            dedup: Some("key".parse().unwrap()),
+            ..Default::default()
+        })]);
+        broker.publish("in", Some(b"a"), Some(b"not json"));
+        broker.publish("in", Some(b"a"), Some(b"not json"));
+        broker.publish("in", Some(b"a"), Some(br#"{"id": 0}"#));
+        broker.publish("in", Some(b"b"), Some(br#"{"id": 1}"#));
+
+        let processor = broker.start(streams, config("processor.journal.enabled=false"));
+        broker.wait_for_messages("dlq", 1, TIMEOUT);
+        broker.wait_for_messages("out", 1, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        // the id of the dead-lettered message is remembered, so its duplicates are dropped
+        assert_eq!(1, broker.messages("dlq").len());
+        assert_eq!(vec![1], ids(&broker.messages("out")));
+        assert_eq!(Some(4), broker.committed("in", 0));
+    }
+
+    #[test]
+    fn should_produce_summaries_of_closed_windows_across_restarts() {
+        let journal = journal_dir("aggregation");
+        let broker = broker();
+        let streams = || HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors: &[&copy_id],
+            aggregation: Some("group.by=$.customer;window.ms=100;timestamp=$.ts;orders=count;total=sum($.amount)".parse().unwrap()),
+            ..Default::default()
+        })]);
+        let config = config(&format!("processor.journal.path={}", journal.display()));
+        let publish = |events: &[(&str, i64, i64)]| for (id, (customer, ts, amount)) in events.iter().enumerate() {
+            broker.publish("in", None, Some(format!(r#"{{"id": {id}, "customer": "{customer}", "ts": {ts}, "amount": {amount}}}"#).as_bytes()));
+        };
+        let summaries = |messages: Vec<MemoryMessage>| messages.iter()
+            .map(|message| (
+                String::from_utf8(message.key.clone().unwrap()).unwrap(),
+                serde_json::from_slice::<Value>(message.payload.as_deref().unwrap()).unwrap(),
+            ))
+            .collect::<Vec<(String, Value)>>();
+
+        publish(&[("a", 10, 1), ("b", 20, 2), ("a", 50, 3), ("a", 120, 4)]);
+        let processor = broker.start(streams(), config.clone());
+        assert_eq!(vec![
+            ("a".to_string(), json!({"key": "a", "window_start": 0, "window_end": 100, "orders": 2, "total": 4})),
+            ("b".to_string(), json!({"key": "b", "window_start": 0, "window_end": 100, "orders": 1, "total": 2})),
+        ], summaries(broker.wait_for_messages("out", 2, TIMEOUT)));
+        assert_eq!(Ok(true), processor.stop());
+
+        // the open window is restored, the late message is dropped
+        publish(&[("a", 150, 5), ("b", 90, 6), ("c", 250, 7)]);
+        let processor = broker.start(streams(), config);
+        broker.wait_for_messages("out", 3, TIMEOUT);
+        assert_eq!(Ok(true), processor.stop());
+
+        assert_eq!(vec![
+            ("a".to_string(), json!({"key": "a", "window_start": 100, "window_end": 200, "orders": 2, "total": 9})),
+        ], summaries(broker.messages("out")).split_off(2));
+        assert_eq!(Some(7), broker.committed("in", 0));
+        let _ = std::fs::remove_dir_all(journal);
+    }
+
+    #[test]
+    fn should_enrich_messages_with_records_of_lookup_tables() {
+        let broker = broker();
+        broker.create_topic("customers", 2);
+        let streams = HashMap::from([("in_out".to_string(), Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            processors: &[&enrich],
+            ordering: Some(OrderingPolicy::Partition),
+            lookup_tables: vec!["customers".to_string()],
+            ..Default::default()
+        })]);
+        let publish = |customers: &[&str]| for customer in customers {
+            broker.publish("in", None, Some(format!(r#"{{"customer": "{customer}"}}"#).as_bytes()));
+        };
+        let names = |messages: Vec<MemoryMessage>| messages.iter()
+            .map(|message| serde_json::from_slice::<Value>(message.payload.as_deref().unwrap()).unwrap()["name"].clone())
+            .collect::<Vec<Value>>();
+        broker.publish("customers", Some(b"c1"), Some(br#"{"name": "Alice"}"#));
+        broker.publish("customers", Some(b"c2"), Some(br#"{"name": "Bob"}"#));
+        broker.publish("customers", Some(b"c3"), Some(br#"{"name": "Carol"}"#));
+        broker.publish("customers", Some(b"c3"), None);
+        publish(&["c1", "c2", "c3"]);
+
+        // tables are loaded before messages are consumed
+        let processor = broker.start(streams, config("processor.journal.enabled=false"));
+        assert_eq!(vec![json!("Alice"), json!("Bob"), Value::Null], names(broker.wait_for_messages("out", 3, TIMEOUT)));
+
+        // and kept up to date
+        broker.publish("customers", Some(b"c1"), Some(br#"{"name": "Alice Smith"}"#));
+        std::thread::sleep(Duration::from_millis(200));
+        publish(&["c1"]);
+        assert_eq!(vec![json!("Alice Smith")], names(broker.wait_for_messages("out", 4, TIMEOUT)).split_off(3));
+        assert_eq!(Ok(true), processor.stop());
+        assert_eq!(None, broker.committed("customers", 0));
+    }
+}
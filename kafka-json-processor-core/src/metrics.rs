@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use lazy_static::lazy_static;
+use crate::Stream;
+use crate::producer::Produced;
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Global metrics of kafka-json-processor.
+///
+/// Metrics are global (and not bound to a runtime), so counters are not reset when the runtime is restarted.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+const STREAM_LABELS: &[&str] = &["source_topic", "target_topic"];
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+pub struct Metrics {
+    /// Messages passed to a stream.
+    consumed: CounterVec,
+    /// Output messages of a stream passed to the producer.
+    produced: CounterVec,
+    /// Messages that could not be processed (sent to dead-letter topic or lost).
+    failed: CounterVec,
+    /// Messages that were filtered out, skipped by a payload policy or dropped as duplicates (or late).
+    dropped: CounterVec,
+    /// Messages dropped as duplicates (also counted as dropped).
+    duplicates: CounterVec,
+    /// Messages dropped by an aggregating stream, because their windows were closed (also counted as dropped).
+    late: CounterVec,
+    /// Messages processed again after a transient error.
+    retried: CounterVec,
+    processor_errors: CounterVec,
+    /// Processors that exceeded the time budget of their stream (including those that did not finish at all).
+    slow_processors: CounterVec,
+    processing_duration: HistogramVec,
+    producer_results: CounterVec,
+    delivery_reports: CounterVec,
+    producer_in_flight: Gauge,
+    producer_queue_size: Gauge,
+    channel_messages: Gauge,
+    channel_capacity: Gauge,
+    in_flight: Gauge,
+    in_flight_limit: Gauge,
+    consumer_paused: Gauge,
+    consumer_pauses: CounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            consumed: CounterVec::new("kjp_stream_consumed_total", "Messages consumed by a stream.", STREAM_LABELS),
+            produced: CounterVec::new("kjp_stream_produced_total", "Output messages of a stream passed to the producer.", STREAM_LABELS),
+            failed: CounterVec::new("kjp_stream_failed_total", "Messages that could not be processed by a stream.", STREAM_LABELS),
+            dropped: CounterVec::new("kjp_stream_dropped_total", "Messages filtered out or skipped by a stream.", STREAM_LABELS),
+            duplicates: CounterVec::new("kjp_stream_duplicates_total", "Messages dropped by a stream as duplicates.", STREAM_LABELS),
+            late: CounterVec::new("kjp_stream_late_total", "Messages dropped by an aggregating stream, because their windows were closed.", STREAM_LABELS),
+            retried: CounterVec::new("kjp_stream_retries_total", "Retries of processing a message after a transient error.", STREAM_LABELS),
+            processor_errors: CounterVec::new("kjp_processor_errors_total", "Errors returned by processors.", &["source_topic", "target_topic", "processor", "kind"]),
+            slow_processors: CounterVec::new("kjp_processor_slow_total", "Processors that exceeded the time budget of their stream.", &["source_topic", "target_topic", "processor"]),
+            processing_duration: HistogramVec::new("kjp_processing_duration_seconds", "Time of processing a single message by a stream.", STREAM_LABELS, LATENCY_BUCKETS),
+            producer_results: CounterVec::new("kjp_producer_messages_total", "Messages passed to the producer by result (enqueued, skipped, failed).", &["result"]),
+            delivery_reports: CounterVec::new("kjp_producer_delivery_reports_total", "Delivery reports received from the broker by result (delivered, failed).", &["result"]),
+            producer_in_flight: Gauge::new("kjp_producer_in_flight", "Messages in the producer queue, not yet delivered."),
+            producer_queue_size: Gauge::new("kjp_producer_queue_size", "Maximum size of the producer queue (processor.queue.size)."),
+            channel_messages: Gauge::new("kjp_channel_messages", "Messages waiting in the channel between processors and the producer."),
+            channel_capacity: Gauge::new("kjp_channel_capacity", "Capacity of the channel between processors and the producer (processor.channel.capacity)."),
+            in_flight: Gauge::new("kjp_in_flight_messages", "Output messages consumed, but not yet delivered (or skipped)."),
+            in_flight_limit: Gauge::new("kjp_in_flight_limit", "Maximum number of output messages in flight (processor.max.in.flight)."),
+            consumer_paused: Gauge::new("kjp_consumer_paused", "Whether the consumer is paused, because the in-flight limit was reached (1 if paused)."),
+            consumer_pauses: CounterVec::new("kjp_consumer_pauses_total", "How many times the consumer was paused, because the in-flight limit was reached.", &[]),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn consumed(&self, stream: &Stream) {
+        self.consumed.inc(&stream_labels(stream));
+    }
+
+    pub fn produced(&self, stream: &Stream) {
+        self.produced.inc(&stream_labels(stream));
+    }
+
+    pub fn failed(&self, stream: &Stream) {
+        self.failed.inc(&stream_labels(stream));
+    }
+
+    pub fn dropped(&self, stream: &Stream) {
+        self.dropped.inc(&stream_labels(stream));
+    }
+
+    pub fn duplicate(&self, stream: &Stream) {
+        self.dropped(stream);
+        self.duplicates.inc(&stream_labels(stream));
+    }
+
+    pub fn late(&self, stream: &Stream) {
+        self.dropped(stream);
+        self.late.inc(&stream_labels(stream));
+    }
+
+    pub fn retried(&self, stream: &Stream) {
+        self.retried.inc(&stream_labels(stream));
+    }
+
+    pub fn processor_error(&self, stream: &Stream, processor: usize, kind: &str) {
+        self.processor_errors.inc(&[&stream.source_topic, &stream.target_topic, &processor.to_string(), kind]);
+    }
+
+    pub fn slow_processor(&self, stream: &Stream, processor: usize) {
+        self.slow_processors.inc(&[&stream.source_topic, &stream.target_topic, &processor.to_string()]);
+    }
+
+    pub fn processing_time(&self, stream: &Stream, duration: Duration) {
+        self.processing_duration.observe(&stream_labels(stream), duration.as_secs_f64());
+    }
+
+    pub fn producer_result(&self, produced: &Produced) {
+        let result = match produced {
+            Produced::Enqueued(_) => "enqueued",
+            Produced::Skipped(_) => "skipped",
+            Produced::Failed(_) => "failed",
+        };
+        self.producer_results.inc(&[result]);
+    }
+
+    pub fn delivery_report(&self, delivered: bool) {
+        self.delivery_reports.inc(&[if delivered { "delivered" } else { "failed" }]);
+    }
+
+    pub fn producer_queue(&self, in_flight: usize, queue_size: usize) {
+        self.producer_in_flight.set(in_flight as i64);
+        self.producer_queue_size.set(queue_size as i64);
+    }
+
+    pub fn channel(&self, messages: usize, capacity: Option<usize>) {
+        self.channel_messages.set(messages as i64);
+        self.channel_capacity.set(capacity.unwrap_or_default() as i64);
+    }
+
+    pub fn in_flight(&self, messages: usize, limit: usize) {
+        self.in_flight.set(messages as i64);
+        self.in_flight_limit.set(limit as i64);
+    }
+
+    pub fn consumer_paused(&self, paused: bool) {
+        self.consumer_paused.set(paused as i64);
+        if paused {
+            self.consumer_pauses.inc(&[]);
+        }
+    }
+
+    /// Renders all metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.consumed.render(&mut out);
+        self.produced.render(&mut out);
+        self.failed.render(&mut out);
+        self.dropped.render(&mut out);
+        self.duplicates.render(&mut out);
+        self.late.render(&mut out);
+        self.retried.render(&mut out);
+        self.processor_errors.render(&mut out);
+        self.slow_processors.render(&mut out);
+        self.processing_duration.render(&mut out);
+        self.producer_results.render(&mut out);
+        self.delivery_reports.render(&mut out);
+        self.producer_in_flight.render(&mut out);
+        self.producer_queue_size.render(&mut out);
+        self.channel_messages.render(&mut out);
+        self.channel_capacity.render(&mut out);
+        self.in_flight.render(&mut out);
+        self.in_flight_limit.render(&mut out);
+        self.consumer_paused.render(&mut out);
+        self.consumer_pauses.render(&mut out);
+        out
+    }
+}
+
+fn stream_labels(stream: &Stream) -> [&str; 2] {
+    [&stream.source_topic, &stream.target_topic]
+}
+
+struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> CounterVec {
+        CounterVec { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn inc(&self, label_values: &[&str]) {
+        let mut values = self.values.lock().unwrap();
+        *values.entry(label_values.iter().map(|value| value.to_string()).collect())
+            .or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {value}", self.name, format_labels(self.labels, label_values, None));
+        }
+    }
+}
+
+struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations in each bucket (non-cumulative).
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str], buckets: &'static [f64]) -> HistogramVec {
+        HistogramVec { name, help, labels, buckets, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn observe(&self, label_values: &[&str], value: f64) {
+        let mut values = self.values.lock().unwrap();
+        let histogram = values.entry(label_values.iter().map(|value| value.to_string()).collect())
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; self.buckets.len()],
+                ..Default::default()
+            });
+
+        if let Some(i) = self.buckets.iter().position(|bound| value <= *bound) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (label_values, histogram) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in self.buckets.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "{}_bucket{} {cumulative}", self.name, format_labels(self.labels, label_values, Some(&bound.to_string())));
+            }
+            let _ = writeln!(out, "{}_bucket{} {}", self.name, format_labels(self.labels, label_values, Some("+Inf")), histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, format_labels(self.labels, label_values, None), histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, format_labels(self.labels, label_values, None), histogram.count);
+        }
+    }
+}
+
+struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    fn new(name: &'static str, help: &'static str) -> Gauge {
+        Gauge { name, help, value: AtomicI64::new(0) }
+    }
+
+    fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} gauge", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.value.load(Ordering::Relaxed));
+    }
+}
+
+/// Formats labels as `{name="value",...}` (with optional histogram bucket bound as `le`).
+fn format_labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut labels: Vec<String> = names.iter()
+        .zip(values)
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(value)))
+        .collect();
+
+    if let Some(le) = le {
+        labels.push(format!("le=\"{le}\""));
+    }
+
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::metrics::Metrics;
+    use crate::Stream;
+
+    #[test]
+    fn should_render_metrics_in_prometheus_format() {
+        let metrics = Metrics::default();
+        let stream = Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out\"1".to_string(),
+            ..Default::default()
+        };
+
+        metrics.consumed(&stream);
+        metrics.consumed(&stream);
+        metrics.processor_error(&stream, 1, "FieldNotFound");
+        metrics.processing_time(&stream, Duration::from_millis(3));
+        metrics.producer_queue(5, 100);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE kjp_stream_consumed_total counter\n"));
+        assert!(rendered.contains("kjp_stream_consumed_total{source_topic=\"in\",target_topic=\"out\\\"1\"} 2\n"));
+        assert!(rendered.contains("kjp_processor_errors_total{source_topic=\"in\",target_topic=\"out\\\"1\",processor=\"1\",kind=\"FieldNotFound\"} 1\n"));
+        assert!(rendered.contains("kjp_processing_duration_seconds_bucket{source_topic=\"in\",target_topic=\"out\\\"1\",le=\"0.001\"} 0\n"));
+        assert!(rendered.contains("kjp_processing_duration_seconds_bucket{source_topic=\"in\",target_topic=\"out\\\"1\",le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("kjp_processing_duration_seconds_count{source_topic=\"in\",target_topic=\"out\\\"1\"} 1\n"));
+        assert!(rendered.contains("kjp_producer_in_flight 5\n"));
+        assert!(rendered.contains("kjp_producer_queue_size 100\n"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use crate::processor::MessageContext;
+
+/// Decides whether output messages of a stream are produced in the order of input messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OrderingPolicy {
+    /// Every message is processed in an independent task - output order is not preserved.
+    Unordered,
+    /// Messages of a partition are processed one by one and produced in input order.
+    /// Partitions are processed in parallel.
+    Partition,
+    /// Messages with the same key are processed one by one and produced in input order.
+    /// Messages with different keys (even in the same partition) are processed in parallel.
+    /// Messages without a key are ordered by partition.
+    Key,
+}
+
+impl OrderingPolicy {
+    /// Returns the lane (out of `lanes`) that processes given message, so that messages
+    /// that must stay in order always go to the same lane.
+    pub(crate) fn lane(&self, context: &MessageContext, lanes: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        context.topic.hash(&mut hasher);
+        match (self, &context.key) {
+            (OrderingPolicy::Key, Some(key)) => key.hash(&mut hasher),
+            _ => context.partition.hash(&mut hasher),
+        }
+        (hasher.finish() % lanes as u64) as usize
+    }
+}
+
+impl FromStr for OrderingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unordered" => Ok(OrderingPolicy::Unordered),
+            "partition" => Ok(OrderingPolicy::Partition),
+            "key" => Ok(OrderingPolicy::Key),
+            _ => Err(format!("Unknown ordering policy: {s}. Available policies: unordered, partition, key.")),
+        }
+    }
+}
+
+impl Display for OrderingPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderingPolicy::Unordered => write!(f, "unordered"),
+            OrderingPolicy::Partition => write!(f, "partition"),
+            OrderingPolicy::Key => write!(f, "key"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ordering::OrderingPolicy;
+    use crate::processor::MessageContext;
+
+    #[test]
+    fn should_dispatch_messages_to_the_same_lane() {
+        let message = |partition: i32, key: Option<&str>| MessageContext {
+            topic: "in".to_string(),
+            partition,
+            key: key.map(|key| key.as_bytes().to_vec()),
+            ..Default::default()
+        };
+
+        let partition = OrderingPolicy::Partition;
+        assert_eq!(partition.lane(&message(3, Some("a")), 8), partition.lane(&message(3, Some("b")), 8));
+        assert_eq!(partition.lane(&message(3, None), 8), partition.lane(&message(3, Some("c")), 8));
+
+        let key = OrderingPolicy::Key;
+        assert_eq!(key.lane(&message(1, Some("a")), 8), key.lane(&message(5, Some("a")), 8));
+        assert_eq!(key.lane(&message(1, None), 8), partition.lane(&message(1, None), 8));
+        assert!((0..100).all(|i| key.lane(&message(0, Some(&i.to_string())), 8) < 8));
+
+        assert_eq!(Ok(OrderingPolicy::Key), "key".parse());
+        assert!("random".parse::<OrderingPolicy>().is_err());
+    }
+}
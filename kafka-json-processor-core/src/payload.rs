@@ -0,0 +1,38 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Decides what happens with a message that cannot be processed, because it has no payload (a tombstone)
+/// or its payload is not a valid JSON.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadPolicy {
+    /// Message is produced to the target topic as it is (with the same key and payload).
+    /// A tombstone stays a tombstone, so compacted topics can be cleaned up.
+    Forward,
+    /// Message is skipped (nothing is produced).
+    Skip,
+    /// Message is sent to the dead-letter topic (or skipped if there is no dead-letter topic).
+    DeadLetter,
+}
+
+impl FromStr for PayloadPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(PayloadPolicy::Forward),
+            "skip" => Ok(PayloadPolicy::Skip),
+            "dead-letter" => Ok(PayloadPolicy::DeadLetter),
+            _ => Err(format!("Unknown payload policy: {s}. Available policies: forward, skip, dead-letter.")),
+        }
+    }
+}
+
+impl Display for PayloadPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadPolicy::Forward => write!(f, "forward"),
+            PayloadPolicy::Skip => write!(f, "skip"),
+            PayloadPolicy::DeadLetter => write!(f, "dead-letter"),
+        }
+    }
+}
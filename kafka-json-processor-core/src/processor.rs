@@ -1,14 +1,61 @@
+use std::any::Any;
 use std::error::Error;
 use std::mem::discriminant;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde_json::{Map, Value};
-use log::{trace, error, debug};
-use crate::error::{ErrorKind, ProcessingError};
+use log::{trace, error, debug, warn};
+use crate::error::{ErrorKind, MessageError, ProcessingError};
+use crate::error_policy::ErrorPolicy;
+use crate::key::KeyPolicy;
+use crate::metrics::metrics;
+use crate::state::State;
+use crate::Stream;
 
 pub struct OutputMessage {
     pub key: Option<String>,
+    /// Output topic of this message. If not set, the target topic of the stream is used.
+    pub topic: Option<String>,
+    /// Headers of the output message. If the stream propagates headers, input headers are added to these
+    /// (unless a header with the same name is set here).
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// If set, the output message is produced without payload (as a tombstone) - the value is ignored.
+    pub tombstone: bool,
+    context: MessageContext,
+    state: State,
     value: Value,
 }
 
+/// Metadata of the input message (available for processors with [`OutputMessage::context`]).
+#[derive(Clone, Debug, Default)]
+pub struct MessageContext {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    /// Timestamp of the message in milliseconds (if available).
+    pub timestamp: Option<i64>,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl MessageContext {
+    /// Returns the value of the first header with given name.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the value of the first header with given name (if it is a valid UTF-8 string).
+    pub fn header_str(&self, name: &str) -> Option<&str> {
+        self.header(name)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+}
+
 impl Default for OutputMessage {
     fn default() -> Self {
         Self::new()
@@ -17,11 +64,46 @@ impl Default for OutputMessage {
 
 impl OutputMessage {
     pub fn new() -> OutputMessage {
+        Self::with_context(MessageContext::default())
+    }
+
+    pub fn with_context(context: MessageContext) -> OutputMessage {
         OutputMessage {
             key: None,
+            topic: None,
+            headers: vec![],
+            tombstone: false,
+            context,
+            state: State::default(),
             value: Value::Null,
         }
     }
+
+    /// Metadata of the input message (topic, partition, offset, key, timestamp, headers).
+    pub fn context(&self) -> &MessageContext {
+        &self.context
+    }
+
+    /// State of the stream for the partition of the input message (see [`crate::state::StateStore`]).
+    ///
+    /// Messages processed outside of a running processor (eg. in simulations) have empty state
+    /// and their changes are not saved.
+    pub fn state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// Returns the record with given key from a lookup table of the stream (see [`crate::lookup::LookupTable`]).
+    ///
+    /// Messages processed outside of a running processor (eg. in simulations) have no lookup tables.
+    pub fn lookup(&self, table: &str, key: &str) -> Option<Value> {
+        self.state.lookup(table, key)
+    }
+
+    /// Sets the output header (replaces headers with the same name).
+    pub fn set_header(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        self.headers.retain(|(header, _)| header != name);
+        self.headers.push((name.to_string(), value.into()));
+    }
 }
 
 pub trait ObjectTree {
@@ -35,6 +117,28 @@ pub enum ObjectKey {
     Key(String),
 }
 
+/// Interprets JSONPath (eg. `$.phoneNumbers[1].type`) as object keys.
+///
+/// This is the runtime equivalent of `##JSONPATH(...)##` used by generators.
+pub fn json_path_to_object_keys(jsonpath: &str) -> Vec<ObjectKey> {
+    lazy_static! {
+        static ref SEPARATOR_REGEX: Regex = Regex::new(r"[.\[\]]").unwrap();
+    }
+
+    if !jsonpath.starts_with('$') {
+        return vec![ObjectKey::Key(jsonpath.to_string())];
+    }
+
+    SEPARATOR_REGEX.split(jsonpath)
+        .skip(1)
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.parse::<usize>() {
+            Ok(i) => ObjectKey::Index(i),
+            Err(_) => ObjectKey::Key(s.to_string()),
+        })
+        .collect()
+}
+
 impl ObjectTree for OutputMessage {
     fn get_val(&self, key: &[ObjectKey]) -> Result<&Value, ProcessingError> {
         self.value.get_val(key)
@@ -172,13 +276,11 @@ fn verify_type_compatibility(node: &Value, child: &Value) -> Result<(), Processi
     let node_discriminant = discriminant(node);
 
     match node {
-        Value::Object(_) | Value::Array(_) => {
-            if node_discriminant != child_discriminant {
-                return Err(ErrorKind::InvalidObjectTree {
-                    invalid_key: vec![],
-                    reason: format!("Child node has incompatible type, cannot merge {child_discriminant:?} into {node_discriminant:?}."),
-                }.into());
-            }
+        Value::Object(_) | Value::Array(_) if node_discriminant != child_discriminant => {
+            return Err(ErrorKind::InvalidObjectTree {
+                invalid_key: vec![],
+                reason: format!("Child node has incompatible type, cannot merge {child_discriminant:?} into {node_discriminant:?}."),
+            }.into());
         }
 
         _ => {}
@@ -187,39 +289,151 @@ fn verify_type_compatibility(node: &Value, child: &Value) -> Result<(), Processi
     Ok(())
 }
 
+/// Processed message, ready to be produced.
+///
+/// New fields may be added in the future, so it cannot be created outside of kafka-json-processor.
+#[non_exhaustive]
 pub struct SerializedOutputMessage {
-    pub key: String,
-    pub message: String,
+    pub key: Option<Vec<u8>>,
+    pub topic: Option<String>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Serialized output message (`None` for tombstones).
+    pub payload: Option<Vec<u8>>,
+}
+
+/// Runs a single processor. A panic of the processor is returned as [`ErrorKind::Panicked`]
+/// and a processor that succeeded, but exceeded the time budget of the stream, as [`ErrorKind::TimedOut`].
+fn run_processor(id: &str, i: usize, process: Processor, source: &Value, message: &mut OutputMessage, stream: &Stream) -> Result<(), ProcessingError> {
+    let start = Instant::now();
+    // the message may be left half-modified, but it's not used after a panic unless the error policy is `continue`
+    let result = catch_unwind(AssertUnwindSafe(|| process(source, message)))
+        .unwrap_or_else(|panic| Err(ErrorKind::Panicked { message: panic_message(panic.as_ref()) }.into()));
+
+    if let Some(budget) = stream.time_budget() {
+        let elapsed = start.elapsed();
+        if elapsed > budget {
+            warn!("[{id}]#{i} Processor took {}ms (time budget: {}ms).", elapsed.as_millis(), budget.as_millis());
+            metrics().slow_processor(stream, i);
+            if result.is_ok() {
+                return Err(ErrorKind::TimedOut { budget, elapsed: Some(elapsed) }.into());
+            }
+        }
+    }
+
+    result
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic.downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown reason".to_string())
 }
 
 pub type ProcessingResult<T> = Result<T, Box<dyn Error>>;
 pub type Processor = &'static (dyn Fn(&Value, &mut OutputMessage) -> Result<(), ProcessingError> + Sync + Send);
 
+/// Processes the payload with given processors, as a stream with default options would (without the context of the message).
+#[deprecated(note = "use process_message, it processes the message with its context and options of its stream")]
 pub fn process_payload(id: String, payload: &[u8], processors: &[Processor]) -> ProcessingResult<SerializedOutputMessage> {
+    let stream = Stream::default();
+    process_payload_with_progress(id, payload, MessageContext::default(), &stream, processors, State::default(), &Progress::default())
+        .map_err(|e| e.into())
+}
+
+/// Processes the message with processors of the stream (as kafka-json-processor does, but without state).
+pub fn process_message(id: String, payload: &[u8], context: MessageContext, stream: &Stream) -> Result<SerializedOutputMessage, MessageError> {
+    process_payload_with_progress(id, payload, context, stream, stream.processors, State::default(), &Progress::default())
+}
+
+/// Progress of processing a message, shared with the task waiting for it (see [`process_payload_with_progress`]).
+#[derive(Default)]
+pub(crate) struct Progress {
+    /// Index of the running processor.
+    pub processor: AtomicUsize,
+    /// Set by whichever comes first - the end of processing or abandoning the message.
+    done: AtomicBool,
+}
+
+impl Progress {
+    /// Marks the processing as finished. Returns `false` if the message was abandoned before.
+    fn finish(&self) -> bool {
+        !self.done.swap(true, Ordering::SeqCst)
+    }
+
+    /// Abandons the message. Returns `false` if its processing has already finished (so its result will be ready soon).
+    pub(crate) fn abandon(&self) -> bool {
+        !self.done.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// Same as [`process_message`], but with the state of the stream. Changes of the state are committed if the message
+/// is processed (or filtered out) - unless it was abandoned in the meantime. The index of the running processor
+/// is stored in `progress`, so it is known which processor got stuck if the message is abandoned.
+pub(crate) fn process_payload_with_progress(id: String, payload: &[u8], context: MessageContext, stream: &Stream, processors: &[Processor], state: State, progress: &Progress) -> Result<SerializedOutputMessage, MessageError> {
     trace!("[{id}] Start of processing.");
-    let source: Value = serde_json::from_slice(payload)?;
-    let mut message: OutputMessage = OutputMessage::new();
+    let source: Value = serde_json::from_slice(payload)
+        .map_err(|e| ErrorKind::InvalidPayload { err: Box::new(e) })?;
+    let mut message: OutputMessage = OutputMessage {
+        state,
+        ..OutputMessage::with_context(context)
+    };
 
     for (i, process) in processors.iter().enumerate() {
-        if let Err(e) = process(&source, &mut message) {
+        progress.processor.store(i, Ordering::Relaxed);
+        if let Err(e) = run_processor(&id, i, *process, &source, &mut message, stream) {
             let e: ProcessingError = e;
+            metrics().processor_error(stream, i, e.inner.name());
             match e.inner {
+                ErrorKind::MessageFiltered { .. } => {
+                    debug!("[{id}]#{i} {e}");
+                    if progress.finish() {
+                        message.state.commit();
+                    }
+                    return Err(MessageError { inner: e.inner, processor: Some(i) });
+                }
+
                 ErrorKind::FieldNotFound { .. } =>
                     debug!("[{id}]#{i} {e}. Skipping processor."),
 
                 ErrorKind::ProcessorSkipped { .. } =>
                     debug!("[{id}]#{i} {e}"),
 
-                _ =>
-                    error!("[{id}]#{i} Cannot process message. Reason: {e}"),
+                _ => match stream.error_policy.unwrap_or(ErrorPolicy::Continue) {
+                    ErrorPolicy::Continue =>
+                        error!("[{id}]#{i} Cannot process message. Reason: {e}"),
+                    // the message is retried (if the error is transient) or fails
+                    ErrorPolicy::Fail | ErrorPolicy::Retry { .. } =>
+                        return Err(MessageError { inner: e.inner, processor: Some(i) }),
+                },
             }
         }
     }
 
     trace!("[{id}] End of processing - serializing message.");
 
+    let key = match message.key {
+        Some(key) => Some(key.into_bytes()),
+        None => stream.key_policy.as_ref()
+            .unwrap_or(&KeyPolicy::Synthetic)
+            .key_for(&id, &source, message.context()),
+    };
+
+    let payload = if message.tombstone {
+        None
+    } else {
+        Some(serde_json::to_vec(&message.value)
+            .map_err(ErrorKind::from)?)
+    };
+    // changes of an abandoned message are discarded (it is not produced)
+    if progress.finish() {
+        message.state.commit();
+    }
+
     Ok(SerializedOutputMessage {
-        key: message.key.unwrap_or(id),
-        message: serde_json::to_string(&message.value)?,
+        key,
+        topic: message.topic,
+        headers: message.headers,
+        payload,
     })
 }
\ No newline at end of file
@@ -1,112 +1,206 @@
+use std::error::Error;
 use std::sync::Arc;
 use std::time::Duration;
-use crossbeam_channel::Receiver;
-use log::{debug, error, info, trace, warn};
-use rdkafka::error::KafkaError;
-use rdkafka::error::RDKafkaErrorCode::{InvalidTopic, QueueFull, UnknownTopic, UnknownTopicOrPartition};
-use rdkafka::producer::{Producer, BaseProducer, BaseRecord};
-use rdkafka::util::Timeout;
-use crate::{PendingMessage, SerializedOutputMessage};
-use crate::journal::MessageOffsetHolder;
-
-pub async fn producer_loop(producer: BaseProducer, rx: Receiver<PendingMessage>, queue_size: usize, queue_slowdown_time: Duration, offset_holder: Arc<MessageOffsetHolder>) {
-    while let Ok(pending) = rx.recv() {
-        match pending {
-            PendingMessage::Received => {
-                // Producer ready to receive messages.
-                // This message is used to slow down consumer if the producer is not able to keep up.
-                // Sender<PendingMessage> uses a bounded channel, so it will block if it's full (that is,
-                // when Receiver<PendingMessage> did not read objects from queue).
+use log::{debug, error, info, trace};
+use rdkafka::producer::ThreadedProducer;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+use crate::PendingMessage;
+use crate::dead_letter::DeadLetter;
+use crate::delivery::{DeliveryContext, OffsetTracker};
+use crate::error::FatalError;
+use crate::health::health;
+use crate::metrics::metrics;
+use crate::MessageOffset;
+use crate::sink::{Delivery, SendError, Sink, SinkMessage};
+
+/// Producer with its own thread serving delivery reports (so they are not delayed by the producer loop).
+pub type KafkaProducer = ThreadedProducer<DeliveryContext>;
+
+/// How often the producer loop reports that it's alive (and updates queue metrics) when there is nothing to produce.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Result of producing a single [`PendingMessage`].
+pub enum Produced {
+    /// The message (or the dead letter) was enqueued in the producer.
+    Enqueued(MessageOffset),
+    /// The message cannot be sent to its topic (and there is no dead-letter topic). It is lost.
+    Skipped(MessageOffset),
+    /// The producer failed to send the message (see [`OffsetTracker::failed`]).
+    Failed(MessageOffset),
+}
+
+/// Produces messages until the channel is closed (all senders were dropped on shutdown or at the end of input),
+/// then flushes the sink.
+///
+/// Stops with an error when a message is not delivered in at-least-once delivery mode, so the runtime is restarted
+/// and messages are consumed again from the last committed offsets.
+pub async fn producer_loop<K: Sink>(
+    sink: K,
+    mut rx: Receiver<PendingMessage>,
+    queue_size: usize,
+    tracker: Arc<OffsetTracker>,
+    flush_timeout: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        observe_queues(&sink, &rx, queue_size);
+        check_delivered(&tracker)?;
+
+        let pending = match timeout(HEARTBEAT_INTERVAL, rx.recv()).await {
+            Ok(Some(pending)) => pending,
+            Ok(None) => break,
+            // no messages to produce, delivery reports are served by the producer thread
+            Err(_) => continue,
+        };
+
+        match produce(&sink, pending, &tracker).await? {
+            Produced::Enqueued(offset) => {
+                // offset needed in case of recovery from crash
+                tracker.enqueued(offset);
             }
-            PendingMessage::Processed { id, topic, message, offset } => {
-                debug!("[{id}] Producing message [{}]", message.key);
-                trace!("[{id}] Produced: {}", message.message);
+            Produced::Skipped(offset) => {
+                tracker.skipped(offset);
+            }
+            Produced::Failed(offset) => {
+                tracker.failed(offset);
+            }
+        }
+    }
+
+    info!("All messages were received, flushing producer ({} message(s) in flight)...", sink.in_flight());
+    sink.flush(flush_timeout)?;
+    check_delivered(&tracker)
+}
+
+/// Returns an error if a message was not delivered in at-least-once delivery mode.
+fn check_delivered(tracker: &OffsetTracker) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match tracker.undelivered() {
+        Some(offset) => Err(format!("Message from [Topic: {}] [Partition: {}] [Offset: {}] was not delivered, \
+            offsets of the partition cannot be committed until it is consumed again", offset.topic, offset.partition, offset.offset).into()),
+        None => Ok(()),
+    }
+}
 
-                if let SentMessage::ShouldSkipMessage = send_loop(&producer, &topic, &id, message, queue_size, queue_slowdown_time) {
-                    // Message not sent, so
-                    continue;
+/// Updates metrics of producer queue and channel occupancy (and marks the producer loop as alive).
+pub fn observe_queues<K: Sink>(sink: &K, rx: &Receiver<PendingMessage>, queue_size: usize) {
+    health().producer_heartbeat();
+    metrics().producer_queue(sink.in_flight(), queue_size);
+    metrics().channel(rx.len(), Some(rx.max_capacity()));
+}
+
+/// Sends the message to its target topic (or to the dead-letter topic if the target topic is invalid
+/// or the message ran out of delivery attempts in at-least-once delivery mode).
+///
+/// Returns an error only if the sink cannot send any more messages (or a message ran out of delivery attempts
+/// and cannot be sent to the dead-letter topic).
+pub async fn produce<K: Sink>(sink: &K, pending: PendingMessage, tracker: &Arc<OffsetTracker>) -> Result<Produced, Box<dyn Error + Send + Sync>> {
+    let produced = produce_pending(sink, pending, tracker).await?;
+    metrics().producer_result(&produced);
+    Ok(produced)
+}
+
+async fn produce_pending<K: Sink>(sink: &K, pending: PendingMessage, tracker: &Arc<OffsetTracker>) -> Result<Produced, Box<dyn Error + Send + Sync>> {
+    match pending {
+        PendingMessage::Processed { id, topic, message, offset, dead_letter } => {
+            debug!("[{id}] Producing message [{}]", String::from_utf8_lossy(message.key.as_deref().unwrap_or_default()));
+            trace!("[{id}] Produced: {}", message.payload.as_deref().map(String::from_utf8_lossy).unwrap_or_else(|| "<tombstone>".into()));
+
+            let failed_attempts = tracker.failed_attempts(&offset);
+            if failed_attempts >= tracker.max_attempts() {
+                let reason = format!("Message was not delivered to [{topic}] in {failed_attempts} attempt(s).");
+                return match dead_letter {
+                    // the dead letter has a single attempt, it's not sent again if it fails too
+                    Some(target) if failed_attempts == tracker.max_attempts() => {
+                        error!("[{id}] {reason} Sending it to the dead-letter topic.");
+                        let dead_letter = target.into_dead_letter("DeliveryFailed", reason, None);
+                        send_dead_letter(sink, &id, offset, dead_letter, tracker).await
+                    }
+                    _ => Err(give_up(&id, offset, reason, tracker)),
+                };
+            }
+
+            let sink_message = SinkMessage {
+                topic: &topic,
+                partition: None,
+                key: message.key.as_deref(),
+                payload: message.payload.as_deref(),
+                headers: &message.headers,
+            };
+
+            match sink.send(sink_message, Delivery::new(offset.clone(), tracker.clone())).await {
+                Ok(()) => Ok(Produced::Enqueued(offset)),
+                Err(SendError::InvalidTopic) => {
+                    error!("[{id}] Topic [{topic}] is invalid (or not existent in Kafka).");
+                    match dead_letter {
+                        Some(target) => {
+                            let dead_letter = target.into_dead_letter(
+                                "UnknownTopic",
+                                format!("Topic [{topic}] is invalid (or not existent in Kafka)."),
+                                None,
+                            );
+                            send_dead_letter(sink, &id, offset, dead_letter, tracker).await
+                        }
+                        None => {
+                            error!("[{id}] Message will be lost!");
+                            Ok(Produced::Skipped(offset))
+                        }
+                    }
                 }
-                
-                // offset needed in case of recovery from crash
-                offset_holder.update(offset);
-                
-                while producer.in_flight_count() as f64 >= 0.95f64 * queue_size as f64 {
-                    warn!("Producer queue is almost full ({}/{}). Halting production for {}s.", producer.in_flight_count(), queue_size, queue_slowdown_time.as_secs());
-                    // This flush will block current thread for queue_slowdown_time or less.
-                    producer.flush(Timeout::After(queue_slowdown_time));
-                    info!("Restarting production (pending: {})...", producer.in_flight_count());
+                Err(SendError::Failed(e)) => {
+                    error!("[{id}] Could not send message [{}]! Reason: {e}", String::from_utf8_lossy(message.key.as_deref().unwrap_or_default()));
+                    Ok(Produced::Failed(offset))
                 }
+                Err(SendError::Fatal(e)) => Err(fatal(&id, e)),
             }
         }
+        PendingMessage::DeadLetter { id, offset, message } => {
+            let failed_attempts = tracker.failed_attempts(&offset);
+            if failed_attempts >= tracker.max_attempts() {
+                let reason = format!("Message was not delivered to dead-letter topic [{}] in {failed_attempts} attempt(s).", message.topic);
+                return Err(give_up(&id, offset, reason, tracker));
+            }
+            send_dead_letter(sink, &id, offset, message, tracker).await
+        }
     }
 }
 
-fn send_loop(producer: &BaseProducer, topic: &str, id: &str, message: SerializedOutputMessage, queue_size: usize, queue_slowdown_time: Duration) -> SentMessage {
-    loop {
-        match send_and_recover(
-            producer,
-            topic,
-            id,
-            message.key.clone(),
-            message.message.clone(),
-            queue_size,
-            queue_slowdown_time
-        ) {
-            SentMessage::Ok |
-            SentMessage::ShouldIgnore => { return SentMessage::Ok; },
-            SentMessage::ShouldSkipMessage => { return SentMessage::ShouldSkipMessage; },
-            SentMessage::CanRetry => { /* try, try again */ },
-        }
+/// Sends the original message to the dead-letter topic.
+async fn send_dead_letter<K: Sink>(sink: &K, id: &str, offset: MessageOffset, message: DeadLetter, tracker: &Arc<OffsetTracker>) -> Result<Produced, Box<dyn Error + Send + Sync>> {
+    debug!("[{id}] Producing message to dead-letter topic [{}] (error kind: {})", message.topic, message.error_kind);
+    trace!("[{id}] Dead letter: {}", String::from_utf8_lossy(&message.payload));
+
+    let headers = message.headers(&offset);
+    let sink_message = SinkMessage {
+        topic: &message.topic,
+        partition: None,
+        key: Some(id.as_bytes()),
+        payload: Some(&message.payload),
+        headers: &headers,
     };
-}
 
-fn send_and_recover(
-    producer: &BaseProducer,
-    topic: &str,
-    id: &str,
-    key: String,
-    payload: String,
-    queue_size: usize,
-    queue_slowdown_time: Duration,
-) -> SentMessage {
-    if let Err(KafkaError::MessageProduction(err)) = send(producer, topic, key, payload) {
-        if err == QueueFull {
-            warn!(
-                "[{id}] Producer queue is full. Try changing producer config (bigger queue, lower linger.ms). Output queue: {}/{}, slowing down producer for {}s.",
-                producer.in_flight_count(), queue_size, queue_slowdown_time.as_secs()
-            );
-            // This flush will block current thread for queue_slowdown_time or less.
-            producer.flush(Timeout::After(queue_slowdown_time));
-            SentMessage::CanRetry
-        } else if err == UnknownTopic || err == UnknownTopicOrPartition || err == InvalidTopic {
-            error!("[{id}] Topic [{}] is invalid (or not existent in Kafka). Message will be lost!", topic);
-            SentMessage::ShouldSkipMessage
-        } else {
-            SentMessage::ShouldIgnore
+    match sink.send(sink_message, Delivery::new(offset.clone(), tracker.clone())).await {
+        Ok(()) => Ok(Produced::Enqueued(offset)),
+        Err(SendError::InvalidTopic) => {
+            error!("[{id}] Dead-letter topic [{}] is invalid. Message will be lost!", message.topic);
+            Ok(Produced::Skipped(offset))
         }
-    } else {
-        SentMessage::Ok
+        Err(SendError::Failed(e)) => {
+            error!("[{id}] Message was not sent to dead-letter topic! Reason: {e}");
+            Ok(Produced::Failed(offset))
+        }
+        Err(SendError::Fatal(e)) => Err(fatal(id, e)),
     }
 }
 
-enum SentMessage {
-    Ok,
-    ShouldIgnore,
-    ShouldSkipMessage,
-    CanRetry
+/// The message ran out of delivery attempts and cannot be sent anywhere - kafka-json-processor must stop.
+fn give_up(id: &str, offset: MessageOffset, reason: String, tracker: &OffsetTracker) -> Box<dyn Error + Send + Sync> {
+    // stops the consumer, which then reports the error of the producer
+    tracker.failed(offset);
+    fatal(id, reason.into())
 }
 
-fn send(producer: &BaseProducer, topic: &str, key: String, payload: String) -> Result<(), KafkaError> {
-    producer.send(
-        BaseRecord::to(topic)
-            .key(&key)
-            .payload(&payload),
-    )
-        .map(|_| {
-            producer.poll(Duration::from_millis(0));
-        })
-        .map_err(|(e, _)| {
-            error!("Could not send message [{}]! Reason: {}, queue: {}", key, e, producer.in_flight_count());
-            e
-        })
-}
\ No newline at end of file
+fn fatal(id: &str, e: Box<dyn Error + Send + Sync>) -> Box<dyn Error + Send + Sync> {
+    Box::new(FatalError {
+        reason: format!("[{id}] Cannot send messages: {e}"),
+    })
+}
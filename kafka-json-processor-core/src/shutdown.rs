@@ -0,0 +1,90 @@
+use std::io;
+use log::{error, info, warn};
+use tokio::runtime::Builder;
+use tokio::sync::watch;
+
+/// Starts a thread that waits for SIGTERM or SIGINT (Ctrl+C).
+///
+/// Returns a receiver that changes to `true` when shutdown is requested.
+/// A second signal exits kafka-json-processor immediately (without draining messages).
+pub fn listen_for_shutdown() -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+
+    std::thread::spawn(move || {
+        let runtime = match Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("Cannot start signal handler runtime: {e}. Graceful shutdown will not be available.");
+                return;
+            }
+        };
+
+        runtime.block_on(async {
+            match wait_for_signal().await {
+                Ok(signal) => {
+                    info!("Received {signal}, shutting down...");
+                    let _ = tx.send(true);
+                }
+                Err(e) => {
+                    error!("Cannot listen for signals: {e}. Graceful shutdown will not be available.");
+                    return;
+                }
+            }
+
+            if let Ok(signal) = wait_for_signal().await {
+                warn!("Received {signal} again, exiting immediately.");
+                std::process::exit(130);
+            }
+        });
+    });
+
+    rx
+}
+
+/// Waits until shutdown is requested.
+pub async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            // signals are not handled, so shutdown will never be requested
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> io::Result<&'static str> {
+    tokio::signal::ctrl_c().await
+        .map(|_| "Ctrl+C")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::runtime::Builder;
+    use tokio::sync::watch;
+    use tokio::time::timeout;
+    use crate::shutdown::shutdown_requested;
+
+    #[test]
+    fn should_wait_until_shutdown_is_requested() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let (tx, mut rx) = watch::channel(false);
+
+        runtime.block_on(async {
+            assert!(timeout(Duration::from_millis(10), shutdown_requested(&mut rx)).await.is_err());
+            tx.send(true).unwrap();
+            assert!(timeout(Duration::from_millis(10), shutdown_requested(&mut rx)).await.is_ok());
+        });
+    }
+}
@@ -1,14 +1,14 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::{fs, io};
-use std::io::ErrorKind;
 use std::path::Path;
 use std::time::Instant;
 use lazy_static::lazy_static;
 use log::{debug, error, info, warn};
 use regex::Regex;
 use serde_json::Value;
-use crate::processor::process_payload;
+use crate::error::{ErrorKind, MessageError};
+use crate::processor::{process_message, MessageContext};
 use crate::Stream;
 
 pub fn simulate_streams_from_default_folder(streams: HashMap<String, Stream>) {
@@ -17,7 +17,7 @@ pub fn simulate_streams_from_default_folder(streams: HashMap<String, Stream>) {
 
 pub fn simulate_streams<P: AsRef<Path>>(streams: HashMap<String, Stream>, base_path: P) {
     for (source, stream) in streams {
-        if let Err(e) = find_samples_and_simulate(&base_path.as_ref().join(source), &stream) {
+        if let Err(e) = find_samples_and_simulate(base_path.as_ref().join(source), &stream) {
             error!("Error during simulation: {}", e);
         }
     }
@@ -69,23 +69,20 @@ fn read_data_from<P: AsRef<Path>>(path: P) -> Result<SimulationDefinition, io::E
         .and_then(|l| FILE_START_REGEX.captures(l))
         .and_then(|c| c.get(1))
         .map(|m| m.as_str().to_string())
-        .ok_or_else(|| io::Error::new(
-            ErrorKind::Other,
+        .ok_or_else(|| io::Error::other(
             format!(
                 "File format error [{}]: Missing kjp-sim header.", path.as_ref().display()
             ),
         ))?;
 
     if file_ver.trim() != "1.0" {
-        return Err(io::Error::new(
-            ErrorKind::Other,
+        return Err(io::Error::other(
             format!("File format error [{}]: Incorrect file version: {}.", path.as_ref().display(), file_ver),
         ));
     }
 
     if !matches!(&lines.next(), Some(INPUT_HEADER)) {
-        return Err(io::Error::new(
-            ErrorKind::Other,
+        return Err(io::Error::other(
             format!("File format error [{}]: Missing [{INPUT_HEADER}] part.", path.as_ref().display()
             )));
     }
@@ -105,8 +102,7 @@ fn read_data_from<P: AsRef<Path>>(path: P) -> Result<SimulationDefinition, io::E
         .collect();
 
     if input.trim().is_empty() || output.trim().is_empty() {
-        return Err(io::Error::new(
-            ErrorKind::Other,
+        return Err(io::Error::other(
             format!("File format error [{}]: Missing [{INPUT_HEADER}] or [{EXPECTED_HEADER}] part.", path.as_ref().display()
             )));
     }
@@ -122,23 +118,32 @@ fn run_single_simulation(msg_id: String, stream: &Stream, input: String, expecte
     let interval = Instant::now();
 
     debug!("[{msg_id}] Simulation started.");
-    let result = process_payload(msg_id.clone(), input.as_bytes(), stream.processors);
+    let result = process_message(msg_id.clone(), input.as_bytes(), MessageContext {
+        topic: stream.source_topic.clone(),
+        ..Default::default()
+    }, stream);
     info!("[{msg_id}] Simulation finished in {}us", interval.elapsed().as_micros());
 
-    if let Err(e) = &result {
-        error!("[{msg_id}] FAILED: {}", e);
-        assert!(result.is_ok());
-    }
-
-    let message = result.unwrap();
+    let actual = match result {
+        // tombstone is expected to be `null`
+        Ok(message) => message.payload
+            .map(|payload| String::from_utf8_lossy(&payload).to_string())
+            .unwrap_or_else(|| "null".to_string()),
+        // filtered message is not produced at all, it is expected to be `null`
+        Err(MessageError { inner: ErrorKind::MessageFiltered { .. }, .. }) => "null".to_string(),
+        Err(e) => {
+            error!("[{msg_id}] FAILED: {}", e);
+            panic!("Simulation failed.");
+        }
+    };
 
     let expected: Value = serde_json::from_str(&expected_output).unwrap();
-    let actual: Value = serde_json::from_str(&message.message).unwrap();
+    let actual_value: Value = serde_json::from_str(&actual).unwrap();
 
-    assert_eq!(actual, expected,
+    assert_eq!(actual_value, expected,
                "FAILED. Expected: {}, actual: {}",
                expected_output.replace('\n', ""),
-               message.message.replace('\n', "")
+               actual.replace('\n', "")
     );
     info!("[{}] PASSED.", msg_id);
 }
@@ -168,6 +173,7 @@ mod tests {
             source_topic: "example".to_string(),
             target_topic: "example".to_string(),
             processors: &[&add_static_field, &format_xml_field, &format_json_field],
+            ..Default::default()
         });
 
         simulate_streams_from_default_folder(streams);
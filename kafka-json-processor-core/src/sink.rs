@@ -0,0 +1,204 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use log::warn;
+use rdkafka::ClientConfig;
+use rdkafka::error::{KafkaError, KafkaResult};
+use rdkafka::error::RDKafkaErrorCode::{InvalidTopic, QueueFull, UnknownTopic, UnknownTopicOrPartition};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{BaseRecord, Producer};
+use rdkafka::util::Timeout;
+use tokio::task::block_in_place;
+use tokio::time::sleep;
+use crate::MessageOffset;
+use crate::delivery::{DeliveryContext, OffsetTracker};
+use crate::producer::KafkaProducer;
+
+/// How long to wait before retrying if the producer queue is full.
+pub(crate) const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Destination of output messages (see [`crate::run_processor_with`]).
+///
+/// Implementations: [`KafkaSink`] and [`crate::json_lines::JsonLinesSink`] (file, directory of files, stdout).
+pub trait Sink: Send + Sync + 'static {
+    /// Sends the message. When it's written (or acknowledged by the broker), the sink must complete `delivery`.
+    ///
+    /// If an error is returned, `delivery` must not be completed.
+    fn send(&self, message: SinkMessage<'_>, delivery: Delivery) -> impl Future<Output = Result<(), SendError>> + Send;
+
+    /// Waits until all sent messages are delivered. Called after the last message.
+    fn flush(&self, timeout: Duration) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Number of messages sent, but not delivered yet.
+    fn in_flight(&self) -> usize {
+        0
+    }
+}
+
+/// An output message.
+pub struct SinkMessage<'a> {
+    pub topic: &'a str,
+    /// Partition of the topic (`None` - chosen by the partitioner, eg. by hash of the key).
+    pub partition: Option<i32>,
+    pub key: Option<&'a [u8]>,
+    /// `None` for tombstones.
+    pub payload: Option<&'a [u8]>,
+    pub headers: &'a [(String, Vec<u8>)],
+}
+
+/// Completion of a sent message - either [`Delivery::delivered`] or [`Delivery::failed`] must be called.
+///
+/// It decides when the offset of the input message can be committed and when the consumer can be resumed
+/// (see `processor.max.in.flight`).
+pub struct Delivery {
+    /// Offset of the input message and its tracker (`None` for messages that are not outputs of an input message).
+    input: Option<(MessageOffset, Arc<OffsetTracker>)>,
+}
+
+impl Delivery {
+    pub(crate) fn new(offset: MessageOffset, tracker: Arc<OffsetTracker>) -> Delivery {
+        Delivery { input: Some((offset, tracker)) }
+    }
+
+    /// Delivery of a message that does not affect committed offsets (eg. a changelog record).
+    pub(crate) fn untracked() -> Delivery {
+        Delivery { input: None }
+    }
+
+    /// Offset of the input message.
+    pub(crate) fn offset(&self) -> Option<&MessageOffset> {
+        self.input.as_ref().map(|(offset, _)| offset)
+    }
+
+    /// The message was written (or acknowledged by the broker).
+    pub fn delivered(self) {
+        if let Some((offset, tracker)) = self.input {
+            tracker.delivered(offset);
+        }
+    }
+
+    /// The message was not delivered. The offset of the input message will not be committed (in at-least-once delivery mode).
+    pub fn failed(self) {
+        if let Some((offset, tracker)) = self.input {
+            tracker.failed(offset);
+        }
+    }
+}
+
+/// Why the message could not be sent.
+#[derive(Debug)]
+pub enum SendError {
+    /// The target topic does not exist (or its name is invalid). The message can be sent to the dead-letter topic instead.
+    InvalidTopic,
+    /// The message could not be sent. It is lost, unless offsets are committed after delivery (see [`crate::delivery::OffsetTracker::failed`]).
+    Failed(Box<dyn Error + Send + Sync>),
+    /// The sink cannot send any more messages (eg. the output file cannot be written) - kafka-json-processor stops.
+    Fatal(Box<dyn Error + Send + Sync>),
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::InvalidTopic => write!(f, "Invalid topic"),
+            SendError::Failed(e) => write!(f, "{e}"),
+            SendError::Fatal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for SendError {}
+
+/// Sends messages to Kafka.
+///
+/// Messages are delivered when the broker acknowledges them (the producer serves delivery reports in its own thread).
+pub struct KafkaSink {
+    producer: KafkaProducer,
+}
+
+impl KafkaSink {
+    /// Creates the producer (without connecting to Kafka yet).
+    pub fn new(config: &ClientConfig) -> KafkaResult<KafkaSink> {
+        Ok(KafkaSink {
+            producer: config.create_with_context(DeliveryContext)?,
+        })
+    }
+
+    pub(crate) fn producer(&self) -> &KafkaProducer {
+        &self.producer
+    }
+}
+
+impl Sink for KafkaSink {
+    /// Retries (without blocking the runtime) while the producer queue is full.
+    async fn send(&self, message: SinkMessage<'_>, delivery: Delivery) -> Result<(), SendError> {
+        let headers = to_owned_headers(message.headers);
+        let mut delivery = Box::new(delivery);
+        let mut warned = false;
+
+        loop {
+            let mut record = BaseRecord::with_opaque_to(message.topic, delivery);
+
+            if let Some(partition) = message.partition {
+                record = record.partition(partition);
+            }
+
+            if let Some(payload) = message.payload {
+                record = record.payload(payload);
+            }
+
+            if let Some(key) = message.key {
+                record = record.key(key);
+            }
+
+            if let Some(headers) = &headers {
+                record = record.headers(headers.clone());
+            }
+
+            match self.producer.send(record) {
+                Ok(()) => return Ok(()),
+                Err((KafkaError::MessageProduction(QueueFull), record)) => {
+                    if !warned {
+                        warn!(
+                            "Producer queue is full ({} message(s) in flight). processor.max.in.flight should not be greater than \
+                            producer.queue.buffering.max.messages. Retrying...",
+                            self.producer.in_flight_count()
+                        );
+                        warned = true;
+                    }
+                    delivery = record.delivery_opaque;
+                    sleep(QUEUE_FULL_BACKOFF).await;
+                }
+                Err((KafkaError::MessageProduction(UnknownTopic | UnknownTopicOrPartition | InvalidTopic), _)) => {
+                    return Err(SendError::InvalidTopic);
+                }
+                Err((e, _)) => {
+                    return Err(SendError::Failed(format!("{e}, queue: {}", self.producer.in_flight_count()).into()));
+                }
+            }
+        }
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+        block_in_place(|| self.producer.flush(Timeout::After(timeout)));
+
+        match self.producer.in_flight_count() {
+            0 => Ok(()),
+            in_flight => Err(format!("{in_flight} message(s) were not delivered in {}ms.", timeout.as_millis()).into()),
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.producer.in_flight_count().max(0) as usize
+    }
+}
+
+fn to_owned_headers(headers: &[(String, Vec<u8>)]) -> Option<OwnedHeaders> {
+    if headers.is_empty() {
+        return None;
+    }
+
+    Some(headers.iter()
+        .fold(OwnedHeaders::new_with_capacity(headers.len()), |owned, (name, value)| owned.add(name, value)))
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, trace, warn};
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::error::KafkaResult;
+use rdkafka::message::Headers;
+use crate::delivery::to_topic_partition_list;
+use crate::journal::OffsetKey;
+use crate::processor::MessageContext;
+
+pub type SourceResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// How long to wait for metadata of topics read from the beginning.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Origin of input messages (see [`crate::run_processor_with`]).
+///
+/// Implementations: [`KafkaSource`] and [`crate::json_lines::JsonLinesSource`] (file, directory of files, stdin).
+pub trait Source: Send + Sync + 'static {
+    /// Starts reading given topics (source topics of all streams).
+    fn subscribe(&self, topics: &[&str]) -> SourceResult<()>;
+
+    /// Starts reading given topics. Partitions with given offsets (eg. from journal) are read after those offsets
+    /// (offsets of the last processed messages).
+    ///
+    /// Offsets are ignored by default.
+    fn subscribe_from(&self, topics: &[&str], offsets: HashMap<OffsetKey, i64>) -> SourceResult<()> {
+        let _ = offsets;
+        self.subscribe(topics)
+    }
+
+    /// Starts reading all partitions of given topics from the beginning, outside of the consumer group
+    /// (topics of lookup tables, see [`crate::lookup::LookupTable`]).
+    ///
+    /// Returns offsets of the end of non-empty partitions (when reading started).
+    /// By default, topics are subscribed and no offsets are returned.
+    fn read_all(&self, topics: &[&str]) -> SourceResult<HashMap<OffsetKey, i64>> {
+        self.subscribe(topics)?;
+        Ok(HashMap::new())
+    }
+
+    /// Receives the next message. Returns `None` at the end of input (a Kafka source never ends).
+    ///
+    /// An error concerns a single message only - it's logged and the next message is received.
+    /// The future must be `Send`, as lookup tables are read by a spawned task.
+    fn recv(&self) -> impl Future<Output = Option<SourceResult<SourceMessage>>> + Send;
+
+    /// Pauses reading when `processor.max.in.flight` is reached. Messages received before pausing can still be returned.
+    ///
+    /// Returns `false` if the source cannot be paused - it's not read at all until it's resumed then.
+    fn pause(&self) -> bool {
+        false
+    }
+
+    /// Resumes reading paused by [`Source::pause`].
+    fn resume(&self) {}
+
+    /// Commits offsets of processed messages (the offset of the last processed message in each partition),
+    /// so that reading starts after them next time (in at-least-once delivery mode).
+    ///
+    /// Offsets are not committed by default.
+    fn commit(&self, offsets: &[(OffsetKey, i64)], mode: CommitMode) -> SourceResult<()> {
+        let _ = (offsets, mode);
+        Ok(())
+    }
+
+    /// Commits offsets of all messages received so far (in auto-commit delivery mode, before shutdown).
+    fn commit_received(&self) -> SourceResult<()> {
+        Ok(())
+    }
+
+    /// Whether the source has partitions assigned (for readiness endpoint).
+    fn assigned(&self) -> bool {
+        true
+    }
+}
+
+/// An input message.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMessage {
+    pub context: MessageContext,
+    /// `None` for tombstones.
+    pub payload: Option<Vec<u8>>,
+}
+
+/// Consumes messages from Kafka topics.
+pub struct KafkaSource {
+    consumer: Arc<StreamConsumer>,
+}
+
+impl KafkaSource {
+    /// Creates the consumer (without connecting to Kafka yet).
+    pub fn new(config: &ClientConfig) -> KafkaResult<KafkaSource> {
+        Ok(KafkaSource {
+            consumer: Arc::new(config.create()?),
+        })
+    }
+
+    pub(crate) fn consumer(&self) -> &Arc<StreamConsumer> {
+        &self.consumer
+    }
+}
+
+impl Source for KafkaSource {
+    fn subscribe(&self, topics: &[&str]) -> SourceResult<()> {
+        self.subscribe_from(topics, HashMap::new())
+    }
+
+    /// Partitions with given offsets are assigned, other partitions are assigned by the consumer group.
+    fn subscribe_from(&self, topics: &[&str], offsets: HashMap<OffsetKey, i64>) -> SourceResult<()> {
+        self.consumer.subscribe(topics)?;
+
+        let mut topic_list = TopicPartitionList::new();
+
+        // add topics with saved offsets to topic_list and store those topics in a vec
+        let topics_with_offsets: Vec<String> = offsets.into_iter()
+            .filter(|(offset_key, _)| {
+                // topic is valid if it's associated with known stream
+                topics.contains(&offset_key.0.as_str())
+            })
+            .filter_map(|(offset_key, offset)| {
+                trace!("Adding topic [Topic: {}] [Partition: {}] with offset {}", &offset_key.0, offset_key.1, offset);
+
+                // journal has the offset of the last processed message
+                topic_list.add_partition_offset(&offset_key.0, offset_key.1, Offset::Offset(offset + 1))
+                    .map_err(|e| {
+                        error!("Cannot assign topic with offset {offset} [Topic: {}] [Partition: {}]. Reason: {e}", &offset_key.0, offset_key.1)
+                    })
+                    .map(|_| {
+                        offset_key.0
+                    })
+                    .ok()
+            })
+            .collect();
+
+        topics.iter()
+            .filter(|topic| !topics_with_offsets.contains(&topic.to_string()))
+            .for_each(|topic| {
+                trace!("Adding unassigned topic [Topic: {topic}]");
+                topic_list.add_topic_unassigned(topic);
+            });
+
+        if let Err(e) = self.consumer.assign(&topic_list) {
+            error!("Cannot assign topics. Topics or offsets are incorrect. Will continue anyway. Reason for failure: {e}");
+        }
+
+        Ok(())
+    }
+
+    /// All partitions are assigned from the beginning, so offsets must not be committed by this consumer.
+    fn read_all(&self, topics: &[&str]) -> SourceResult<HashMap<OffsetKey, i64>> {
+        let mut topic_list = TopicPartitionList::new();
+        let mut end_offsets = HashMap::new();
+
+        for topic in topics {
+            let metadata = self.consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+            let partitions: Vec<i32> = metadata.topics().iter()
+                .flat_map(|topic| topic.partitions())
+                .map(|partition| partition.id())
+                .collect();
+            if partitions.is_empty() {
+                warn!("Topic [{topic}] does not exist or has no partitions.");
+            }
+            for partition in partitions {
+                let (low, high) = self.consumer.fetch_watermarks(topic, partition, METADATA_TIMEOUT)?;
+                if high > low {
+                    end_offsets.insert(OffsetKey(topic.to_string(), partition), high);
+                }
+                topic_list.add_partition_offset(topic, partition, Offset::Beginning)?;
+            }
+        }
+
+        self.consumer.assign(&topic_list)?;
+        Ok(end_offsets)
+    }
+
+    async fn recv(&self) -> Option<SourceResult<SourceMessage>> {
+        let message = match self.consumer.recv().await {
+            Ok(message) => message,
+            Err(e) => return Some(Err(Box::new(e))),
+        };
+
+        Some(Ok(SourceMessage {
+            context: MessageContext {
+                topic: message.topic().to_string(),
+                partition: message.partition(),
+                offset: message.offset(),
+                key: message.key().map(|key| key.to_vec()),
+                timestamp: message.timestamp().to_millis(),
+                headers: message.headers()
+                    .map(|headers| {
+                        (0..headers.count())
+                            .filter_map(|i| headers.get(i))
+                            .map(|(name, value)| (name.to_string(), value.to_vec()))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            },
+            payload: message.payload().map(|payload| payload.to_vec()),
+        }))
+    }
+
+    /// Pauses assigned partitions, so the consumer keeps serving rebalances (and stays in the group).
+    fn pause(&self) -> bool {
+        let result = self.consumer.assignment()
+            .and_then(|assignment| self.consumer.pause(&assignment));
+        if let Err(e) = result {
+            warn!("Cannot pause consumer: {e}");
+        }
+        true
+    }
+
+    fn resume(&self) {
+        let result = self.consumer.assignment()
+            .and_then(|assignment| self.consumer.resume(&assignment));
+        if let Err(e) = result {
+            warn!("Cannot resume consumer: {e}");
+        }
+    }
+
+    /// Kafka expects the offset of the next message to read, so committed offsets are incremented.
+    fn commit(&self, offsets: &[(OffsetKey, i64)], mode: CommitMode) -> SourceResult<()> {
+        Ok(self.consumer.commit(&to_topic_partition_list(offsets), mode)?)
+    }
+
+    fn commit_received(&self) -> SourceResult<()> {
+        Ok(self.consumer.commit_consumer_state(CommitMode::Sync)?)
+    }
+
+    fn assigned(&self) -> bool {
+        self.consumer.assignment()
+            .map(|assignment| assignment.count() > 0)
+            .unwrap_or(false)
+    }
+}
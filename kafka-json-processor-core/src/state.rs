@@ -0,0 +1,586 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use serde_json::Value;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::task::spawn_blocking;
+use crate::Stream;
+use crate::aggregation::{Aggregated, Aggregation, Windows};
+use crate::dedup::{now, write_checkpoint, Deduplicator, SeenIds};
+use crate::error::ProcessingError;
+use crate::lookup::LookupTables;
+use crate::ordering::OrderingPolicy;
+use crate::sink::{Delivery, SendError, Sink, SinkMessage};
+
+/// Directory of state checkpoints (in `processor.journal.path`).
+const STATE_DIR: &str = "state";
+/// How long to wait for changelog records when restoring state.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A record of a changelog topic - a key with its new value (`None` if the key was deleted).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangelogRecord {
+    pub partition: i32,
+    pub key: String,
+    pub value: Option<Value>,
+}
+
+/// Reads all records of a partition of a changelog topic (to restore state that is not on the local disk).
+pub type ChangelogReader = Arc<dyn Fn(&str, i32) -> Result<Vec<ChangelogRecord>, Box<dyn Error + Send + Sync>> + Send + Sync>;
+
+/// Changelog records with their topics, sent to the changelog producer.
+pub(crate) type ChangelogSender = UnboundedSender<(String, ChangelogRecord)>;
+pub(crate) type ChangelogReceiver = UnboundedReceiver<(String, ChangelogRecord)>;
+
+/// Key-value state of a stream, available to processors with [`crate::processor::OutputMessage::state`].
+///
+/// State is scoped by partition of the input topic - a processor sees only the state of the partition of its message.
+/// A partition is loaded when its first message is processed: from the checkpoint in `processor.journal.path`
+/// or, if there is no checkpoint, from the changelog topic of the stream (if it has one).
+/// Checkpoints are saved with the journal (and on shutdown) and every change is produced to the changelog topic
+/// (to the same partition as the input message, keyed by the state key).
+///
+/// State is at-least-once in every delivery mode (also exactly-once): checkpoints are saved separately from offsets,
+/// so changes made by messages consumed again (after a crash or an aborted transaction) are applied again.
+/// Changelog records cannot be produced in transactions, so changelog topics are rejected in exactly-once delivery mode.
+///
+/// Ids seen by a deduplicated stream ([`crate::Stream::dedup`]) are kept here too - for the whole stream, checkpointed
+/// separately from partitions. Ids seen by [`crate::dedup::deduplicate`] processors are kept in the state of
+/// the partition (and checkpointed with it), but they are not produced to the changelog topic.
+///
+/// Open windows of an aggregating stream are kept by partition (and checkpointed with the state) too.
+///
+/// Lookup tables of the stream are shared by all partitions (and all streams using them), see [`crate::lookup::LookupTable`].
+pub struct StateStore {
+    stream: String,
+    dir: Option<PathBuf>,
+    changelog: Option<Arc<Changelog>>,
+    partitions: Mutex<HashMap<i32, Arc<PartitionState>>>,
+    dedup: Option<Deduplicator>,
+    /// Maximum number of ids remembered by processors in a partition.
+    max_seen_ids: usize,
+    aggregation: Option<Aggregation>,
+    lookups: LookupTables,
+    /// Set if messages of the stream are not ordered (see [`PartitionState::unordered_changes`]).
+    unordered_changes: Option<Arc<AtomicBool>>,
+}
+
+/// Changelog topic of a stream with the channel to the changelog producer.
+struct Changelog {
+    topic: String,
+    reader: ChangelogReader,
+    /// Taken on shutdown, so the changelog producer can deliver remaining records and stop.
+    tx: Mutex<Option<ChangelogSender>>,
+}
+
+impl Changelog {
+    fn send(&self, record: ChangelogRecord) {
+        let key = record.key.clone();
+        let sent = self.tx.lock().unwrap().as_ref()
+            .is_some_and(|tx| tx.send((self.topic.clone(), record)).is_ok());
+        if !sent {
+            warn!("Changelog producer stopped, state change of key [{key}] will not be written to [{}].", self.topic);
+        }
+    }
+}
+
+/// State of a single partition.
+pub(crate) struct PartitionState {
+    partition: i32,
+    values: Mutex<BTreeMap<String, Value>>,
+    seen_ids: Mutex<SeenIds>,
+    windows: Mutex<Windows>,
+    /// Whether there are changes that are not saved in the checkpoint.
+    dirty: AtomicBool,
+    changelog: Option<Arc<Changelog>>,
+    lookups: LookupTables,
+    stream: String,
+    /// Set if messages of the stream are not ordered - whether it was already logged that they change the state.
+    unordered_changes: Option<Arc<AtomicBool>>,
+}
+
+impl StateStore {
+    /// Creates the state store of a stream. Checkpoints are saved in `journal_dir` (if set).
+    pub(crate) fn new(stream: &Stream, journal_dir: Option<&str>, changelog: Option<(ChangelogReader, &ChangelogSender)>, lookups: LookupTables) -> StateStore {
+        let name = format!("{}_{}", stream.source_topic, stream.target_topic);
+        let dir = journal_dir.map(|dir| Path::new(dir).join(STATE_DIR).join(&name));
+        let max_seen_ids = stream.dedup_max_entries.unwrap_or(usize::MAX);
+        StateStore {
+            dedup: stream.dedup.clone().map(|policy| Deduplicator::new(
+                policy,
+                stream.dedup_window_ms.unwrap_or_default() as u64,
+                max_seen_ids,
+                dir.as_deref(),
+            )),
+            max_seen_ids,
+            aggregation: stream.aggregation.clone(),
+            lookups,
+            dir,
+            changelog: stream.state_changelog_topic.clone()
+                .zip(changelog)
+                .map(|(topic, (reader, tx))| Arc::new(Changelog {
+                    topic,
+                    reader,
+                    tx: Mutex::new(Some(tx.clone())),
+                })),
+            partitions: Mutex::new(HashMap::new()),
+            unordered_changes: Some(Arc::new(AtomicBool::new(false)))
+                .filter(|_| stream.ordering.unwrap_or(OrderingPolicy::Unordered) == OrderingPolicy::Unordered),
+            stream: name,
+        }
+    }
+
+    /// Returns the state of the partition, loading it first if needed.
+    pub(crate) async fn partition(self: &Arc<Self>, partition: i32) -> Result<Arc<PartitionState>, String> {
+        if let Some(state) = self.partitions.lock().unwrap().get(&partition) {
+            return Ok(state.clone());
+        }
+
+        // reading checkpoint files (or a changelog topic) blocks
+        let store = self.clone();
+        let (values, seen_ids, windows) = spawn_blocking(move || store.load(partition)).await
+            .map_err(|e| e.to_string())??;
+
+        Ok(self.partitions.lock().unwrap()
+            .entry(partition)
+            .or_insert_with(|| Arc::new(PartitionState {
+                partition,
+                values: Mutex::new(values),
+                seen_ids: Mutex::new(seen_ids),
+                windows: Mutex::new(windows),
+                dirty: AtomicBool::new(false),
+                changelog: self.changelog.clone(),
+                lookups: self.lookups.clone(),
+                stream: self.stream.clone(),
+                unordered_changes: self.unordered_changes.clone(),
+            }))
+            .clone())
+    }
+
+    fn load(&self, partition: i32) -> Result<(BTreeMap<String, Value>, SeenIds, Windows), String> {
+        let seen_ids = match self.seen_ids_path(partition).filter(|path| path.exists()) {
+            Some(path) => SeenIds::load(&path, self.max_seen_ids)?,
+            None => SeenIds::new(self.max_seen_ids),
+        };
+        let windows = match self.windows_path(partition).filter(|path| path.exists()) {
+            Some(path) => fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| Windows::from_json(&content))
+                .map_err(|e| format!("Invalid windows checkpoint {path:?}: {e}"))?,
+            None => Windows::default(),
+        };
+
+        if let Some(path) = self.checkpoint_path(partition).filter(|path| path.exists()) {
+            debug!("Loading state of stream {} (partition {partition}) from {path:?}.", self.stream);
+            let content = fs::read(&path)
+                .map_err(|e| format!("Cannot read state checkpoint {path:?}: {e}"))?;
+            let values = serde_json::from_slice(&content)
+                .map_err(|e| format!("Invalid state checkpoint {path:?}: {e}"))?;
+            return Ok((values, seen_ids, windows));
+        }
+
+        let mut values = BTreeMap::new();
+        if let Some(changelog) = &self.changelog {
+            info!("Restoring state of stream {} (partition {partition}) from changelog topic [{}]...", self.stream, changelog.topic);
+            let records = (changelog.reader)(&changelog.topic, partition)
+                .map_err(|e| format!("Cannot restore state from changelog topic [{}]: {e}", changelog.topic))?;
+            for record in records {
+                match record.value {
+                    Some(value) => values.insert(record.key, value),
+                    None => values.remove(&record.key),
+                };
+            }
+            info!("Restored {} key(s) of stream {} (partition {partition}).", values.len(), self.stream);
+        }
+        Ok((values, seen_ids, windows))
+    }
+
+    fn checkpoint_path(&self, partition: i32) -> Option<PathBuf> {
+        self.dir.as_ref()
+            .map(|dir| dir.join(format!("{partition}.json")))
+    }
+
+    fn seen_ids_path(&self, partition: i32) -> Option<PathBuf> {
+        self.dir.as_ref()
+            .map(|dir| dir.join(format!("{partition}.seen.json")))
+    }
+
+    fn windows_path(&self, partition: i32) -> Option<PathBuf> {
+        self.dir.as_ref()
+            .map(|dir| dir.join(format!("{partition}.windows.json")))
+    }
+
+    /// Windowed aggregation of the stream (if the stream aggregates messages).
+    pub(crate) fn aggregation(&self) -> Option<&Aggregation> {
+        self.aggregation.as_ref()
+    }
+
+    /// Deduplicator of the stream (if the stream is deduplicated).
+    pub(crate) fn dedup(&self) -> Option<&Deduplicator> {
+        self.dedup.as_ref()
+    }
+
+    /// Saves checkpoints of partitions (and seen ids) changed since the last flush.
+    pub(crate) fn flush(&self) {
+        if let Some(dedup) = &self.dedup {
+            dedup.flush();
+        }
+        let Some(dir) = &self.dir else {
+            return;
+        };
+
+        let partitions: Vec<Arc<PartitionState>> = self.partitions.lock().unwrap()
+            .values()
+            .filter(|state| state.dirty.swap(false, Ordering::SeqCst))
+            .cloned()
+            .collect();
+        if partitions.is_empty() {
+            return;
+        }
+
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Cannot create directory {dir:?}! State will not be saved. Reason: {e}");
+            partitions.iter().for_each(|state| state.dirty.store(true, Ordering::SeqCst));
+            return;
+        }
+
+        for state in partitions {
+            let content = serde_json::to_vec(&*state.values.lock().unwrap())
+                .expect("state values are valid JSON-s");
+            let path = dir.join(format!("{}.json", state.partition));
+            let seen_ids = state.seen_ids.lock().unwrap().to_json();
+            let seen_ids_path = dir.join(format!("{}.seen.json", state.partition));
+            let windows = Some(state.windows.lock().unwrap())
+                .filter(|windows| !windows.is_empty())
+                .map(|windows| windows.to_json());
+            let windows_path = dir.join(format!("{}.windows.json", state.partition));
+            let saved = write_checkpoint(&path, content)
+                .and_then(|_| write_checkpoint(&seen_ids_path, seen_ids))
+                .and_then(|_| windows.map_or(Ok(()), |windows| write_checkpoint(&windows_path, windows)));
+            if let Err(e) = saved {
+                error!("Failed to save state checkpoint {path:?}. Reason: {e}");
+                state.dirty.store(true, Ordering::SeqCst);
+            } else {
+                debug!("Saved state checkpoint {path:?}.");
+            }
+        }
+    }
+
+    /// Stops sending changes to the changelog topic (on shutdown).
+    pub(crate) fn close_changelog(&self) {
+        if let Some(changelog) = &self.changelog {
+            changelog.tx.lock().unwrap().take();
+        }
+    }
+}
+
+impl Drop for StateStore {
+    fn drop(&mut self) {
+        // as the journal, state is saved when kafka-json-processor finishes
+        self.flush()
+    }
+}
+
+impl PartitionState {
+    /// Adds the input message to open windows of the partition (see [`Windows::add`]).
+    pub(crate) fn aggregate(&self, aggregation: &Aggregation, input: &Value, timestamp: Option<i64>, offset: i64) -> Result<Aggregated, ProcessingError> {
+        let aggregated = self.windows.lock().unwrap().add(aggregation, input, timestamp, offset)?;
+        if aggregated != Aggregated::Replayed {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        Ok(aggregated)
+    }
+}
+
+/// State of the partition of the input message (see [`StateStore`]).
+///
+/// Changes are visible to the following processors at once, but they are applied to the partition state
+/// (and produced to the changelog topic) only when the message is processed (or filtered out).
+/// If processing fails (or it's retried), changes are discarded.
+///
+/// Messages of the same partition can be processed in parallel - to have consistent state, use `processor.ordering`
+/// `partition` (or `key`, if state keys are derived from message keys). Streams with a changelog topic are ordered
+/// by partition, unless they are ordered by key (a warning is logged when an unordered stream changes its state).
+#[derive(Default)]
+pub struct State {
+    partition: Option<Arc<PartitionState>>,
+    changes: BTreeMap<String, Option<Value>>,
+    /// Ids seen by this message (with their message and expiry time).
+    seen_ids: Vec<(String, String, u64)>,
+}
+
+impl State {
+    pub(crate) fn new(partition: Arc<PartitionState>) -> State {
+        State { partition: Some(partition), changes: BTreeMap::new(), seen_ids: vec![] }
+    }
+
+    /// Returns the value of the key.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        match self.changes.get(key) {
+            Some(value) => value.clone(),
+            None => self.partition.as_ref()
+                .and_then(|partition| partition.values.lock().unwrap().get(key).cloned()),
+        }
+    }
+
+    /// Sets the value of the key.
+    pub fn put(&mut self, key: &str, value: Value) {
+        self.changes.insert(key.to_string(), Some(value));
+    }
+
+    /// Removes the key.
+    pub fn delete(&mut self, key: &str) {
+        self.changes.insert(key.to_string(), None);
+    }
+
+    /// Returns the record with given key from a lookup table of the stream (see [`crate::lookup::LookupTable`]).
+    pub(crate) fn lookup(&self, table: &str, key: &str) -> Option<Value> {
+        self.partition.as_ref()
+            .and_then(|partition| partition.lookups.get(table, key))
+    }
+
+    /// Returns `true` if another message (than `source`) with this id was seen within the window.
+    /// Otherwise, the id is remembered when the message is committed (see [`crate::dedup::deduplicate`]).
+    pub(crate) fn seen(&mut self, id: &str, source: &str, window: Duration) -> bool {
+        let now = now();
+        let seen_by_other = self.partition.as_ref()
+            .is_some_and(|partition| partition.seen_ids.lock().unwrap()
+                .seen_by(id, now)
+                .is_some_and(|seen_in| seen_in != source));
+        if !seen_by_other {
+            self.seen_ids.push((id.to_string(), source.to_string(), now.saturating_add(window.as_millis() as u64)));
+        }
+        seen_by_other
+    }
+
+    /// Applies changes to the partition state and sends them to the changelog topic.
+    pub(crate) fn commit(self) {
+        let Some(partition) = self.partition else {
+            return;
+        };
+        if !self.seen_ids.is_empty() {
+            let mut seen_ids = partition.seen_ids.lock().unwrap();
+            for (id, source, expires_at) in self.seen_ids {
+                seen_ids.remember(id, source, expires_at);
+            }
+            partition.dirty.store(true, Ordering::SeqCst);
+        }
+        if self.changes.is_empty() {
+            return;
+        }
+        if partition.unordered_changes.as_ref().is_some_and(|logged| !logged.swap(true, Ordering::SeqCst)) {
+            // aggregating streams and streams with a changelog topic are always ordered, other streams cannot be checked upfront
+            warn!("Processors of stream {} change its state, but the stream is not ordered - concurrent changes may overwrite each other. \
+                Set ordering of the stream to partition (or key, if state keys are derived from message keys).", partition.stream);
+        }
+
+        let mut values = partition.values.lock().unwrap();
+        for (key, value) in self.changes {
+            match &value {
+                Some(value) => values.insert(key.clone(), value.clone()),
+                None => values.remove(&key),
+            };
+            if let Some(changelog) = &partition.changelog {
+                changelog.send(ChangelogRecord { partition: partition.partition, key, value });
+            }
+        }
+        partition.dirty.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Produces changelog records until all state stores close their changelogs, then flushes the sink.
+pub(crate) async fn changelog_loop<K: Sink>(sink: K, mut rx: ChangelogReceiver, flush_timeout: Duration) {
+    while let Some((topic, record)) = rx.recv().await {
+        let payload = record.value.as_ref()
+            .map(|value| serde_json::to_vec(value).expect("state values are valid JSON-s"));
+        let message = SinkMessage {
+            topic: &topic,
+            partition: Some(record.partition),
+            key: Some(record.key.as_bytes()),
+            payload: payload.as_deref(),
+            headers: &[],
+        };
+
+        match sink.send(message, Delivery::untracked()).await {
+            Ok(()) => {}
+            Err(SendError::InvalidTopic) =>
+                error!("Changelog topic [{topic}] (partition {}) is invalid. State change of key [{}] is lost!", record.partition, record.key),
+            Err(e) =>
+                error!("State change of key [{}] was not sent to changelog topic [{topic}]! Reason: {e}", record.key),
+        }
+    }
+
+    if let Err(e) = sink.flush(flush_timeout) {
+        error!("Changelog producer was not flushed: {e}");
+    }
+}
+
+/// Creates a channel for changelog records of all state stores.
+pub(crate) fn changelog_channel() -> (ChangelogSender, ChangelogReceiver) {
+    unbounded_channel()
+}
+
+/// Reads changelog partitions from Kafka (from the beginning to the current end of the partition).
+pub(crate) fn kafka_changelog_reader(config: &ClientConfig) -> ChangelogReader {
+    let mut config = config.clone();
+    config.set("enable.auto.commit", "false");
+
+    Arc::new(move |topic, partition| {
+        let consumer: BaseConsumer = config.create()?;
+        let (low, high) = consumer.fetch_watermarks(topic, partition, RESTORE_TIMEOUT)?;
+        let mut records = vec![];
+        if high <= low {
+            return Ok(records);
+        }
+
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(topic, partition, Offset::Beginning)?;
+        consumer.assign(&assignment)?;
+
+        loop {
+            let message = match consumer.poll(RESTORE_TIMEOUT) {
+                Some(message) => message?,
+                None => return Err(format!("no records received in {}ms", RESTORE_TIMEOUT.as_millis()).into()),
+            };
+
+            if let Some(key) = message.key() {
+                let value = message.payload()
+                    .map(serde_json::from_slice)
+                    .transpose()?;
+                records.push(ChangelogRecord {
+                    partition,
+                    key: String::from_utf8_lossy(key).to_string(),
+                    value,
+                });
+            }
+
+            if message.offset() >= high - 1 {
+                return Ok(records);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use serde_json::json;
+    use tokio::runtime::Builder;
+    use crate::lookup::LookupTables;
+    use crate::state::{changelog_channel, ChangelogRecord, State, StateStore};
+    use crate::Stream;
+
+    fn stream() -> Stream {
+        Stream {
+            source_topic: "in".to_string(),
+            target_topic: "out".to_string(),
+            state_changelog_topic: Some("in_out_changelog".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_apply_changes_only_on_commit() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let (tx, mut rx) = changelog_channel();
+        let reader = Arc::new(|_: &str, _: i32| Ok(vec![]));
+        let store = Arc::new(StateStore::new(&stream(), None, Some((reader, &tx)), LookupTables::default()));
+        let partition = runtime.block_on(store.partition(1)).unwrap();
+
+        let mut state = State::new(partition.clone());
+        state.put("a", json!(1));
+        state.put("b", json!(2));
+        state.delete("b");
+        assert_eq!(Some(json!(1)), state.get("a"));
+        assert_eq!(None, State::new(partition.clone()).get("a"));
+
+        state.commit();
+        assert_eq!(Some(json!(1)), State::new(partition.clone()).get("a"));
+        assert_eq!(Some(("in_out_changelog".to_string(), ChangelogRecord { partition: 1, key: "a".to_string(), value: Some(json!(1)) })), rx.try_recv().ok());
+        assert_eq!(Some(("in_out_changelog".to_string(), ChangelogRecord { partition: 1, key: "b".to_string(), value: None })), rx.try_recv().ok());
+
+        // discarded
+        let mut state = State::new(partition.clone());
+        state.put("a", json!(3));
+        drop(state);
+        assert_eq!(Some(json!(1)), State::new(partition).get("a"));
+    }
+
+    #[test]
+    fn should_restore_state_from_checkpoint_or_changelog() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let dir = std::env::temp_dir().join(format!("kjp-state-test-{}", std::process::id()));
+        let journal_dir = dir.to_str().unwrap();
+        let (tx, _rx) = changelog_channel();
+        let reader = Arc::new(|topic: &str, partition: i32| {
+            assert_eq!("in_out_changelog", topic);
+            Ok(vec![
+                ChangelogRecord { partition, key: "a".to_string(), value: Some(json!("old")) },
+                ChangelogRecord { partition, key: "b".to_string(), value: Some(json!("deleted")) },
+                ChangelogRecord { partition, key: "a".to_string(), value: Some(json!("new")) },
+                ChangelogRecord { partition, key: "b".to_string(), value: None },
+            ])
+        });
+
+        {
+            let store = Arc::new(StateStore::new(&stream(), Some(journal_dir), Some((reader.clone(), &tx)), LookupTables::default()));
+            let mut state = State::new(runtime.block_on(store.partition(0)).unwrap());
+            assert_eq!(Some(json!("new")), state.get("a"));
+            assert_eq!(None, state.get("b"));
+
+            state.put("c", json!({"count": 1}));
+            state.commit();
+            // saved on drop
+        }
+
+        let store = Arc::new(StateStore::new(&stream(), Some(journal_dir), Some((reader, &tx)), LookupTables::default()));
+        let state = State::new(runtime.block_on(store.partition(0)).unwrap());
+        assert_eq!(Some(json!({"count": 1})), state.get("c"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_remember_seen_ids_of_committed_messages() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let dir = std::env::temp_dir().join(format!("kjp-seen-ids-test-{}", std::process::id()));
+        let journal_dir = dir.to_str().unwrap();
+        let window = Duration::from_secs(60);
+
+        {
+            let store = Arc::new(StateStore::new(&stream(), Some(journal_dir), None, LookupTables::default()));
+            let partition = runtime.block_on(store.partition(0)).unwrap();
+
+            let mut state = State::new(partition.clone());
+            assert!(!state.seen("a", "in:0@0", window));
+            // failed messages are not remembered
+            let mut failed = State::new(partition.clone());
+            assert!(!failed.seen("b", "in:0@1", window));
+            drop(failed);
+            state.commit();
+
+            let mut state = State::new(partition);
+            assert!(state.seen("a", "in:0@2", window));
+            // the same message consumed again is not a duplicate
+            assert!(!state.seen("a", "in:0@0", window));
+            assert!(!state.seen("b", "in:0@3", window));
+            state.commit();
+        }
+
+        let store = Arc::new(StateStore::new(&stream(), Some(journal_dir), None, LookupTables::default()));
+        let mut state = State::new(runtime.block_on(store.partition(0)).unwrap());
+        assert!(state.seen("a", "in:0@4", window));
+        assert!(state.seen("b", "in:0@4", window));
+        assert!(!state.seen("c", "in:0@4", window));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
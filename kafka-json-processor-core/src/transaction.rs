@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, trace, warn};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::error::{KafkaError, KafkaResult};
+use rdkafka::producer::Producer;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+use crate::{MessageOffset, PendingMessage};
+use crate::delivery::{to_topic_partition_list, OffsetTracker};
+use crate::error::FatalError;
+use crate::producer::{observe_queues, produce, KafkaProducer, Produced, HEARTBEAT_INTERVAL};
+use crate::sink::KafkaSink;
+
+pub struct TransactionSettings {
+    /// Transaction is committed after this number of messages...
+    pub batch_size: usize,
+    /// ...or after this time (whichever comes first).
+    pub interval: Duration,
+    /// Timeout of transactional operations (init, commit, abort).
+    pub timeout: Duration,
+    /// Maximum number of messages deferred to the next transaction (`processor.max.in.flight`).
+    pub max_deferred: usize,
+}
+
+type TransactionResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Producer loop for [`crate::config::DeliveryMode::ExactlyOnce`].
+///
+/// Output messages are produced in transactions. Consumed offsets are sent to the same transaction,
+/// so the output and the offsets are committed atomically.
+///
+/// Messages are processed concurrently, so they can arrive in any order. A transaction is committed only
+/// if all messages (in every partition) up to the highest produced offset are a part of this or previous transaction.
+/// Until then, messages that would make a new gap are deferred to the next transaction. Deferred messages are in flight,
+/// so the consumer pauses when there are too many of them - if the gap is still not filled when `max_deferred` messages
+/// are deferred, the transaction is aborted.
+///
+/// A transaction is aborted as soon as any of its messages is not delivered (it could not be committed anyway).
+///
+/// Returns an error if the transaction was aborted - runtime is restarted then and messages are consumed again
+/// from the last committed offsets.
+pub async fn transactional_producer_loop(
+    sink: KafkaSink,
+    mut rx: Receiver<PendingMessage>,
+    consumer: Arc<StreamConsumer>,
+    queue_size: usize,
+    tracker: Arc<OffsetTracker>,
+    settings: TransactionSettings,
+) -> TransactionResult<()> {
+    let producer = sink.producer();
+    debug!("Initializing transactions...");
+    producer.init_transactions(settings.timeout)
+        .map_err(fatal_if_fenced)?;
+    producer.begin_transaction()
+        .map_err(fatal_if_fenced)?;
+
+    let mut in_transaction = 0;
+    let mut transaction_start = Instant::now();
+    let mut deferred = VecDeque::new();
+
+    loop {
+        observe_queues(&sink, &rx, queue_size);
+        check_delivered(producer, &tracker, &settings)?;
+
+        let commit_due = in_transaction >= settings.batch_size
+            || (in_transaction > 0 && transaction_start.elapsed() >= settings.interval);
+
+        if commit_due && tracker.is_consistent() {
+            commit_transaction(producer, &consumer, &tracker, &settings)?;
+            producer.begin_transaction()
+                .map_err(fatal_if_fenced)?;
+
+            in_transaction = 0;
+            transaction_start = Instant::now();
+
+            while let Some(pending) = deferred.pop_front() {
+                in_transaction += produce_in_transaction(&sink, pending, &tracker, &settings).await?;
+            }
+            continue;
+        }
+
+        let pending = match timeout(HEARTBEAT_INTERVAL, rx.recv()).await {
+            Ok(Some(pending)) => pending,
+            Ok(None) => break,
+            Err(_) => continue,
+        };
+
+        if commit_due && !fills_gap(&tracker, &pending) {
+            if deferred.len() >= settings.max_deferred {
+                error!("Transaction cannot be committed, {} messages are waiting for the next transaction.", deferred.len());
+                abort_transaction(producer, &settings)?;
+                return Err("Transaction was aborted, because too many messages were deferred to the next transaction.".into());
+            }
+            trace!("Transaction cannot be committed yet, deferring message.");
+            deferred.push_back(pending);
+            continue;
+        }
+
+        in_transaction += produce_in_transaction(&sink, pending, &tracker, &settings).await?;
+    }
+
+    // All senders were dropped (shutdown) - every message is either a part of current transaction or deferred.
+    check_delivered(producer, &tracker, &settings)?;
+    if !tracker.is_consistent() {
+        info!("Producer stopped, aborting current transaction.");
+        return abort_transaction(producer, &settings);
+    }
+
+    info!("All messages were received, committing the last transaction.");
+    commit_transaction(producer, &consumer, &tracker, &settings)?;
+
+    if !deferred.is_empty() {
+        producer.begin_transaction()
+            .map_err(fatal_if_fenced)?;
+        while let Some(pending) = deferred.pop_front() {
+            produce_in_transaction(&sink, pending, &tracker, &settings).await?;
+        }
+        commit_transaction(producer, &consumer, &tracker, &settings)?;
+    }
+
+    Ok(())
+}
+
+/// Aborts the transaction if any of its messages was not delivered. Returns a fatal error if the producer failed fatally.
+fn check_delivered(producer: &KafkaProducer, tracker: &OffsetTracker, settings: &TransactionSettings) -> TransactionResult<()> {
+    if let Some((code, reason)) = producer.client().fatal_error() {
+        return Err(Box::new(FatalError {
+            reason: format!("Fatal producer error: {reason} ({code})."),
+        }));
+    }
+
+    match tracker.undelivered() {
+        Some(offset) => {
+            abort_transaction(producer, settings)?;
+            Err(transaction_aborted(&offset))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Checks whether the message should be a part of current transaction (so it can be committed).
+fn fills_gap(tracker: &OffsetTracker, pending: &PendingMessage) -> bool {
+    match pending {
+        PendingMessage::Processed { offset, .. } | PendingMessage::DeadLetter { offset, .. } => tracker.fills_gap(offset),
+    }
+}
+
+/// Produces the message in current transaction. Returns the number of enqueued messages.
+async fn produce_in_transaction(
+    sink: &KafkaSink,
+    pending: PendingMessage,
+    tracker: &Arc<OffsetTracker>,
+    settings: &TransactionSettings,
+) -> TransactionResult<usize> {
+    match produce(sink, pending, tracker).await? {
+        Produced::Enqueued(offset) => {
+            tracker.enqueued(offset);
+            Ok(1)
+        }
+        Produced::Skipped(offset) => {
+            tracker.skipped(offset);
+            Ok(0)
+        }
+        Produced::Failed(offset) => {
+            error!("Message [Topic: {}] [Partition: {}] [Offset: {}] could not be produced in transaction.", offset.topic, offset.partition, offset.offset);
+            abort_transaction(sink.producer(), settings)?;
+            Err(transaction_aborted(&offset))
+        }
+    }
+}
+
+fn commit_transaction(producer: &KafkaProducer, consumer: &StreamConsumer, tracker: &OffsetTracker, settings: &TransactionSettings) -> TransactionResult<()> {
+    let offsets = tracker.uncommitted();
+    debug!("Committing transaction with offsets: {offsets:?}");
+
+    if !offsets.is_empty() {
+        let group_metadata = consumer.group_metadata()
+            .ok_or("Consumer group metadata is not available. Is group.id set?")?;
+
+        retry_or_abort(producer, settings, || {
+            producer.send_offsets_to_transaction(&to_topic_partition_list(&offsets), &group_metadata, settings.timeout)
+        })?;
+    }
+
+    retry_or_abort(producer, settings, || producer.commit_transaction(settings.timeout))?;
+    tracker.committed(offsets);
+
+    Ok(())
+}
+
+fn abort_transaction(producer: &KafkaProducer, settings: &TransactionSettings) -> TransactionResult<()> {
+    warn!("Aborting transaction. Messages will be consumed again from the last committed offsets.");
+    producer.abort_transaction(settings.timeout)
+        .map_err(fatal_if_fenced)
+}
+
+/// Retries retriable errors, aborts transaction if required.
+fn retry_or_abort<T>(producer: &KafkaProducer, settings: &TransactionSettings, operation: impl Fn() -> KafkaResult<T>) -> TransactionResult<T> {
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(KafkaError::Transaction(e)) if e.is_retriable() => {
+                warn!("Transaction error: {e}. Retrying...");
+            }
+            Err(KafkaError::Transaction(e)) if e.txn_requires_abort() => {
+                error!("Transaction error: {e}.");
+                abort_transaction(producer, settings)?;
+                return Err(format!("Transaction was aborted. Reason: {e}").into());
+            }
+            Err(e) => return Err(fatal_if_fenced(e)),
+        }
+    }
+}
+
+/// Fatal errors (eg. producer fenced by another instance with the same `transactional.id`)
+/// cannot be recovered by restarting the runtime.
+fn fatal_if_fenced(e: KafkaError) -> Box<dyn Error + Send + Sync> {
+    match e {
+        KafkaError::Transaction(e) if e.is_fatal() => Box::new(FatalError {
+            reason: format!("Fatal transaction error: {e}. Another instance with the same transactional.id may be running."),
+        }),
+        e => Box::new(e),
+    }
+}
+
+fn transaction_aborted(offset: &MessageOffset) -> Box<dyn Error + Send + Sync> {
+    format!("Transaction was aborted, because message [Topic: {}] [Partition: {}] [Offset: {}] was not produced.", offset.topic, offset.partition, offset.offset).into()
+}
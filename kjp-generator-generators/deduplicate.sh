@@ -0,0 +1,60 @@
+#!/usr/bin/env bash
+
+source "$(dirname "$0")/util/params.sh" || exit 255
+
+function_source="fn %%FUNCTION_NAME%%(%%INPUT%%: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+    let id = %%ID%%;
+
+    kafka_json_processor_core::dedup::deduplicate(&id, std::time::Duration::from_millis(%%WINDOW_MS%%), message)
+}
+"
+
+window_ms="600000"
+optional_param_to_var field
+optional_param_to_var header
+optional_param_to_var window_ms
+
+if [[ ! "$window_ms" =~ ^[0-9]+$ ]]; then
+  echo "ERR"
+  printf 'Invalid window_ms: %s. It should be a number of milliseconds.\n' "$window_ms"
+  exit 1
+fi
+
+if [[ -n "$field" && -n "$header" ]]; then
+  echo "ERR"
+  echo "Dedup id can be taken either from a field or from a header, not both."
+  exit 1
+elif [[ -n "$field" ]]; then
+  input="input"
+  id="match input.get_val(##JSONPATH(%%FIELD%%)##)? {
+        Value::String(id) => id.clone(),
+        id => id.to_string(),
+    }"
+elif [[ -n "$header" ]]; then
+  input="_input"
+  id="match message.context().header(r#\"%%HEADER%%\"#) {
+        Some(id) => String::from_utf8_lossy(id).into_owned(),
+        None => return Err(ErrorKind::ProcessorSkipped {
+            reason: r#\"Header %%HEADER%% is missing, message cannot be deduplicated.\"#.to_string()
+        }.into()),
+    }"
+else
+  input="_input"
+  id="match &message.context().key {
+        Some(key) => String::from_utf8_lossy(key).into_owned(),
+        None => return Err(ErrorKind::ProcessorSkipped {
+            reason: \"Message has no key, it cannot be deduplicated.\".to_string()
+        }.into()),
+    }"
+fi
+
+function_source="${function_source//"%%INPUT%%"/"$input"}"
+function_source="${function_source//"%%ID%%"/"$id"}"
+function_source="${function_source//"%%WINDOW_MS%%"/"$window_ms"}"
+function_source="${function_source//"%%FIELD%%"/"$field"}"
+function_source="${function_source//"%%HEADER%%"/"$header"}"
+function_source="${function_source//"%%FUNCTION_NAME%%"/"$kjp_function_name"}"
+
+echo "OK"
+echo "$function_source"
+exit 0
@@ -0,0 +1,77 @@
+#!/usr/bin/env bash
+
+source "$(dirname "$0")/util/params.sh" || exit 255
+
+function_source="fn %%FUNCTION_NAME%%(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {%%REGEX%%
+    let matches = match input.get_val(##JSONPATH(%%FIELD%%)##) {
+        Ok(field) => %%CONDITION%%,
+        Err(_) => false,
+    };
+
+    if %%NEGATION%%matches {
+        return Err(ErrorKind::MessageFiltered {
+            reason: r#\"Field %%FIELD%% %%DESCRIPTION%%.\"#.to_string()
+        }.into());
+    }
+    Ok(())
+}
+"
+
+required_param_to_var field
+
+action="drop"
+optional_param_to_var value
+optional_param_to_var pattern
+optional_param_to_var action
+
+regex=""
+if [[ -n "$value" ]]; then
+  condition="match field {
+            Value::String(field) => field == r#\"%%VALUE%%\"#,
+            field => field.to_string() == r#\"%%VALUE%%\"#,
+        }"
+  predicate="equals \"%%VALUE%%\""
+elif [[ -n "$pattern" ]]; then
+  regex="
+    lazy_static! {
+       static ref REGEX: regex::Regex = regex::Regex::new(r#\"%%PATTERN%%\"#).unwrap();
+    }
+"
+  condition="match field {
+            Value::String(field) => REGEX.is_match(field),
+            field => REGEX.is_match(&field.to_string()),
+        }"
+  predicate="matches /%%PATTERN%%/"
+else
+  condition="true"
+  predicate="exists"
+fi
+
+case "$action" in
+  drop)
+    negation=""
+    description="$predicate"
+    ;;
+  keep)
+    negation="!"
+    description="does not satisfy: $predicate"
+    ;;
+  *)
+    echo "ERR"
+    printf 'Unknown filter action: %s. Available actions: keep, drop.\n' "$action"
+    exit 1
+    ;;
+esac
+
+function_source="${function_source//"%%REGEX%%"/"$regex"}"
+function_source="${function_source//"%%CONDITION%%"/"$condition"}"
+function_source="${function_source//"%%NEGATION%%"/"$negation"}"
+function_source="${function_source//"%%DESCRIPTION%%"/"$description"}"
+function_source="${function_source//"%%FIELD%%"/"$field"}"
+function_source="${function_source//"%%VALUE%%"/"$value"}"
+function_source="${function_source//"%%PATTERN%%"/"$pattern"}"
+function_source="${function_source//"%%FUNCTION_NAME%%"/"$kjp_function_name"}"
+
+echo "OK"
+echo "$function_source"
+exit 0
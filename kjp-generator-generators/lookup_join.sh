@@ -0,0 +1,90 @@
+#!/usr/bin/env bash
+
+source "$(dirname "$0")/util/params.sh" || exit 255
+
+function_source="fn %%FUNCTION_NAME%%(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+    let key = match input.get_val(##JSONPATH(%%KEY_FIELD%%)##)? {
+        Value::String(key) => key.clone(),
+        key => key.to_string(),
+    };
+
+    let Some(record) = message.lookup(r#\"%%TABLE%%\"#, &key) else {
+        return Err(%%ON_MISSING%%.into());
+    };
+
+%%COPIES%%    Ok(())
+}
+"
+
+required_param_to_var table
+required_param_to_var key_field
+
+on_missing="skip"
+optional_param_to_var fields
+optional_param_to_var target_field
+optional_param_to_var on_missing
+
+if [[ -z "$fields" && -z "$target_field" ]]; then
+  echo "ERR"
+  echo "Nothing to copy from the record. Specify fields (source=target, separated by commas) and/or target_field (for the whole record)."
+  exit 1
+fi
+
+copies=""
+IFS=',' read -ra field_list <<< "$fields"
+for field in "${field_list[@]}"; do
+  if [[ "$field" != *=* ]]; then
+    echo "ERR"
+    printf 'Invalid field: %s. Fields should be defined as $.record_field=$.target_field (separated by commas).\n' "$field"
+    exit 1
+  fi
+
+  source_field="${field%%=*}"
+  source_field="${source_field#"${source_field%%[![:space:]]*}"}"
+  source_field="${source_field%"${source_field##*[![:space:]]}"}"
+  field_target="${field#*=}"
+  field_target="${field_target#"${field_target%%[![:space:]]*}"}"
+  field_target="${field_target%"${field_target##*[![:space:]]}"}"
+  copies+="    if let Ok(value) = record.get_val(##JSONPATH(${source_field})##) {
+        message.insert_val(##JSONPATH(${field_target})##, value.clone())?;
+    }
+"
+done
+
+if [[ -n "$target_field" ]]; then
+  copies+="    message.insert_val(##JSONPATH(${target_field})##, record)?;
+"
+fi
+
+case "$on_missing" in
+  skip)
+    error="ErrorKind::ProcessorSkipped {
+            reason: format!(\"There is no record [{key}] in lookup table %%TABLE%%.\")
+        }"
+    ;;
+  filter)
+    error="ErrorKind::MessageFiltered {
+            reason: format!(\"There is no record [{key}] in lookup table %%TABLE%%.\")
+        }"
+    ;;
+  fail)
+    error="ErrorKind::OtherError {
+            err: format!(\"There is no record [{key}] in lookup table %%TABLE%%.\").into()
+        }"
+    ;;
+  *)
+    echo "ERR"
+    printf 'Unknown on_missing action: %s. Available actions: skip, filter, fail.\n' "$on_missing"
+    exit 1
+    ;;
+esac
+
+function_source="${function_source//"%%COPIES%%"/"$copies"}"
+function_source="${function_source//"%%ON_MISSING%%"/"$error"}"
+function_source="${function_source//"%%KEY_FIELD%%"/"$key_field"}"
+function_source="${function_source//"%%TABLE%%"/"$table"}"
+function_source="${function_source//"%%FUNCTION_NAME%%"/"$kjp_function_name"}"
+
+echo "OK"
+echo "$function_source"
+exit 0
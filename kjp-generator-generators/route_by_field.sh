@@ -0,0 +1,57 @@
+#!/usr/bin/env bash
+
+source "$(dirname "$0")/util/params.sh" || exit 255
+
+function_source="fn %%FUNCTION_NAME%%(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+    let value = match input.get_val(##JSONPATH(%%FIELD%%)##) {
+        Ok(Value::String(value)) => Some(value.clone()),
+        Ok(value) => Some(value.to_string()),
+        Err(_) => None,
+    };
+
+    let topic = match value.as_deref() {
+%%ROUTES%%        _ => %%DEFAULT%%,
+    };
+
+    message.topic = Some(topic.to_string());
+    Ok(())
+}
+"
+
+required_param_to_var field
+required_param_to_var routes
+
+default=""
+optional_param_to_var default
+
+route_arms=""
+IFS=',' read -ra route_list <<< "$routes"
+for route in "${route_list[@]}"; do
+  if [[ "$route" != *=* ]]; then
+    echo "ERR"
+    printf 'Invalid route: %s. Routes should be defined as value=topic (separated by commas).\n' "$route"
+    exit 1
+  fi
+
+  route_value="${route%%=*}"
+  route_topic="${route#*=}"
+  route_arms+="        Some(r#\"${route_value}\"#) => r#\"${route_topic}\"#,
+"
+done
+
+if [[ -n "$default" ]]; then
+  default_arm="r#\"${default}\"#"
+else
+  default_arm="return Err(ErrorKind::ProcessorSkipped {
+            reason: format!(\"No route for value {value:?} of field %%FIELD%%, using target topic of the stream.\")
+        }.into())"
+fi
+
+function_source="${function_source//"%%ROUTES%%"/"$route_arms"}"
+function_source="${function_source//"%%DEFAULT%%"/"$default_arm"}"
+function_source="${function_source//"%%FIELD%%"/"$field"}"
+function_source="${function_source//"%%FUNCTION_NAME%%"/"$kjp_function_name"}"
+
+echo "OK"
+echo "$function_source"
+exit 0
@@ -0,0 +1,41 @@
+#!/usr/bin/env bash
+
+source "$(dirname "$0")/util/params.sh" || exit 255
+
+function_source="fn %%FUNCTION_NAME%%(input: &Value, message: &mut OutputMessage) -> Result<(), ProcessingError> {
+    let parts = [
+%%PARTS%%    ];
+
+    let parts: Vec<String> = parts.iter()
+        .map(|part| match part {
+            Value::String(part) => part.clone(),
+            part => part.to_string(),
+        })
+        .collect();
+
+    message.key = Some(parts.join(r#\"%%SEPARATOR%%\"#));
+    Ok(())
+}
+"
+
+required_param_to_var fields
+
+separator=":"
+optional_param_to_var separator
+
+parts=""
+IFS=',' read -ra field_list <<< "$fields"
+for field in "${field_list[@]}"; do
+  field="${field#"${field%%[![:space:]]*}"}"
+  field="${field%"${field##*[![:space:]]}"}"
+  parts+="        input.get_val(##JSONPATH(${field})##)?,
+"
+done
+
+function_source="${function_source//"%%PARTS%%"/"$parts"}"
+function_source="${function_source//"%%SEPARATOR%%"/"$separator"}"
+function_source="${function_source//"%%FUNCTION_NAME%%"/"$kjp_function_name"}"
+
+echo "OK"
+echo "$function_source"
+exit 0
@@ -1,16 +1,16 @@
-/// Example of kafka-json-processor-generator plugin, in Rust.
-///
-/// This plugin has the same purpose as ./kjp-generator-generators/static_field.sh.
-/// The following code will generate a processor that adds a static field to the output JSON.
-/// See the test at the end of this file for an example of generated code.
-///
-/// This executable needs to be run with the following arguments:
-/// `./static_field $function_name field $field_name value $static_value`
-/// Example:
-/// `./static_field static_field_function field '$.hello' value 'Greetings, folks!'`
-///
-/// It will be activated by placing a compiled executable in the generators directory
-/// (default: ./generators, can be set with --generators-path <GENERATORS_PATH> when using kjp_generator).
+//! Example of kafka-json-processor-generator plugin, in Rust.
+//!
+//! This plugin has the same purpose as ./kjp-generator-generators/static_field.sh.
+//! The following code will generate a processor that adds a static field to the output JSON.
+//! See the test at the end of this file for an example of generated code.
+//!
+//! This executable needs to be run with the following arguments:
+//! `./static_field $function_name field $field_name value $static_value`
+//! Example:
+//! `./static_field static_field_function field '$.hello' value 'Greetings, folks!'`
+//!
+//! It will be activated by placing a compiled executable in the generators directory
+//! (default: ./generators, can be set with --generators-path <GENERATORS_PATH> when using kjp_generator).
 
 use kjp_generator_plugin::{GeneratorError, json_path_to_object_key, JsonFieldName, ProcessorParams, return_generated};
 use kjp_generator_plugin::GeneratorError::RequiredConfigNotFound;
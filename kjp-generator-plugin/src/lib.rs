@@ -76,7 +76,6 @@ pub fn read_params() -> Result<ProcessorParams, GeneratorError> {
         .skip(2)
         .collect::<Vec<String>>()
         .chunks_exact(2)
-        .into_iter()
         .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
         .collect();
 
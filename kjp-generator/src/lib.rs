@@ -8,7 +8,7 @@ use std::io::Write;
 use std::path::Path;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
-use crate::processors::{create_processor_generators, generate_processors};
+use crate::processors::{create_processor_generators, generate_processors, GENERATOR_KEY};
 use crate::project::{generate_cargo, generate_main};
 
 /// Reads template, parses it and generated project based on it.
@@ -34,12 +34,52 @@ pub fn read_and_parse_and_generate<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<P
     }
 
     let generators = create_processor_generators(generators_path)?;
-    let streams = template.streams.into_iter()
-        .map(|stream| {
-            let processors = generate_processors(stream.clone(), &generators)?;
-            Ok(((stream.input_topic, stream.output_topic), processors))
-        })
-        .collect::<Result<BTreeMap<_, _>, Box<dyn Error>>>()?;
+    let mut streams = BTreeMap::new();
+    for stream in template.streams {
+        let topics = (stream.input_topic.clone(), stream.output_topic.clone());
+        if streams.contains_key(&topics) {
+            return Err(format!("Stream [{}] --> [{}] is defined more than once.", topics.0, topics.1).into());
+        }
+
+        if let Some(key_policy) = &stream.key_policy {
+            validate_key_policy(key_policy)?;
+        }
+
+        for payload_policy in [&stream.null_payload_policy, &stream.invalid_payload_policy].into_iter().flatten() {
+            validate_payload_policy(payload_policy)?;
+        }
+
+        if let Some(ordering) = &stream.ordering {
+            validate_ordering(ordering)?;
+        }
+
+        if let Some(error_policy) = &stream.error_policy {
+            validate_error_policy(error_policy)?;
+        }
+
+        if let Some(dedup) = &stream.dedup {
+            validate_dedup(dedup)?;
+        }
+
+        if stream.dedup_max_entries == Some(0) {
+            return Err(format!("Stream [{}] --> [{}]: dedup_max_entries must be greater than 0.", topics.0, topics.1).into());
+        }
+
+        if let Some(aggregation) = &stream.aggregation {
+            validate_aggregation(aggregation)
+                .map_err(|e| format!("Stream [{}] --> [{}]: {e}", topics.0, topics.1))?;
+        }
+
+        validate_lookup_tables(&stream)
+            .map_err(|e| format!("Stream [{}] --> [{}]: {e}", topics.0, topics.1))?;
+
+        if stream.concurrency == Some(0) {
+            return Err(format!("Stream [{}] --> [{}]: concurrency must be greater than 0.", topics.0, topics.1).into());
+        }
+
+        let processors = generate_processors(stream.clone(), &generators)?;
+        streams.insert(topics, (stream, processors));
+    }
 
     let main = generate_main(streams);
     let main_file = output_path.join("src").join("main.rs");
@@ -54,6 +94,105 @@ pub fn read_and_parse_and_generate<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<P
     Ok(())
 }
 
+/// Key policy is parsed at runtime, but it's better to fail during generation.
+fn validate_key_policy(key_policy: &str) -> Result<(), Box<dyn Error>> {
+    match key_policy {
+        "preserve" | "synthetic" | "null" => Ok(()),
+        _ if key_policy.starts_with("jsonpath:$") => Ok(()),
+        _ => Err(format!("Unknown key policy: {key_policy}. Available policies: preserve, synthetic, null, jsonpath:$.field[,$.other_field].").into()),
+    }
+}
+
+fn validate_payload_policy(payload_policy: &str) -> Result<(), Box<dyn Error>> {
+    match payload_policy {
+        "forward" | "skip" | "dead-letter" => Ok(()),
+        _ => Err(format!("Unknown payload policy: {payload_policy}. Available policies: forward, skip, dead-letter.").into()),
+    }
+}
+
+fn validate_ordering(ordering: &str) -> Result<(), Box<dyn Error>> {
+    match ordering {
+        "unordered" | "partition" | "key" => Ok(()),
+        _ => Err(format!("Unknown ordering policy: {ordering}. Available policies: unordered, partition, key.").into()),
+    }
+}
+
+fn validate_error_policy(error_policy: &str) -> Result<(), Box<dyn Error>> {
+    match error_policy {
+        "continue" | "fail" | "retry" => Ok(()),
+        _ if error_policy.starts_with("retry:") => {
+            let options: Vec<&str> = error_policy.split(':').skip(1).collect();
+            let valid = options.len() <= 2
+                && options[0].parse::<u32>().map(|attempts| attempts > 0).unwrap_or(false)
+                && options.get(1).map(|backoff| backoff.parse::<u64>().is_ok()).unwrap_or(true);
+            if valid {
+                Ok(())
+            } else {
+                Err(format!("Invalid error policy: {error_policy}. Expected retry[:<max attempts greater than 0>[:<backoff ms>]].").into())
+            }
+        }
+        _ => Err(format!("Unknown error policy: {error_policy}. Available policies: continue, fail, retry[:<max attempts>[:<backoff ms>]].").into()),
+    }
+}
+
+fn validate_dedup(dedup: &str) -> Result<(), Box<dyn Error>> {
+    match dedup.split_once(':') {
+        _ if dedup == "key" => Ok(()),
+        Some(("header", name)) if !name.trim().is_empty() => Ok(()),
+        Some(("jsonpath", path)) if path.trim().starts_with('$') => Ok(()),
+        _ => Err(format!("Unknown dedup policy: {dedup}. Available policies: key, header:<name>, jsonpath:$.field.").into()),
+    }
+}
+
+/// Tables used by `lookup_join` processors must be declared by the stream, otherwise they are never loaded.
+fn validate_lookup_tables(stream: &Stream) -> Result<(), Box<dyn Error>> {
+    let declared = stream.lookup_tables.as_deref().unwrap_or_default();
+    if let Some(table) = declared.iter().find(|table| table.trim().is_empty()) {
+        return Err(format!("Invalid lookup table: {table:?}. Expected a topic name.").into());
+    }
+
+    let used = stream.processors.iter()
+        .filter(|processor| processor.get(GENERATOR_KEY).is_some_and(|generator| generator == "lookup_join"))
+        .filter_map(|processor| processor.get("table"));
+    for table in used {
+        if !declared.contains(table) {
+            return Err(format!("Lookup table {table} is used by lookup_join, but it's not declared in lookup_tables of the stream.").into());
+        }
+    }
+    Ok(())
+}
+
+fn validate_aggregation(aggregation: &Aggregation) -> Result<(), Box<dyn Error>> {
+    for (option, path) in [("group_by", Some(&aggregation.group_by)), ("timestamp_field", aggregation.timestamp_field.as_ref())] {
+        if path.is_some_and(|path| !path.trim().starts_with('$') || path.contains(';')) {
+            return Err(format!("Invalid aggregation {option}: {}. Expected JSONPath (eg. $.field).", path.unwrap()).into());
+        }
+    }
+    if aggregation.window_ms == 0 {
+        return Err("Aggregation window_ms must be greater than 0.".into());
+    }
+    if aggregation.advance_ms.is_some_and(|advance_ms| advance_ms == 0 || advance_ms > aggregation.window_ms) {
+        return Err(format!("Aggregation advance_ms must be greater than 0 and not greater than window_ms ({}).", aggregation.window_ms).into());
+    }
+    if aggregation.aggregates.is_empty() {
+        return Err("Aggregation requires at least one aggregate (eg. events: count).".into());
+    }
+
+    for (name, aggregate) in &aggregation.aggregates {
+        if ["key", "window_start", "window_end"].contains(&name.as_str()) || name.contains(['=', ';']) || name.trim().is_empty() {
+            return Err(format!("Invalid aggregate name: {name}. Names key, window_start and window_end are reserved.").into());
+        }
+        let valid = aggregate == "count" || ["sum", "min", "max", "collect"].iter()
+            .any(|function| aggregate.strip_prefix(function)
+                .and_then(|rest| rest.strip_prefix("($"))
+                .is_some_and(|rest| rest.ends_with(')') && !rest.contains(';')));
+        if !valid {
+            return Err(format!("Unknown aggregate: {aggregate}. Available aggregates: count, sum($.field), min($.field), max($.field), collect($.field).").into());
+        }
+    }
+    Ok(())
+}
+
 fn create_directories<P: AsRef<Path>>(base_path: P) -> Result<(), Box<dyn Error>> {
     let path = base_path.as_ref();
     debug!("Creating directory: {}", path.display());
@@ -78,11 +217,84 @@ pub struct Template {
     streams: Vec<Stream>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
 pub struct Stream {
     input_topic: String,
     output_topic: String,
-    processors: Vec<HashMap<String, String>>
+    processors: Vec<HashMap<String, String>>,
+    #[serde(default)]
+    dead_letter_topic: Option<String>,
+    #[serde(default)]
+    propagate_headers: Option<bool>,
+    #[serde(default)]
+    key_policy: Option<String>,
+    #[serde(default)]
+    null_payload_policy: Option<String>,
+    #[serde(default)]
+    invalid_payload_policy: Option<String>,
+    #[serde(default)]
+    ordering: Option<String>,
+    #[serde(default)]
+    error_policy: Option<String>,
+    #[serde(default)]
+    concurrency: Option<usize>,
+    /// Maximum time of running a single processor in milliseconds.
+    #[serde(default)]
+    time_budget_ms: Option<usize>,
+    /// Topic backing up the state of stateful processors.
+    #[serde(default)]
+    state_changelog_topic: Option<String>,
+    /// Where the dedup id of a message is taken from (`key`, `header:<name>` or `jsonpath:$.field`).
+    #[serde(default)]
+    dedup: Option<String>,
+    #[serde(default)]
+    dedup_window_ms: Option<usize>,
+    #[serde(default)]
+    dedup_max_entries: Option<usize>,
+    /// Windowed aggregation producing summaries of closed windows instead of processed messages.
+    #[serde(default)]
+    aggregation: Option<Aggregation>,
+    /// Compacted topics materialized as lookup tables (used by `lookup_join` processors).
+    #[serde(default)]
+    lookup_tables: Option<Vec<String>>,
+    /// rdkafka properties overriding `producer.*` properties for this stream.
+    #[serde(default)]
+    producer: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct Aggregation {
+    group_by: String,
+    window_ms: usize,
+    #[serde(default)]
+    advance_ms: Option<usize>,
+    #[serde(default)]
+    lateness_ms: Option<usize>,
+    /// Field with the event time in milliseconds (default: timestamp of the Kafka message).
+    #[serde(default)]
+    timestamp_field: Option<String>,
+    /// Aggregates by their names in summary messages (eg. `total: sum($.amount)`).
+    aggregates: BTreeMap<String, String>,
+}
+
+impl Aggregation {
+    /// Aggregation as parsed by kafka-json-processor-core (`;`-separated options).
+    fn spec(&self) -> String {
+        let mut spec = format!("group.by={};window.ms={}", self.group_by.trim(), self.window_ms);
+        if let Some(advance_ms) = self.advance_ms {
+            spec.push_str(&format!(";advance.ms={advance_ms}"));
+        }
+        if let Some(lateness_ms) = self.lateness_ms {
+            spec.push_str(&format!(";lateness.ms={lateness_ms}"));
+        }
+        if let Some(timestamp_field) = &self.timestamp_field {
+            spec.push_str(&format!(";timestamp={}", timestamp_field.trim()));
+        }
+        for (name, aggregate) in &self.aggregates {
+            spec.push_str(&format!(";{name}={aggregate}"));
+        }
+        spec
+    }
 }
 
 #[cfg(test)]
@@ -112,7 +324,8 @@ mod test {
                             ("source_field".to_string(), "$.abc[1]".to_string()),
                             ("target_field".to_string(), "$.def".to_string()),
                         ]),
-                    ]
+                    ],
+                    ..Default::default()
                 }
             ]
         }, result.unwrap())
@@ -1,4 +1,4 @@
-use clap::{arg, Parser};
+use clap::Parser;
 use log::{error, info};
 use kjp_generator::read_and_parse_and_generate;
 
@@ -64,7 +64,6 @@ pub fn create_processor_generators<P: AsRef<Path>>(generators_path: P) -> Result
     info!("Loading available generators from: {:?}", generators_path.as_ref());
 
     let m: HashMap<String, PathBuf> = fs::read_dir(&generators_path)?
-        .into_iter()
         .filter_map(|entry| entry.map_err(|err|  {
             info!("Cannot read file in [{:?}]: {}", generators_path.as_ref(), err);
             err
@@ -215,7 +214,8 @@ mod test {
                     ("field".to_string(), "static_field0".to_string()),
                     ("value".to_string(), "hello world".to_string()),
                 ])
-            ]
+            ],
+            ..Default::default()
         };
         let mut generators: HashMap<String, PathBuf> = HashMap::new();
         generators.insert("test_generator".to_string(), PathBuf::from("../kjp-generator-generators/static_field.sh"));
@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 use log::debug;
 use crate::processors::Processor;
-use crate::Template;
+use crate::{Stream, Template};
 
 pub fn generate_cargo(template: &Template, core_path: Option<String>) -> String {
     debug!("Generating Cargo.toml");
@@ -16,10 +16,10 @@ pub fn generate_cargo(template: &Template, core_path: Option<String>) -> String
         )
 }
 
-pub fn generate_main(streams: BTreeMap<(String, String), Vec<Processor>>) -> String {
+pub fn generate_main(streams: BTreeMap<(String, String), (Stream, Vec<Processor>)>) -> String {
     debug!("Generating main.rs");
     let streams_config: String = streams.iter()
-        .map(|((input_topic, output_topic), processors)| {
+        .map(|((input_topic, output_topic), (stream, processors))| {
             let processor_list: String = processors.iter()
                 .map(|processor| format!("&{}, ", processor.function_name))
                 .collect();
@@ -28,17 +28,18 @@ pub fn generate_main(streams: BTreeMap<(String, String), Vec<Processor>>) -> Str
                 .replace(INPUT_TOPIC, input_topic)
                 .replace(OUTPUT_TOPIC, output_topic)
                 .replace(PROCESSORS, &processor_list)
+                .replace(STREAM_OPTIONS, &generate_stream_options(stream))
         })
         .collect();
 
-    let function_names: String =  streams.iter()
+    let function_names: String =  streams.values()
         .flat_map(|(_, processors)| {
             processors.iter()
                 .map(|p| format!("{}, ",p.function_name))
         })
         .collect();
 
-    let functions: String = streams.into_iter()
+    let functions: String = streams.into_values()
         .flat_map(|(_, processors)| {
             processors.into_iter()
                 .map(|p| p.function_body)
@@ -54,6 +55,83 @@ pub fn generate_main(streams: BTreeMap<(String, String), Vec<Processor>>) -> Str
     )
 }
 
+/// Generates optional fields of a stream (those that were set in the template).
+fn generate_stream_options(stream: &Stream) -> String {
+    let mut options = String::new();
+
+    if let Some(topic) = &stream.dead_letter_topic {
+        options.push_str(&format!("\n        dead_letter_topic: Some({topic:?}.to_string()),"));
+    }
+
+    if let Some(propagate_headers) = stream.propagate_headers {
+        options.push_str(&format!("\n        propagate_headers: Some({propagate_headers}),"));
+    }
+
+    if let Some(key_policy) = &stream.key_policy {
+        options.push_str(&format!("\n        key_policy: Some({key_policy:?}.parse().unwrap()),"));
+    }
+
+    if let Some(payload_policy) = &stream.null_payload_policy {
+        options.push_str(&format!("\n        null_payload_policy: Some({payload_policy:?}.parse().unwrap()),"));
+    }
+
+    if let Some(payload_policy) = &stream.invalid_payload_policy {
+        options.push_str(&format!("\n        invalid_payload_policy: Some({payload_policy:?}.parse().unwrap()),"));
+    }
+
+    if let Some(ordering) = &stream.ordering {
+        options.push_str(&format!("\n        ordering: Some({ordering:?}.parse().unwrap()),"));
+    }
+
+    if let Some(error_policy) = &stream.error_policy {
+        options.push_str(&format!("\n        error_policy: Some({error_policy:?}.parse().unwrap()),"));
+    }
+
+    if let Some(concurrency) = stream.concurrency {
+        options.push_str(&format!("\n        concurrency: Some({concurrency}),"));
+    }
+
+    if let Some(time_budget_ms) = stream.time_budget_ms {
+        options.push_str(&format!("\n        time_budget_ms: Some({time_budget_ms}),"));
+    }
+
+    if let Some(topic) = &stream.state_changelog_topic {
+        options.push_str(&format!("\n        state_changelog_topic: Some({topic:?}.to_string()),"));
+    }
+
+    if let Some(dedup) = &stream.dedup {
+        options.push_str(&format!("\n        dedup: Some({dedup:?}.parse().unwrap()),"));
+    }
+
+    if let Some(window_ms) = stream.dedup_window_ms {
+        options.push_str(&format!("\n        dedup_window_ms: Some({window_ms}),"));
+    }
+
+    if let Some(max_entries) = stream.dedup_max_entries {
+        options.push_str(&format!("\n        dedup_max_entries: Some({max_entries}),"));
+    }
+
+    if let Some(aggregation) = &stream.aggregation {
+        options.push_str(&format!("\n        aggregation: Some({:?}.parse().unwrap()),", aggregation.spec()));
+    }
+
+    if let Some(lookup_tables) = &stream.lookup_tables {
+        let topics: String = lookup_tables.iter()
+            .map(|topic| format!("{topic:?}.to_string(), "))
+            .collect();
+        options.push_str(&format!("\n        lookup_tables: vec![{topics}],"));
+    }
+
+    if let Some(producer) = &stream.producer {
+        let properties: String = producer.iter()
+            .map(|(key, value)| format!("({key:?}.to_string(), {value:?}.to_string()), "))
+            .collect();
+        options.push_str(&format!("\n        producer_config: vec![{properties}],"));
+    }
+
+    options
+}
+
 const CARGO_TOML: &str = r##"[package]
 name = "%%PROJECT_NAME%%"
 version = "0.1.0"
@@ -80,7 +158,7 @@ use log::{LevelFilter, trace, debug, error, info, warn};
 use serde_json::Value;
 use kafka_json_processor_core::processor::{ObjectKey, ObjectTree, OutputMessage};
 use kafka_json_processor_core::error::{ProcessingError, ErrorKind};
-use kafka_json_processor_core::{run_processor, Stream};
+use kafka_json_processor_core::{check_config, run_processor_from_args, Stream};
 use kafka_json_processor_core::processor::ObjectKey::{Key, Index};
 use lazy_static::lazy_static;
 
@@ -88,10 +166,14 @@ fn main() {
     env_logger::builder()
         .init();
 
+    if std::env::args().any(|arg| arg == "--check-config") {
+        std::process::exit(if check_config() { 0 } else { 1 });
+    }
+
     let mut streams = HashMap::new();
 %%STREAMS%%
 
-    run_processor(streams);
+    run_processor_from_args(streams);
 }
 "##;
 
@@ -101,12 +183,14 @@ const SINGLE_STREAM: &str = r##"
     streams.insert("%%INPUT_TOPIC%%_%%OUTPUT_TOPIC%%".to_string(), Stream {
         source_topic: "%%INPUT_TOPIC%%".to_string(),
         target_topic: "%%OUTPUT_TOPIC%%".to_string(),
-        processors: &[%%PROCESSORS%%],
+        processors: &[%%PROCESSORS%%],%%STREAM_OPTIONS%%
+        ..Default::default()
     });"##;
 
 const INPUT_TOPIC: &str = "%%INPUT_TOPIC%%";
 const OUTPUT_TOPIC: &str = "%%OUTPUT_TOPIC%%";
 const PROCESSORS: &str = "%%PROCESSORS%%";
+const STREAM_OPTIONS: &str = "%%STREAM_OPTIONS%%";
 
 const SIMULATIONS: &str = r##"
 
@@ -140,12 +224,12 @@ mod test {
     use std::collections::BTreeMap;
     use crate::processors::Processor;
     use crate::project::{generate_cargo, generate_main};
-    use crate::Template;
+    use crate::{Aggregation, Stream, Template};
 
     #[test]
     fn should_generate_main() {
         let mut streams = BTreeMap::new();
-        streams.insert(("abc".to_string(), "def".to_string()), vec![
+        streams.insert(("abc".to_string(), "def".to_string()), (Stream::default(), vec![
             Processor {
                 function_name: "function_1".to_string(),
                 function_body: r##"
@@ -160,9 +244,37 @@ fn function_2(_input: &Value, _message: &mut OutputMessage) -> Result<(), Proces
     Ok(())
 }"##.to_string(),
             },
-        ]);
-
-        streams.insert(("topic1".to_string(), "topic2".to_string()), vec![
+        ]));
+
+        let topic1_stream = Stream {
+            dead_letter_topic: Some("topic1_dlq".to_string()),
+            propagate_headers: Some(false),
+            key_policy: Some("jsonpath:$.id".to_string()),
+            null_payload_policy: Some("forward".to_string()),
+            ordering: Some("key".to_string()),
+            error_policy: Some("retry:5:200".to_string()),
+            concurrency: Some(16),
+            time_budget_ms: Some(500),
+            state_changelog_topic: Some("topic1_state".to_string()),
+            dedup: Some("jsonpath:$.id".to_string()),
+            dedup_window_ms: Some(60000),
+            aggregation: Some(Aggregation {
+                group_by: "$.customerId".to_string(),
+                window_ms: 60000,
+                lateness_ms: Some(5000),
+                aggregates: BTreeMap::from([
+                    ("events".to_string(), "count".to_string()),
+                    ("total".to_string(), "sum($.amount)".to_string()),
+                ]),
+                ..Default::default()
+            }),
+            lookup_tables: Some(vec!["customers".to_string()]),
+            producer: Some(BTreeMap::from([
+                ("compression.codec".to_string(), "zstd".to_string()),
+            ])),
+            ..Default::default()
+        };
+        streams.insert(("topic1".to_string(), "topic2".to_string()), (topic1_stream, vec![
             Processor {
                 function_name: "function_3".to_string(),
                 function_body: r##"
@@ -177,7 +289,7 @@ fn function_4(_input: &Value, _message: &mut OutputMessage) -> Result<(), Proces
     Ok(())
 }"##.to_string(),
             },
-        ]);
+        ]));
 
         let main = generate_main(streams);
         assert_eq!(r##"#![allow(unused_variables, unused_imports)]
@@ -187,7 +299,7 @@ use log::{LevelFilter, trace, debug, error, info, warn};
 use serde_json::Value;
 use kafka_json_processor_core::processor::{ObjectKey, ObjectTree, OutputMessage};
 use kafka_json_processor_core::error::{ProcessingError, ErrorKind};
-use kafka_json_processor_core::{run_processor, Stream};
+use kafka_json_processor_core::{check_config, run_processor_from_args, Stream};
 use kafka_json_processor_core::processor::ObjectKey::{Key, Index};
 use lazy_static::lazy_static;
 
@@ -195,20 +307,40 @@ fn main() {
     env_logger::builder()
         .init();
 
+    if std::env::args().any(|arg| arg == "--check-config") {
+        std::process::exit(if check_config() { 0 } else { 1 });
+    }
+
     let mut streams = HashMap::new();
 
     streams.insert("abc_def".to_string(), Stream {
         source_topic: "abc".to_string(),
         target_topic: "def".to_string(),
         processors: &[&function_1, &function_2, ],
+        ..Default::default()
     });
     streams.insert("topic1_topic2".to_string(), Stream {
         source_topic: "topic1".to_string(),
         target_topic: "topic2".to_string(),
         processors: &[&function_3, &function_4, ],
+        dead_letter_topic: Some("topic1_dlq".to_string()),
+        propagate_headers: Some(false),
+        key_policy: Some("jsonpath:$.id".parse().unwrap()),
+        null_payload_policy: Some("forward".parse().unwrap()),
+        ordering: Some("key".parse().unwrap()),
+        error_policy: Some("retry:5:200".parse().unwrap()),
+        concurrency: Some(16),
+        time_budget_ms: Some(500),
+        state_changelog_topic: Some("topic1_state".to_string()),
+        dedup: Some("jsonpath:$.id".parse().unwrap()),
+        dedup_window_ms: Some(60000),
+        aggregation: Some("group.by=$.customerId;window.ms=60000;lateness.ms=5000;events=count;total=sum($.amount)".parse().unwrap()),
+        lookup_tables: vec!["customers".to_string(), ],
+        producer_config: vec![("compression.codec".to_string(), "zstd".to_string()), ],
+        ..Default::default()
     });
 
-    run_processor(streams);
+    run_processor_from_args(streams);
 }
 
 fn function_1(_input: &Value, _message: &mut OutputMessage) -> Result<(), ProcessingError> {
@@ -244,11 +376,27 @@ mod simulations {
         source_topic: "abc".to_string(),
         target_topic: "def".to_string(),
         processors: &[&function_1, &function_2, ],
+        ..Default::default()
     });
     streams.insert("topic1_topic2".to_string(), Stream {
         source_topic: "topic1".to_string(),
         target_topic: "topic2".to_string(),
         processors: &[&function_3, &function_4, ],
+        dead_letter_topic: Some("topic1_dlq".to_string()),
+        propagate_headers: Some(false),
+        key_policy: Some("jsonpath:$.id".parse().unwrap()),
+        null_payload_policy: Some("forward".parse().unwrap()),
+        ordering: Some("key".parse().unwrap()),
+        error_policy: Some("retry:5:200".parse().unwrap()),
+        concurrency: Some(16),
+        time_budget_ms: Some(500),
+        state_changelog_topic: Some("topic1_state".to_string()),
+        dedup: Some("jsonpath:$.id".parse().unwrap()),
+        dedup_window_ms: Some(60000),
+        aggregation: Some("group.by=$.customerId;window.ms=60000;lateness.ms=5000;events=count;total=sum($.amount)".parse().unwrap()),
+        lookup_tables: vec!["customers".to_string(), ],
+        producer_config: vec![("compression.codec".to_string(), "zstd".to_string()), ],
+        ..Default::default()
     });
 
         simulate_streams_from_default_folder(streams);